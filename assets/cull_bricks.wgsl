@@ -0,0 +1,203 @@
+// builds the brick instances of a volume on the gpu. the brickmap is walked
+// one level per dispatch, nodes outside the frustum or behind the previous
+// frame's depth are dropped together with their children
+
+struct CullUniforms {
+    view_proj: mat4x4<f32>,
+    // view projection the depth pyramid was built with
+    previous_view_proj: mat4x4<f32>,
+    // volume local space to world space
+    transform: mat4x4<f32>,
+    brick_map_depth: u32,
+    index_count: u32,
+    // 0 disables occlusion culling
+    hi_z_mips: u32,
+    hi_z_size: vec2<u32>,
+}
+
+struct Counters {
+    // depth of the level being culled
+    depth: u32,
+    // range of the queue holding the level
+    level_start: u32,
+    level_end: u32,
+    // queued nodes of every level
+    total: atomic<u32>,
+}
+
+struct DrawArgs {
+    index_count: u32,
+    instance_count: atomic<u32>,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+struct DispatchArgs {
+    x: u32,
+    y: u32,
+    z: u32,
+}
+
+const BRICK_OFFSET: u32 = 2147483648u;
+
+@group(0) @binding(0)
+var<uniform> uniforms: CullUniforms;
+@group(0) @binding(1)
+var<storage, read> brickmap: array<u32>;
+// node index and position in bricks
+@group(0) @binding(2)
+var<storage, read_write> queue: array<vec4<u32>>;
+@group(0) @binding(3)
+var<storage, read_write> counters: Counters;
+@group(0) @binding(4)
+var<storage, read_write> draw_args: DrawArgs;
+// position, scale, brick and fade of each instance, the same layout as the
+// cpu instance buffer
+@group(0) @binding(5)
+var<storage, read_write> instances: array<u32>;
+@group(0) @binding(6)
+var hi_z: texture_2d<f32>;
+
+// only bound for reset and next_level, the cull pass reads it indirectly
+@group(1) @binding(0)
+var<storage, read_write> dispatch_args: DispatchArgs;
+
+fn octant(i: u32) -> vec3<u32> {
+    return vec3(i >> 2u & 1u, i >> 1u & 1u, i & 1u);
+}
+
+// queue the root nodes and clear the draw
+@compute @workgroup_size(1)
+fn reset() {
+    draw_args.index_count = uniforms.index_count;
+    atomicStore(&draw_args.instance_count, 0u);
+    draw_args.first_index = 0u;
+    draw_args.base_vertex = 0;
+    draw_args.first_instance = 0u;
+
+    let half_size = 1u << (uniforms.brick_map_depth - 1u);
+    for (var i = 0u; i < 8u; i++) {
+        queue[i] = vec4(i, octant(i) * half_size);
+    }
+    counters.depth = 1u;
+    counters.level_start = 0u;
+    counters.level_end = 8u;
+    atomicStore(&counters.total, 8u);
+    dispatch_args = DispatchArgs(1u, 1u, 1u);
+}
+
+// move on to the children queued by the last level
+@compute @workgroup_size(1)
+fn next_level() {
+    counters.depth += 1u;
+    counters.level_start = counters.level_end;
+    counters.level_end = atomicLoad(&counters.total);
+    dispatch_args.x = (counters.level_end - counters.level_start + 63u) / 64u;
+}
+
+// whether a box between the ndc corners with its closest depth at closest is
+// hidden behind the depth pyramid
+fn is_occluded(ndc_min: vec2<f32>, ndc_max: vec2<f32>, closest: f32) -> bool {
+    // the previous frame saw nothing outside the screen
+    if any(ndc_min < vec2(-1.0)) || any(ndc_max > vec2(1.0)) {
+        return false;
+    }
+
+    let uv_min = vec2(ndc_min.x, -ndc_max.y) * 0.5 + 0.5;
+    let uv_max = vec2(ndc_max.x, -ndc_min.y) * 0.5 + 0.5;
+
+    // pick the mip where the box covers at most 2x2 texels
+    let size = (uv_max - uv_min) * vec2<f32>(uniforms.hi_z_size);
+    let mip = i32(min(u32(ceil(log2(max(max(size.x, size.y), 1.0)))), uniforms.hi_z_mips - 1u));
+    let mip_size = textureDimensions(hi_z, mip);
+    let texel_min = min(vec2<u32>(uv_min * vec2<f32>(mip_size)), mip_size - 1u);
+    let texel_max = min(vec2<u32>(uv_max * vec2<f32>(mip_size)), mip_size - 1u);
+
+    // depth is reversed, so the pyramid holds the smallest depth
+    let furthest = min(
+        min(textureLoad(hi_z, texel_min, mip).r, textureLoad(hi_z, vec2(texel_max.x, texel_min.y), mip).r),
+        min(textureLoad(hi_z, vec2(texel_min.x, texel_max.y), mip).r, textureLoad(hi_z, texel_max, mip).r),
+    );
+    return closest < furthest;
+}
+
+// frustum and occlusion test of a node's box in the volume's local space
+fn is_visible(box_min: vec3<f32>, size: f32) -> bool {
+    var left = true;
+    var right = true;
+    var bottom = true;
+    var top = true;
+    var behind = true;
+
+    var ndc_min = vec2(1e9);
+    var ndc_max = vec2(-1e9);
+    var closest = 0.0;
+    var in_front = true;
+
+    for (var i = 0u; i < 8u; i++) {
+        let world_pos = uniforms.transform * vec4(box_min + vec3<f32>(octant(i)) * size, 1.0);
+
+        let clip = uniforms.view_proj * world_pos;
+        left = left && clip.x < -clip.w;
+        right = right && clip.x > clip.w;
+        bottom = bottom && clip.y < -clip.w;
+        top = top && clip.y > clip.w;
+        behind = behind && clip.w <= 0.0;
+
+        let previous = uniforms.previous_view_proj * world_pos;
+        if previous.w <= 0.0 {
+            in_front = false;
+        } else {
+            let ndc = previous.xyz / previous.w;
+            ndc_min = min(ndc_min, ndc.xy);
+            ndc_max = max(ndc_max, ndc.xy);
+            closest = max(closest, ndc.z);
+        }
+    }
+
+    if left || right || bottom || top || behind {
+        return false;
+    }
+    if uniforms.hi_z_mips == 0u || !in_front {
+        return true;
+    }
+    return !is_occluded(ndc_min, ndc_max, closest);
+}
+
+@compute @workgroup_size(64)
+fn cull(@builtin(global_invocation_id) id: vec3<u32>) {
+    let entry = counters.level_start + id.x;
+    if entry >= counters.level_end {
+        return;
+    }
+
+    let node = queue[entry];
+    let value = brickmap[node.x];
+    if value == BRICK_OFFSET {
+        return;
+    }
+
+    // instances are in the volume's local space, centred on the volume
+    let size = 1u << (uniforms.brick_map_depth - counters.depth);
+    let position = vec3<f32>(node.yzw) - f32(1u << (uniforms.brick_map_depth - 1u));
+    if !is_visible(position, f32(size)) {
+        return;
+    }
+
+    if value < BRICK_OFFSET {
+        let children = atomicAdd(&counters.total, 8u);
+        for (var i = 0u; i < 8u; i++) {
+            queue[children + i] = vec4(value * 8u + i, node.yzw + octant(i) * (size / 2u));
+        }
+        return;
+    }
+
+    let instance = atomicAdd(&draw_args.instance_count, 1u) * 6u;
+    instances[instance] = bitcast<u32>(position.x);
+    instances[instance + 1u] = bitcast<u32>(position.y);
+    instances[instance + 2u] = bitcast<u32>(position.z);
+    instances[instance + 3u] = bitcast<u32>(f32(size));
+    instances[instance + 4u] = value - BRICK_OFFSET;
+    instances[instance + 5u] = bitcast<u32>(1.0);
+}
@@ -0,0 +1,66 @@
+#define_import_path alex::debug
+
+// the variants of `DebugView`, 0 is the normal shading
+const DEBUG_DEPTH: u32 = 1u;
+const DEBUG_BRICK_WIREFRAME: u32 = 2u;
+const DEBUG_BRICK_INDEX: u32 = 3u;
+const DEBUG_STEPS: u32 = 4u;
+const DEBUG_NORMALS: u32 = 5u;
+const DEBUG_AMBIENT_OCCLUSION: u32 = 6u;
+const DEBUG_STREAMING: u32 = 7u;
+const DEBUG_MATERIAL: u32 = 8u;
+
+// the top bit of a voxel's material is set when it's transparent, the rest
+// is its id
+const MATERIAL_TRANSPARENT: u32 = 128u;
+
+// colour of a material in the debug view
+fn material_colour(material: u32) -> vec3<f32> {
+    let colour = hash_colour(material & ~MATERIAL_TRANSPARENT);
+    return select(colour, colour * 0.5, (material & MATERIAL_TRANSPARENT) != 0u);
+}
+
+// the variants of `StreamingState`
+const STREAMING_FINER: u32 = 1u;
+const STREAMING_COARSER: u32 = 2u;
+
+// the volume's debug view wins over the camera's
+fn resolve_debug_view(volume: u32, camera: u32) -> u32 {
+    return select(camera, volume, volume != 0u);
+}
+
+// blue through green to red as t goes from 0 to 1
+fn heatmap(t: f32) -> vec3<f32> {
+    let x = clamp(t, 0.0, 1.0) * 4.0;
+    return clamp(vec3(x - 2.0, 2.0 - abs(x - 2.0), 2.0 - x), vec3(0.0), vec3(1.0));
+}
+
+// a stable colour for an index, neighbouring indices get unrelated colours
+fn hash_colour(index: u32) -> vec3<f32> {
+    // pcg hash
+    var x = index * 747796405u + 2891336453u;
+    x = ((x >> ((x >> 28u) + 4u)) ^ x) * 277803737u;
+    x = (x >> 22u) ^ x;
+    return vec3(f32(x & 255u), f32((x >> 8u) & 255u), f32((x >> 16u) & 255u)) / 255.0;
+}
+
+// draw the edges of the brick face that was hit over colour. pos is the
+// position in the brick from 0 to 1 and normal the normal of the face
+fn brick_wireframe(colour: vec3<f32>, pos: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    // distance to the closest edge, ignoring the axis the face is on
+    let edge = select(min(pos, 1.0 - pos), vec3(1.0), abs(normal) > vec3(0.5));
+    if min(min(edge.x, edge.y), edge.z) < 0.015 {
+        return vec3(1.0, 0.9, 0.0);
+    }
+    return colour;
+}
+
+fn streaming_colour(state: u32) -> vec3<f32> {
+    if state == STREAMING_FINER {
+        return vec3(1.0, 0.15, 0.1);
+    }
+    if state == STREAMING_COARSER {
+        return vec3(0.15, 0.35, 1.0);
+    }
+    return vec3(0.2, 0.85, 0.3);
+}
@@ -0,0 +1,48 @@
+#define_import_path alex::fog
+
+#import alex::sky::sky_colour
+
+struct Fog {
+    start: f32,
+    end: f32,
+    height: f32,
+    height_density: f32,
+    height_falloff: f32,
+    enabled: u32,
+}
+
+// how much of a surface distance away along dir is hidden by fog, seen from a
+// camera at camera_height. everything is in world space
+fn fog_amount(fog: Fog, distance: f32, dir: vec3<f32>, camera_height: f32) -> f32 {
+    let distance_fog = clamp((distance - fog.start) / max(fog.end - fog.start, 0.0001), 0.0, 1.0);
+
+    // exponential height fog integrated along the ray
+    let falloff = max(fog.height_falloff, 0.0001);
+    let density = fog.height_density * exp(min(falloff * (fog.height - camera_height), 30.0));
+    let slope = max(falloff * dir.y * distance, -30.0);
+    var optical_depth = density * distance;
+    if abs(slope) > 0.0001 {
+        optical_depth *= (1.0 - exp(-slope)) / slope;
+    }
+    let height_fog = 1.0 - exp(-optical_depth);
+
+    return 1.0 - (1.0 - distance_fog) * (1.0 - height_fog);
+}
+
+// fade the colour of a surface at world_pos towards the sky near the horizon
+// behind it, sun_dir points towards the sun
+fn apply_fog(fog: Fog, colour: vec3<f32>, world_pos: vec3<f32>, camera_pos: vec3<f32>, sun_dir: vec3<f32>) -> vec3<f32> {
+    if fog.enabled == 0u {
+        return colour;
+    }
+
+    let offset = world_pos - camera_pos;
+    let distance = length(offset);
+    let dir = offset / max(distance, 0.0001);
+
+    // looking down the fog still takes the colour of the horizon, not the
+    // ground below it
+    let horizon_dir = normalize(vec3(dir.x, max(dir.y, 0.0) + 0.0001, dir.z));
+    let amount = fog_amount(fog, distance, dir, camera_pos.y);
+    return mix(colour, sky_colour(horizon_dir, sun_dir), amount);
+}
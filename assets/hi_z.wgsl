@@ -0,0 +1,43 @@
+// depth pyramid for occlusion culling. every texel holds the furthest depth
+// of the texels it covers, the smallest since depth is reversed
+
+#ifdef COPY_DEPTH
+@group(0) @binding(0)
+var source: texture_depth_2d;
+#else
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+#endif
+@group(0) @binding(1)
+var destination: texture_storage_2d<r32float, write>;
+
+#ifdef COPY_DEPTH
+@compute @workgroup_size(8, 8, 1)
+fn copy_depth(@builtin(global_invocation_id) id: vec3<u32>) {
+    if any(id.xy >= textureDimensions(destination)) {
+        return;
+    }
+    textureStore(destination, id.xy, vec4(textureLoad(source, id.xy, 0)));
+}
+#else
+@compute @workgroup_size(8, 8, 1)
+fn downsample(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(destination);
+    if any(id.xy >= size) {
+        return;
+    }
+
+    // the last texel of an odd sized mip covers three source texels
+    let source_size = textureDimensions(source);
+    let start = id.xy * 2u;
+    let end = min(select(start + 1u, start + 2u, id.xy == size - 1u), source_size - 1u);
+
+    var depth = 1.0;
+    for (var y = start.y; y <= end.y; y++) {
+        for (var x = start.x; x <= end.x; x++) {
+            depth = min(depth, textureLoad(source, vec2(x, y), 0).r);
+        }
+    }
+    textureStore(destination, id.xy, vec4(depth));
+}
+#endif
@@ -1,12 +1,21 @@
 #import bevy_pbr::{
-    mesh_view_bindings::view,
-    mesh_functions::{
-        get_model_matrix,
-        mesh_position_local_to_clip,
-    },
-    view_transformations::direction_clip_to_world,
+    mesh_view_bindings::{view, lights, globals, screen_space_ambient_occlusion_texture},
+    mesh_view_types::DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT,
+    view_transformations::{direction_clip_to_world, position_world_to_clip},
     utils::coords_to_viewport_uv,
 }
+#import alex::sky::{sky_colour, sky_ambient}
+#import alex::fog::{Fog, apply_fog}
+#ifdef PREPASS_PIPELINE
+#ifdef MOTION_VECTOR_PREPASS
+#import bevy_pbr::prepass_bindings::previous_view_proj
+#endif
+#endif
+#import alex::debug::{
+    DEBUG_DEPTH, DEBUG_BRICK_WIREFRAME, DEBUG_BRICK_INDEX, DEBUG_STEPS, DEBUG_NORMALS,
+    DEBUG_AMBIENT_OCCLUSION, DEBUG_STREAMING, DEBUG_MATERIAL, MATERIAL_TRANSPARENT,
+    resolve_debug_view, heatmap, hash_colour, brick_wireframe, streaming_colour, material_colour,
+}
 
 struct Vertex {
     // vertex data
@@ -17,6 +26,7 @@ struct Vertex {
     // instance data
     @location(3) pos_scale: vec4<f32>,
     @location(4) brick: u32,
+    @location(5) fade: f32,
 };
 
 struct VertexOutput {
@@ -25,6 +35,7 @@ struct VertexOutput {
     @location(1) pos_scale: vec4<f32>,
     @location(2) normal: vec3<f32>,
     @location(3) brick: u32,
+    @location(4) fade: f32,
 };
 
 @vertex
@@ -32,11 +43,9 @@ fn vertex(vertex: Vertex) -> VertexOutput {
     // the 0.9999 avoids z-fighting with the backface
     let position = 0.9999 * vertex.position * vertex.pos_scale.w + vertex.pos_scale.xyz;
 
-    // NOTE: Passing 0 as the instance_index to get_model_matrix() is a hack
-    // for this example as the instance_index builtin would map to the wrong
-    // index in the Mesh array. This index could be passed in via another
-    // uniform instead but it's unnecessary for the example.
-    let clip_pos = mesh_position_local_to_clip(get_model_matrix(0u), vec4<f32>(position, 1.0));
+    // instances are in the volume's local space
+    let world_pos = voxel_uniforms.transform * vec4<f32>(position, 1.0);
+    let clip_pos = position_world_to_clip(world_pos.xyz);
 
     var out: VertexOutput;
     out.clip_pos = clip_pos;
@@ -44,6 +53,7 @@ fn vertex(vertex: Vertex) -> VertexOutput {
     out.pos_scale = vertex.pos_scale;
     out.normal = vertex.normal;
     out.brick = vertex.brick;
+    out.fade = vertex.fade;
 
     return out;
 }
@@ -52,8 +62,14 @@ struct VoxelUniforms {
     brick_map_depth: u32,
     brick_size: u32, // brick size as a power of 2
     brick_ints: u32,
+    transform: mat4x4<f32>,
+    inverse_transform: mat4x4<f32>,
+    debug_view: u32,
+    previous_transform: mat4x4<f32>,
 }
 
+const BRICK_OFFSET: u32 = 2147483648u;
+
 @group(2) @binding(0)
 var<uniform> voxel_uniforms: VoxelUniforms;
 @group(2) @binding(1)
@@ -64,9 +80,86 @@ var<storage, read_write> counters: array<u32>;
 var<storage, read> bricks: array<u32>;
 @group(2) @binding(4)
 var color_texture: texture_storage_3d<rgba8unorm, read>;
+// block light in red, sky occlusion in green, emission in blue and the
+// packed roughness and metalness in alpha
+@group(2) @binding(5)
+var light_texture: texture_storage_3d<rgba8unorm, read>;
+@group(2) @binding(6)
+var<storage, read> streaming_state: array<u32>;
+// material id in the low 7 bits and whether the voxel is transparent in the
+// top one
+@group(2) @binding(7)
+var material_texture: texture_3d<u32>;
+
+struct ViewUniforms {
+    fog: Fog,
+    // members after a struct in a uniform have to start on 16 bytes
+    @align(16) debug_view: u32,
+}
+
+@group(3) @binding(0)
+var<uniform> view_uniforms: ViewUniforms;
+
+// steps the rays of this fragment took, for the step count debug view
+var<private> debug_steps: u32 = 0u;
+
+// once less light than this gets through the next voxel is treated as opaque
+const MIN_TRANSMITTANCE = 0.02;
+
+// emissive voxels are brighter than anything lit, so they bloom
+const EMISSION_STRENGTH = 4.0;
+// how much non-metals reflect head on, water and glass included
+const DIELECTRIC_REFLECTIVITY = 0.04;
+// colour of the light spread from emissive voxels
+const block_light_colour = vec3<f32>(1.0, 0.8, 0.6);
+
+// light picked up from the transparent voxels a ray passed through
+struct Tint {
+    colour: vec3<f32>,
+    transmittance: f32,
+    // roughness of the first transparent voxel, 1 if it doesn't reflect,
+    // and where the ray entered it in brickmap space
+    roughness: f32,
+    surface_pos: vec3<f32>,
+    surface_normal: vec3<f32>,
+}
+
+fn no_tint() -> Tint {
+    return Tint(vec3(0.0), 1.0, 1.0, vec3(0.0), vec3(0.0));
+}
+
+// where the brick trace_brick is in sits in brickmap space, for the surfaces
+// it records
+var<private> brick_origin: vec3<f32>;
+var<private> brick_scale: f32;
+
+struct BrickHit {
+    // alpha is 0 if nothing was hit
+    colour: vec4<f32>,
+    brick: u32,
+    // position in the brick, 0 to 1
+    pos: vec3<f32>,
+    normal: vec3<f32>,
+    // position in brickmap space
+    volume_pos: vec3<f32>,
+}
 
-// local_pos ranges from (0,0,0) to (1,1,1) inside the brick
-fn trace_brick(index: u32, local_pos: ptr<function, vec3<f32>>, dir: vec3<f32>, normal: ptr<function, vec3<f32>>) -> vec3<f32> {
+// texel of the color and light textures at pos (0 to 1) in the brick
+fn brick_texel(index: u32, pos: vec3<f32>) -> vec3<i32> {
+    let brick_size = i32(1u << voxel_uniforms.brick_size);
+    let dim = vec3<i32>(textureDimensions(color_texture)) / brick_size;
+    let brick_pos_in_texture = vec3(
+        i32(index) / (dim.z * dim.y),
+        (i32(index) / dim.z) % dim.y,
+        i32(index) % dim.z,
+    ) * brick_size;
+    return brick_pos_in_texture + vec3<i32>(pos * f32(brick_size));
+}
+
+// local_pos ranges from (0,0,0) to (1,1,1) inside the brick. transparent voxels
+// are accumulated into tint and stepped through. returns 0 alpha if the ray
+// leaves the brick
+fn trace_brick(index: u32, local_pos: ptr<function, vec3<f32>>, dir: vec3<f32>, normal: ptr<function, vec3<f32>>, tint: ptr<function, Tint>) -> vec4<f32> {
     let r_sign = sign(dir);
     var initial_pos = *local_pos;
     var steps = 0u;
@@ -76,9 +169,9 @@ fn trace_brick(index: u32, local_pos: ptr<function, vec3<f32>>, dir: vec3<f32>,
         let lookup_pos = *local_pos - *normal * 0.000001;
         if any(lookup_pos < vec3(0.0)) || any(lookup_pos > vec3(1.0)) {
             if steps == 0u {
-                return lookup_pos;
+                return vec4(lookup_pos, 1.0);
             }
-            break;
+            return vec4(0.0);
         }
 
         let pos_in_0 = vec3<i32>(lookup_pos * 16.0);
@@ -110,17 +203,27 @@ fn trace_brick(index: u32, local_pos: ptr<function, vec3<f32>>, dir: vec3<f32>,
         }
 
         if bit_0 != 0u {
-            // get color of the voxel
-            let brick_size = i32(1u << voxel_uniforms.brick_size);
-            let dim = vec3<i32>(textureDimensions(color_texture)) / brick_size;
-            let brick_pos_in_texture = vec3(
-                i32(index) / (dim.z * dim.y),
-                (i32(index) / dim.z) % dim.y,
-                i32(index) % dim.z,
-            ) * brick_size;
-            let color = textureLoad(color_texture, brick_pos_in_texture + vec3<i32>(lookup_pos * f32(brick_size))).rgb;
-            return color;
-            // return vec3(f32(steps) / 2.0);
+            // get color of the voxel, transparent voxels only tint the ray
+            let texel = brick_texel(index, lookup_pos);
+            let color = textureLoad(color_texture, texel);
+            let transparent = (textureLoad(material_texture, texel, 0).r & MATERIAL_TRANSPARENT) != 0u;
+            if !transparent || (*tint).transmittance < MIN_TRANSMITTANCE {
+                return vec4(color.rgb, 1.0);
+                // return vec3(f32(steps) / 2.0);
+            }
+
+            // remember the first transparent voxel, its depth is written
+            // and water and glass reflect off it
+            if (*tint).transmittance == 1.0 {
+                (*tint).roughness = unpack_surface(textureLoad(light_texture, texel)).x;
+                (*tint).surface_pos = brick_origin + *local_pos * brick_scale;
+                (*tint).surface_normal = *normal;
+            }
+
+            // transparent voxel, tint the ray and step through it
+            (*tint).colour += (*tint).transmittance * color.a * color.rgb * shade(*normal, 1.0, sky_ambient(get_sun().direction));
+            (*tint).transmittance *= 1.0 - color.a;
+            size = 16;
         }
 
         let rounded_pos = floor(lookup_pos * f32(size)) / f32(size);
@@ -134,12 +237,85 @@ fn trace_brick(index: u32, local_pos: ptr<function, vec3<f32>>, dir: vec3<f32>,
         *local_pos = initial_pos + dir * t_current;
 
         steps += 1u;
+        debug_steps += 1u;
     }
 
-    discard;
+    return vec4(0.0);
     // return vec3(f32(steps) / 10.0);
 }
 
+struct Brick {
+    index: u32,
+    pos: vec3<i32>,
+    depth: u32,
+    node_index: u32,
+}
+
+fn find_brick(pos: vec3<i32>) -> Brick {
+    var node_index = 0u;
+    var node_pos = vec3(0);
+    var depth = 1u;
+    loop {
+        let offset = vec3(1 << (voxel_uniforms.brick_map_depth - depth));
+        let mask = vec3<i32>(pos >= node_pos + offset);
+        node_pos += mask * offset;
+
+        let child_index = mask.x * 4 + mask.y * 2 + mask.z;
+        let new_node_index = node_index + u32(child_index);
+        let new_node = brickmap[new_node_index];
+        if new_node >= BRICK_OFFSET {
+            return Brick(new_node - BRICK_OFFSET, node_pos, depth, new_node_index);
+        }
+
+        depth = depth + 1u;
+        node_index = 8u * new_node;
+    }
+
+    return Brick(0u, vec3(0), 0u, 0u);
+}
+
+// continue a ray through the resident bricks of the volume. pos is in
+// brickmap space, from 0 to 2^brick_map_depth
+fn trace_volume(start: vec3<f32>, dir: vec3<f32>, start_normal: vec3<f32>, tint: ptr<function, Tint>) -> BrickHit {
+    let volume_size = f32(1u << voxel_uniforms.brick_map_depth);
+    let r_sign = sign(dir);
+    var pos = start;
+    var normal = start_normal;
+    // every step leaves a node at least one brick wide, so a ray can't take
+    // more steps than the bricks it crosses along each axis
+    let max_steps = 3u * (1u << voxel_uniforms.brick_map_depth) + 1u;
+    for (var i = 0u; i < max_steps; i++) {
+        if any(pos < vec3(0.0)) || any(pos >= vec3(volume_size)) {
+            break;
+        }
+
+        let brick = find_brick(vec3<i32>(pos));
+        let brick_min = vec3<f32>(brick.pos);
+        let brick_size = f32(1u << (voxel_uniforms.brick_map_depth - brick.depth));
+        if brick.index > 0u {
+            brick_origin = brick_min;
+            brick_scale = brick_size;
+            var local_pos = clamp((pos - brick_min) / brick_size, vec3(0.0), vec3(1.0));
+            var local_normal = normal;
+            let colour = trace_brick(brick.index, &local_pos, dir, &local_normal, tint);
+            if colour.a > 0.0 {
+                let volume_pos = brick_min + local_pos * brick_size;
+                return BrickHit(colour, brick.index, local_pos, local_normal, volume_pos);
+            }
+        }
+
+        // step to where the ray leaves this brick
+        let t_max = (brick_min + 0.5 * (r_sign + 1.0) * brick_size - pos) / dir;
+        let mask = vec3<f32>(t_max.xyz <= min(t_max.yzx, t_max.zxy));
+        normal = mask * -r_sign;
+        let t_current = min(min(t_max.x, t_max.y), t_max.z);
+        pos = pos + dir * t_current - normal * 0.0001;
+        debug_steps += 1u;
+    }
+
+    return BrickHit(vec4(0.0), 0u, vec3(0.0), vec3(0.0), vec3(0.0));
+}
+
 // https://www.shadertoy.com/view/ldl3DS
 fn check_voxel(brick: u32, pos: vec3<i32>) -> f32 {
     let brick_size = i32(1u << voxel_uniforms.brick_size);
@@ -177,25 +353,204 @@ fn glmod(x: vec2<f32>, y: vec2<f32>) -> vec2<f32> {
     return x - y * floor(x / y);
 }
 
+// used when there is no directional light in the scene
 const light_dir = vec3<f32>(0.8, -1.0, 0.8);
 
-struct FragmentOutput {
-    @location(0) color: vec4<f32>,
-    // @builtin(frag_depth) depth: f32,
+struct Sun {
+    // world space direction towards the sun
+    direction: vec3<f32>,
+    colour: vec3<f32>,
+    shadows: bool,
 }
 
-@fragment
-fn fragment(in: VertexOutput, @builtin(front_facing) facing: bool) -> FragmentOutput {
-    var output_color = vec3(0.0);
+// the first directional light is the sun
+fn get_sun() -> Sun {
+    // the prepass doesn't bind the lights, and only needs the hit anyway
+#ifndef PREPASS_PIPELINE
+    if lights.n_directional_lights > 0u {
+        let light = lights.directional_lights[0];
+        let shadows = (light.flags & DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u;
+        return Sun(light.direction_to_light, light.color.rgb, shadows);
+    }
+#endif
+    return Sun(-normalize(light_dir), vec3(1.0), false);
+}
+
+// how much sun reaches pos (in brickmap space), transparent voxels let some
+// of it through
+fn sun_visibility(pos: vec3<f32>, normal: vec3<f32>) -> f32 {
+    let sun = get_sun();
+    if !sun.shadows {
+        return 1.0;
+    }
+
+    let dir = normalize((voxel_uniforms.inverse_transform * vec4(sun.direction, 0.0)).xyz);
+    if dot(dir, normal) <= 0.0 {
+        return 0.0;
+    }
+
+    var tint = no_tint();
+    let hit = trace_volume(pos + normal * 0.001, dir, vec3(0.0), &tint);
+    if hit.colour.a > 0.0 {
+        return 0.0;
+    }
+    return tint.transmittance;
+}
+
+// sun plus ambient light for a normal in the volume's local space
+fn shade(normal: vec3<f32>, shadow: f32, ambient: vec3<f32>) -> vec3<f32> {
+    let sun = get_sun();
+    // the normal is transformed to world space with the inverse transpose
+    let world_normal = normalize((vec4(normal, 0.0) * voxel_uniforms.inverse_transform).xyz);
+    return sun.colour * max(dot(world_normal, sun.direction), 0.0) * shadow + ambient;
+}
+
+// light texel of the voxel a hit landed in
+fn hit_light(hit: BrickHit) -> vec4<f32> {
+    return textureLoad(light_texture, brick_texel(hit.brick, hit.pos - hit.normal * 0.000001));
+}
+
+// material of the voxel a hit landed in
+fn hit_material(hit: BrickHit) -> u32 {
+    return textureLoad(material_texture, brick_texel(hit.brick, hit.pos - hit.normal * 0.000001), 0).r;
+}
+
+// lit colour of a hit, ao darkens the indirect light. metals only get the
+// light they reflect
+fn shade_hit(hit: BrickHit, light: vec4<f32>, ao: f32) -> vec3<f32> {
+    let indirect = ao * sky_ambient(get_sun().direction);
+
+    // direct lighting, shadows are traced through the resident bricks
+    let shadow = sun_visibility(hit.volume_pos, hit.normal);
+
+    // emission, the light spread from nearby emissive voxels and how much of
+    // the sky reaches the voxel, baked when the world was loaded
+    let block_light = block_light_colour * light.r * light.r;
+    let sky_light = (1.0 - light.g) * (1.0 - light.g);
+    let metalness = unpack_surface(light).y;
+    let diffuse = (shade(hit.normal, shadow, indirect) * sky_light + block_light) * (1.0 - metalness);
+
+    return hit.colour.rgb * (diffuse + light.b * EMISSION_STRENGTH);
+}
+
+// roughness and metalness, packed into a nibble each of the light's alpha
+fn unpack_surface(light: vec4<f32>) -> vec2<f32> {
+    let packed = u32(light.a * 255.0 + 0.5);
+    return vec2(f32(packed >> 4u), f32(packed & 15u)) / 15.0;
+}
+
+// schlick's approximation of how much a surface reflects seen from dir
+fn fresnel(reflectivity: vec3<f32>, dir: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let cos_theta = clamp(-dot(dir, normal), 0.0, 1.0);
+    return reflectivity + (1.0 - reflectivity) * pow(1.0 - cos_theta, 5.0);
+}
+
+// https://www.jcgt.org/published/0009/03/02/
+fn pcg(v: u32) -> u32 {
+    let state = v * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// what a surface at pos (brickmap space) reflects of a ray going along dir.
+// rough surfaces scatter the reflection differently every frame, and the
+// upscaling averages it out
+fn trace_reflection(pos: vec3<f32>, normal: vec3<f32>, dir: vec3<f32>, roughness: f32, pixel: vec2<f32>) -> vec3<f32> {
+    var reflected = reflect(dir, normal);
+    if roughness > 0.0 {
+        let seed = pcg(u32(pixel.x) ^ pcg(u32(pixel.y) ^ pcg(globals.frame_count)));
+        let noise = vec3<f32>(vec3(pcg(seed), pcg(seed + 1u), pcg(seed + 2u))) / 4294967295.0 - 0.5;
+        reflected = normalize(reflected + roughness * noise);
+        // keep it above the surface
+        if dot(reflected, normal) <= 0.0 {
+            reflected = reflect(reflected, normal);
+        }
+    }
+
+    var tint = no_tint();
+    let hit = trace_volume(pos + normal * 0.001, reflected, vec3(0.0), &tint);
+    var colour: vec3<f32>;
+    if hit.colour.a > 0.0 {
+        colour = shade_hit(hit, hit_light(hit), 1.0);
+    } else {
+        let world_reflected = normalize((voxel_uniforms.transform * vec4(reflected, 0.0)).xyz);
+        colour = sky_colour(world_reflected, get_sun().direction);
+    }
+    return tint.colour + tint.transmittance * colour;
+}
+
+// colour of a hit in the debug view, colour is the normal shading
+fn debug_colour(debug_view: u32, colour: vec3<f32>, hit: BrickHit, ao: f32, steps: u32) -> vec3<f32> {
+    let brick = find_brick(vec3<i32>(hit.volume_pos - hit.normal * 0.0001));
+
+    if debug_view == DEBUG_DEPTH {
+        return heatmap(f32(brick.depth - 1u) / f32(max(voxel_uniforms.brick_map_depth - 1u, 1u)));
+    }
+    if debug_view == DEBUG_BRICK_WIREFRAME {
+        return brick_wireframe(colour, hit.pos, hit.normal);
+    }
+    if debug_view == DEBUG_BRICK_INDEX {
+        return hash_colour(hit.brick);
+    }
+    if debug_view == DEBUG_STEPS {
+        return heatmap(f32(steps) / 100.0);
+    }
+    if debug_view == DEBUG_NORMALS {
+        let world_normal = normalize((vec4(hit.normal, 0.0) * voxel_uniforms.inverse_transform).xyz);
+        return world_normal * 0.5 + 0.5;
+    }
+    if debug_view == DEBUG_AMBIENT_OCCLUSION {
+        return vec3(ao);
+    }
+    if debug_view == DEBUG_STREAMING {
+        return streaming_colour(streaming_state[brick.node_index]);
+    }
+    if debug_view == DEBUG_MATERIAL {
+        return material_colour(hit_material(hit));
+    }
+    return colour;
+}
+
+// stable per pixel noise in [0, 1)
+fn dither_noise(pixel: vec2<f32>) -> f32 {
+    return fract(52.9829189 * fract(dot(floor(pixel), vec2(0.06711056, 0.00583715))));
+}
+
+struct FragmentRay {
+    hit: BrickHit,
+    tint: Tint,
+    world_dir: vec3<f32>,
+    // direction in the volume's local space
+    dir: vec3<f32>,
+    // brickmap space position of the surface the fragment shows, the first
+    // transparent voxel the ray entered or otherwise the hit
+    surface_pos: vec3<f32>,
+    surface_normal: vec3<f32>,
+}
+
+// traces the ray of a fragment through its brick, and on through the volume
+// if it left the brick through transparent voxels. the main pass and the
+// prepass share this, so they discard the same fragments and agree on depth
+fn trace_fragment(in: VertexOutput, facing: bool) -> FragmentRay {
+    // cross-fade between levels of detail. bricks fading in and out use
+    // complementary dither patterns, so each pixel shows exactly one level
+    if in.fade < 1.0 {
+        let dither = dither_noise(in.clip_pos.xy);
+        if (in.fade >= 0.0 && dither >= in.fade) || (in.fade < 0.0 && dither < 1.0 + in.fade) {
+            discard;
+        }
+    }
 
-    // get ray direction
+    // get ray direction in the volume's local space
     let viewport_uv = coords_to_viewport_uv(in.clip_pos.xy, view.viewport);
     let clip_uv = (viewport_uv * 2.0 - 1.0) * vec2(1.0, -1.0);
-    let dir = normalize(direction_clip_to_world(vec4(clip_uv, 0.0, 1.0)));
+    let world_dir = direction_clip_to_world(vec4(clip_uv, 0.0, 1.0));
+    let dir = normalize((voxel_uniforms.inverse_transform * vec4(world_dir, 0.0)).xyz);
 
     // get local position
     var pos: vec3<f32>;
-    let local_cam = (view.world_position - in.pos_scale.xyz) / in.pos_scale.w;
+    let volume_cam = (voxel_uniforms.inverse_transform * vec4(view.world_position, 1.0)).xyz;
+    let local_cam = (volume_cam - in.pos_scale.xyz) / in.pos_scale.w;
     if all(local_cam < vec3(1.0)) && all(local_cam > vec3(0.0)) {
         pos = local_cam;
     } else {
@@ -206,31 +561,164 @@ fn fragment(in: VertexOutput, @builtin(front_facing) facing: bool) -> FragmentOu
     }
 
     // shoot ray
-    var normal = in.normal;
-    let color = trace_brick(in.brick, &pos, dir, &normal);
-
-    // diffuse
-    let diffuse = max(dot(normal, -normalize(light_dir)), 0.0);
-
-    // indirect lighting
-    let bick_size = f32(1u << voxel_uniforms.brick_size);
-    let ao_pos = vec3<i32>(pos * bick_size + normal * 0.5);
-    let ao = voxel_ao(ao_pos, vec3<i32>(normal), in.brick);
-    let uv = glmod(
-        vec2(
-            dot(normal * pos.yzx, vec3(1.0)),
-            dot(normal * pos.zxy, vec3(1.0))
-        ),
-        vec2(1.0 / bick_size)
-    ) * bick_size;
-    let interpolated_ao = mix(mix(ao.z, ao.w, uv.x), mix(ao.y, ao.x, uv.x), uv.y);
-    let indirect = pow(interpolated_ao, 1.0 / 3.0) * 0.3;
-
-    output_color = color * (diffuse + indirect);
+    var tint = no_tint();
+    let half_size = f32(1u << (voxel_uniforms.brick_map_depth - 1u));
+    var hit = BrickHit(vec4(0.0), in.brick, pos, in.normal, vec3(0.0));
+    brick_origin = in.pos_scale.xyz + half_size;
+    brick_scale = in.pos_scale.w;
+    hit.colour = trace_brick(in.brick, &hit.pos, dir, &hit.normal, &tint);
+    hit.volume_pos = in.pos_scale.xyz + half_size + hit.pos * in.pos_scale.w;
+    if hit.colour.a == 0.0 {
+        if tint.transmittance == 1.0 {
+            discard;
+        }
+
+        // the ray left the brick through transparent voxels, follow it
+        // through the rest of the volume
+        hit = trace_volume(hit.volume_pos - hit.normal * 0.0001, dir, hit.normal, &tint);
+    }
+
+    // the depth of the first transparent voxel is written, so bricks further
+    // along the ray that reach the same hit fail the depth test instead of
+    // drawing it with less of the tint
+    var ray = FragmentRay(hit, tint, world_dir, dir, hit.volume_pos, hit.normal);
+    if tint.transmittance < 1.0 {
+        ray.surface_pos = tint.surface_pos;
+        ray.surface_normal = tint.surface_normal;
+    }
+    return ray;
+}
+
+// position of a point in brickmap space, in the world the transform puts the
+// volume in
+fn volume_to_world(transform: mat4x4<f32>, volume_pos: vec3<f32>) -> vec4<f32> {
+    let half_size = f32(1u << (voxel_uniforms.brick_map_depth - 1u));
+    return transform * vec4(volume_pos - half_size, 1.0);
+}
+
+// the traced depth, so the voxels sort with each other and with meshes by
+// what was hit rather than by the brick's cube
+fn fragment_depth(ray: FragmentRay) -> f32 {
+    let clip_pos = view.view_proj * volume_to_world(voxel_uniforms.transform, ray.surface_pos);
+    return clip_pos.z / clip_pos.w;
+}
+
+#ifdef PREPASS_PIPELINE
+
+struct FragmentOutput {
+#ifdef NORMAL_PREPASS
+    @location(0) normal: vec4<f32>,
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+    @location(1) motion_vector: vec2<f32>,
+#endif
+    @builtin(frag_depth) depth: f32,
+}
+
+@fragment
+fn fragment(in: VertexOutput, @builtin(front_facing) facing: bool) -> FragmentOutput {
+    let ray = trace_fragment(in, facing);
+
+    var out: FragmentOutput;
+    out.depth = fragment_depth(ray);
+
+#ifdef NORMAL_PREPASS
+    // the normal is transformed to world space with the inverse transpose
+    let normal = normalize((vec4(ray.surface_normal, 0.0) * voxel_uniforms.inverse_transform).xyz);
+    out.normal = vec4(normal * 0.5 + 0.5, 1.0);
+#endif
+
+#ifdef MOTION_VECTOR_PREPASS
+    // how far the surface moved on screen since the last frame, in uv. both
+    // the camera and the volume may have moved
+    let clip_pos = view.unjittered_view_proj * volume_to_world(voxel_uniforms.transform, ray.surface_pos);
+    let previous_clip_pos = previous_view_proj * volume_to_world(voxel_uniforms.previous_transform, ray.surface_pos);
+    out.motion_vector = (clip_pos.xy / clip_pos.w - previous_clip_pos.xy / previous_clip_pos.w) * vec2(0.5, -0.5);
+#endif
+
+    return out;
+}
+
+#else
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    @builtin(frag_depth) depth: f32,
+}
+
+@fragment
+fn fragment(in: VertexOutput, @builtin(front_facing) facing: bool) -> FragmentOutput {
+    var output_color = vec3(0.0);
+
+    let ray = trace_fragment(in, facing);
+    let hit = ray.hit;
+    let tint = ray.tint;
+    let world_dir = ray.world_dir;
+    let steps = debug_steps;
+
+    var ao = 1.0;
+    if hit.colour.a > 0.0 {
+        let pos = hit.pos;
+        let normal = hit.normal;
+
+        // indirect lighting
+        let bick_size = f32(1u << voxel_uniforms.brick_size);
+        let ao_pos = vec3<i32>(pos * bick_size + normal * 0.5);
+        let corners = voxel_ao(ao_pos, vec3<i32>(normal), hit.brick);
+        let uv = glmod(
+            vec2(
+                dot(normal * pos.yzx, vec3(1.0)),
+                dot(normal * pos.zxy, vec3(1.0))
+            ),
+            vec2(1.0 / bick_size)
+        ) * bick_size;
+        let interpolated_ao = mix(mix(corners.z, corners.w, uv.x), mix(corners.y, corners.x, uv.x), uv.y);
+        ao = pow(interpolated_ao, 1.0 / 3.0);
+#ifdef SCREEN_SPACE_AMBIENT_OCCLUSION
+        // ssao adds the occlusion between voxels and meshes, and between bricks
+        ao = min(ao, textureLoad(screen_space_ambient_occlusion_texture, vec2<i32>(in.clip_pos.xy), 0).r);
+#endif
+        let light = hit_light(hit);
+        output_color = shade_hit(hit, light, ao);
+
+        // anything smoother than fully rough reflects, dielectrics a little
+        // and metals in their own colour
+        let surface = unpack_surface(light);
+        if surface.x < 1.0 {
+            let reflectivity = mix(vec3(DIELECTRIC_REFLECTIVITY), hit.colour.rgb, surface.y);
+            let reflection = trace_reflection(hit.volume_pos, normal, ray.dir, surface.x, in.clip_pos.xy);
+            output_color = mix(output_color, reflection, fresnel(reflectivity, ray.dir, normal));
+        }
+    } else {
+        // the ray left the volume through transparent voxels
+        output_color = sky_colour(normalize(world_dir), get_sun().direction);
+    }
+
+    // blend in whatever transparent voxels the ray passed through
+    output_color = tint.colour + tint.transmittance * output_color;
+
+    // the first of them reflects on top of what's behind it
+    if tint.roughness < 1.0 {
+        let reflection = trace_reflection(tint.surface_pos, tint.surface_normal, ray.dir, tint.roughness, in.clip_pos.xy);
+        output_color = mix(output_color, reflection, fresnel(vec3(DIELECTRIC_REFLECTIVITY), ray.dir, tint.surface_normal));
+    }
+
+    // fade into the sky with distance
+    let debug_view = resolve_debug_view(voxel_uniforms.debug_view, view_uniforms.debug_view);
+    if hit.colour.a > 0.0 && (debug_view == 0u || debug_view == DEBUG_BRICK_WIREFRAME) {
+        let world_pos = volume_to_world(voxel_uniforms.transform, hit.volume_pos).xyz;
+        output_color = apply_fog(view_uniforms.fog, output_color, world_pos, view.world_position, get_sun().direction);
+    }
+
+    if hit.colour.a > 0.0 && debug_view != 0u {
+        output_color = debug_colour(debug_view, output_color, hit, ao, steps);
+    }
     // output_color = in.local_pos;
     
     var out: FragmentOutput;
     out.color = vec4<f32>(output_color, 1.0);
-    // out.depth = 0.0;
+    out.depth = fragment_depth(ray);
     return out;
 }
+
+#endif
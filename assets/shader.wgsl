@@ -1,4 +1,11 @@
 #import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#import alex::sky::sky_ambient
+#import alex::fog::{Fog, apply_fog}
+#import alex::debug::{
+    DEBUG_DEPTH, DEBUG_BRICK_WIREFRAME, DEBUG_BRICK_INDEX, DEBUG_STEPS, DEBUG_NORMALS,
+    DEBUG_AMBIENT_OCCLUSION, DEBUG_STREAMING, DEBUG_MATERIAL, resolve_debug_view, heatmap,
+    hash_colour, brick_wireframe, streaming_colour, material_colour,
+}
 
 const BRICK_OFFSET: u32 = 2147483648u;
 const COUNTER_BITS: u32 = 32u;
@@ -7,18 +14,28 @@ struct VoxelUniforms {
     brick_map_depth: u32,
     brick_size: u32, // brick size as a power of 2
     brick_ints: u32,
+    transform: mat4x4<f32>,
+    inverse_transform: mat4x4<f32>,
+    debug_view: u32,
 }
 
 struct MainPassUniforms {
     camera: mat4x4<f32>,
     camera_inverse: mat4x4<f32>,
     time: f32,
-    show_ray_steps: u32,
+    debug_view: u32,
     indirect_lighting: u32,
     shadows: u32,
     super_pixel_size: u32,
+    // size of the beam texture, the view in super pixels
+    beam_size: vec2<u32>,
     misc_bool: u32,
     misc_float: f32,
+    // world space direction towards the sun
+    sun_direction: vec3<f32>,
+    sun_colour: vec3<f32>,
+    // structs in uniforms have to start on 16 bytes
+    @align(16) fog: Fog,
 };
 
 @group(0) @binding(0)
@@ -31,6 +48,16 @@ var<storage, read_write> counters: array<u32>;
 var<storage, read> bricks: array<u32>;
 @group(0) @binding(4)
 var color_texture: texture_storage_3d<rgba8unorm, read>;
+// block light in red, sky occlusion in green, emission in blue and the
+// packed roughness and metalness in alpha
+@group(0) @binding(5)
+var light_texture: texture_storage_3d<rgba8unorm, read>;
+@group(0) @binding(6)
+var<storage, read> streaming_state: array<u32>;
+// material id in the low 7 bits and whether the voxel is transparent in the
+// top one
+@group(0) @binding(7)
+var material_texture: texture_3d<u32>;
 
 @group(1) @binding(0)
 var<uniform> uniforms: MainPassUniforms;
@@ -127,6 +154,9 @@ fn ray_plane(r: Ray, pos: vec3<f32>, normal: vec3<f32>) -> vec4<f32> {
 
 struct Voxel {
     col: vec4<f32>,
+    // block light in red, sky occlusion in green and emission in blue
+    light: vec4<f32>,
+    material: u32,
     pos: vec3<f32>,
     half_size: f32,
 };
@@ -152,7 +182,7 @@ fn shoot_ray(r: Ray, maximum_ratio: f32) -> HitInfo {
     if !in_bounds(pos) {
         let ray_box = ray_box_dist(Ray(pos, dir), vec3(0.0), vec3(f32(1u << voxel_uniforms.brick_map_depth)));
         if ray_box.min == 0.0 {
-            return HitInfo(false, Voxel(vec4(0.0), vec3(0.0), 0.0), vec3(0.0), vec3(0.0), 0u);
+            return HitInfo(false, Voxel(vec4(0.0), vec4(0.0), 0u, vec3(0.0), 0.0), vec3(0.0), vec3(0.0), 0u);
         }
 
         pos = pos + dir * ray_box.min;
@@ -220,6 +250,8 @@ fn shoot_ray(r: Ray, maximum_ratio: f32) -> HitInfo {
 
                     // get color of the voxel
                     var col = vec4(1.0);
+                    var light = vec4(0.0);
+                    var material = 0u;
                     if maximum_ratio == 0.0 {
                         let dim = vec3<i32>(textureDimensions(color_texture)) / brick_size;
                         let brick_pos_in_texture = vec3(
@@ -227,11 +259,14 @@ fn shoot_ray(r: Ray, maximum_ratio: f32) -> HitInfo {
                             (i32(brick.index) / dim.z) % dim.y,
                             i32(brick.index) % dim.z,
                         ) * brick_size;
-                        col = textureLoad(color_texture, brick_pos_in_texture + vec3<i32>(pos_in_brick * f32(brick_size)));
+                        let texel = brick_pos_in_texture + vec3<i32>(pos_in_brick * f32(brick_size));
+                        col = textureLoad(color_texture, texel);
+                        light = textureLoad(light_texture, texel);
+                        material = textureLoad(material_texture, texel, 0).r;
                     }
 
                     // let counter_value = f32(counters[brick.node_index]) / 100.0;
-                    return HitInfo(true, Voxel(vec4(col), voxel_pos, half_size), world_pos, normal, steps);
+                    return HitInfo(true, Voxel(vec4(col), light, material, voxel_pos, half_size), world_pos, normal, steps);
                 }
 
                 let rounded_pos = floor(pos_in_brick * f32(size)) / f32(size);
@@ -263,31 +298,36 @@ fn shoot_ray(r: Ray, maximum_ratio: f32) -> HitInfo {
         tcpotr = pos + dir * t_current - normal * 0.00004;
 
         if !in_bounds(tcpotr) {
-            return HitInfo(false, Voxel(vec4(0.0), vec3(0.0), 0.0), vec3(0.0), vec3(0.0), steps);
+            return HitInfo(false, Voxel(vec4(0.0), vec4(0.0), 0u, vec3(0.0), 0.0), vec3(0.0), vec3(0.0), steps);
         }
 
         steps += 1u;
     }
 
-    return HitInfo(false, Voxel(vec4(0.0), vec3(0.0), 0.0), vec3(0.0), vec3(0.0), steps);
+    return HitInfo(false, Voxel(vec4(0.0), vec4(0.0), 0u, vec3(0.0), 0.0), vec3(0.0), vec3(0.0), steps);
 }
 
-const light_dir = vec3<f32>(0.8, -1.0, 0.8);
-const light_colour = vec3<f32>(1.0, 1.0, 0.8);
+// emissive voxels are brighter than anything lit, so they bloom
+const EMISSION_STRENGTH = 4.0;
+// colour of the light spread from emissive voxels
+const block_light_colour = vec3<f32>(1.0, 0.8, 0.6);
 
+// pos and normal are in the volume's local space
 fn calculate_direct(material: vec4<f32>, pos: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
-    // diffuse
-    let diffuse = max(dot(normal, -normalize(light_dir)), 0.0);
+    // diffuse, the normal is transformed to world space with the inverse transpose
+    let world_normal = normalize((vec4(normal, 0.0) * voxel_uniforms.inverse_transform).xyz);
+    let diffuse = max(dot(world_normal, uniforms.sun_direction), 0.0);
 
     // shadow
     var shadow = 1.0;
-    if uniforms.shadows != 0u {
-        let shadow_ray = Ray(pos, -light_dir);
+    if uniforms.shadows != 0u && diffuse > 0.0 {
+        let local_sun_dir = (voxel_uniforms.inverse_transform * vec4(uniforms.sun_direction, 0.0)).xyz;
+        let shadow_ray = Ray(pos, normalize(local_sun_dir));
         let shadow_hit = shoot_ray(shadow_ray, 0.0);
         shadow = f32(!shadow_hit.hit);
     }
 
-    return diffuse * shadow * light_colour;
+    return diffuse * shadow * uniforms.sun_colour;
 }
 
 fn check_voxel(p: vec3<f32>) -> f32 {
@@ -334,8 +374,48 @@ fn glmod(x: vec2<f32>, y: vec2<f32>) -> vec2<f32> {
     return x - y * floor(x / y);
 }
 
+// colour of a hit in the debug view, colour is the normal shading
+fn debug_colour(debug_view: u32, colour: vec3<f32>, hit: HitInfo, ao: f32) -> vec3<f32> {
+    let volume_size = f32(1u << voxel_uniforms.brick_map_depth);
+    let volume_pos = hit.pos + volume_size / 2.0 - hit.normal * 0.0001;
+    let brick = find_brick(vec3<i32>(volume_pos), false);
+    let brick_size = volume_size / f32(1u << brick.depth);
+
+    if debug_view == DEBUG_DEPTH {
+        return heatmap(f32(brick.depth - 1u) / f32(max(voxel_uniforms.brick_map_depth - 1u, 1u)));
+    }
+    if debug_view == DEBUG_BRICK_WIREFRAME {
+        return brick_wireframe(colour, (volume_pos - vec3<f32>(brick.pos)) / brick_size, hit.normal);
+    }
+    if debug_view == DEBUG_BRICK_INDEX {
+        return hash_colour(brick.index);
+    }
+    if debug_view == DEBUG_STEPS {
+        return heatmap(f32(hit.steps) / 100.0);
+    }
+    if debug_view == DEBUG_NORMALS {
+        let world_normal = normalize((vec4(hit.normal, 0.0) * voxel_uniforms.inverse_transform).xyz);
+        return world_normal * 0.5 + 0.5;
+    }
+    if debug_view == DEBUG_AMBIENT_OCCLUSION {
+        return vec3(ao);
+    }
+    if debug_view == DEBUG_STREAMING {
+        return streaming_colour(streaming_state[brick.node_index]);
+    }
+    if debug_view == DEBUG_MATERIAL {
+        return material_colour(hit.voxel.material);
+    }
+    return colour;
+}
+
+struct FragmentOutput {
+    @location(0) colour: vec4<f32>,
+    @builtin(frag_depth) depth: f32,
+}
+
 @fragment
-fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+fn fragment(in: FullscreenVertexOutput) -> FragmentOutput {
     let clip_space = vec2(1.0, -1.0) * vec2<f32>(in.uv * 2.0 - 1.0);
     var output_colour = vec3(0.0);
 
@@ -343,9 +423,15 @@ fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
     let dir4 = uniforms.camera_inverse * vec4(clip_space.x, clip_space.y, 0.01, 1.0);
     let pos = pos4.xyz / pos4.w;
     let dir = normalize(dir4.xyz / dir4.w - pos);
-    var ray = Ray(pos, dir);
 
-    // beam optimization
+    // trace in the volume's local space. the local direction isn't
+    // normalized so distances along it stay in world units
+    let local_pos = (voxel_uniforms.inverse_transform * vec4(pos, 1.0)).xyz;
+    let local_dir = (voxel_uniforms.inverse_transform * vec4(dir, 0.0)).xyz;
+    var ray = Ray(local_pos, normalize(local_dir));
+
+    // beam optimization, start at the closest distance the surrounding super
+    // pixels found
     let beam_texture_size = textureDimensions(beam_texture);
     if all(beam_texture_size > vec2(1u)) {
         let beam_texture_pos = in.uv * vec2<f32>(beam_texture_size) - 0.5;
@@ -355,58 +441,104 @@ fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
         let dist4 = textureLoad(beam_texture, vec2<i32>(beam_texture_pos) + vec2(1, 0)).r;
         let dist = min(min(dist1, dist2), min(dist3, dist4));
         let offset = dist * 1.5 / f32(beam_texture_size.x);
-        ray.pos += ray.dir * max((dist - offset), 0.0);
-
-        let hit = shoot_ray(ray, 0.0);
-        if hit.hit {
-            // direct lighting
-            let direct_lighting = calculate_direct(hit.voxel.col, hit.pos, hit.normal);
-
-            // aproximate indirect with ambient and voxel ao
-            var indirect_lighting = vec3(0.3);
-            if uniforms.indirect_lighting != 0u {
-                let offset = hit.normal * hit.voxel.half_size;
-                let ao = voxel_ao(hit.voxel.pos + offset, offset.zxy, offset.yzx);
-                let uv = glmod(
-                    vec2(
-                        dot(hit.normal * hit.pos.yzx, vec3(1.0)),
-                        dot(hit.normal * hit.pos.zxy, vec3(1.0))
-                    ),
-                    vec2(hit.voxel.half_size)
-                ) / (hit.voxel.half_size);
-
-                var interpolated_ao = mix(mix(ao.z, ao.w, uv.x), mix(ao.y, ao.x, uv.x), uv.y);
-                interpolated_ao = pow(interpolated_ao, 1.0 / 3.0);
-
-                indirect_lighting = vec3(interpolated_ao * 0.3);
-            }
+        ray.pos += local_dir * max((dist - offset), 0.0);
+    }
+
+    let hit = shoot_ray(ray, 0.0);
+    let debug_view = resolve_debug_view(voxel_uniforms.debug_view, uniforms.debug_view);
+    if !hit.hit && debug_view != DEBUG_STEPS {
+        // leave the pixel to the other volumes and the clear colour
+        discard;
+    }
 
-            // final blend
-            output_colour = (direct_lighting + indirect_lighting) * hit.voxel.col.rgb;
-        } else {
-            output_colour = vec3(0.2);
+    if hit.hit {
+        // direct lighting
+        let direct_lighting = calculate_direct(hit.voxel.col, hit.pos, hit.normal);
+
+        // aproximate indirect with ambient and voxel ao
+        let ambient = sky_ambient(uniforms.sun_direction);
+        var indirect_lighting = ambient;
+        var ao = 1.0;
+        if uniforms.indirect_lighting != 0u || debug_view == DEBUG_AMBIENT_OCCLUSION {
+            let offset = hit.normal * hit.voxel.half_size;
+            let corners = voxel_ao(hit.voxel.pos + offset, offset.zxy, offset.yzx);
+            let uv = glmod(
+                vec2(
+                    dot(hit.normal * hit.pos.yzx, vec3(1.0)),
+                    dot(hit.normal * hit.pos.zxy, vec3(1.0))
+                ),
+                vec2(hit.voxel.half_size)
+            ) / (hit.voxel.half_size);
+
+            let interpolated_ao = mix(mix(corners.z, corners.w, uv.x), mix(corners.y, corners.x, uv.x), uv.y);
+            ao = pow(interpolated_ao, 1.0 / 3.0);
+        }
+        if uniforms.indirect_lighting != 0u {
+            indirect_lighting = ao * ambient;
         }
 
-        // let posawoeigh = in.uv * vec2<f32>(beam_texture_size);
-        // output_colour = textureLoad(beam_texture, vec2<i32>(posawoeigh)).rgb;
+        // emission, the light spread from nearby emissive voxels and how much
+        // of the sky reaches the voxel, baked when the world was loaded
+        let light = hit.voxel.light;
+        let block_light = block_light_colour * light.r * light.r + light.b * EMISSION_STRENGTH;
+        let sky_light = (1.0 - light.g) * (1.0 - light.g);
+
+        // final blend
+        output_colour = ((direct_lighting + indirect_lighting) * sky_light + block_light) * hit.voxel.col.rgb;
 
-        if uniforms.show_ray_steps != 0u {
-            output_colour = vec3(f32(hit.steps) / 100.0);
-            // let v = min(f32(hit.steps) / 200.0, 0.3);
-            // output_colour = 0.6 - 0.6 * cos(6.3 * 2.0 * v + vec3(0.0, 23.0, 21.0));
+        // fade into the sky with distance
+        if debug_view == 0u || debug_view == DEBUG_BRICK_WIREFRAME {
+            let world_pos = (voxel_uniforms.transform * vec4(hit.pos, 1.0)).xyz;
+            output_colour = apply_fog(uniforms.fog, output_colour, world_pos, pos, uniforms.sun_direction);
         }
-    } else {
-        let maximum_ratio = 0.02 / f32(beam_texture_size.x);
-        let hit = shoot_ray(ray, maximum_ratio);
-        if hit.hit {
-            output_colour = vec3(length(hit.pos - pos));
-            // output_colour = hit.voxel.col.rgb;
-        } else {
-            output_colour = vec3(10000000.0);
-            // output_colour = vec3(0.2);
+
+        if debug_view != 0u {
+            output_colour = debug_colour(debug_view, output_colour, hit, ao);
         }
+    } else if debug_view == DEBUG_STEPS {
+        // misses show how long the ray took to leave the volume
+        output_colour = heatmap(f32(hit.steps) / 100.0);
+    }
+
+    // depth of the traced hit so volumes sort against each other and meshes
+    var depth = 0.0;
+    if hit.hit {
+        let world_pos = voxel_uniforms.transform * vec4(hit.pos, 1.0);
+        let clip_pos = uniforms.camera * world_pos;
+        depth = clip_pos.z / clip_pos.w;
     }
 
     output_colour = max(output_colour, vec3(0.0));
-    return vec4<f32>(output_colour, 1.0);
+    return FragmentOutput(vec4<f32>(output_colour, 1.0), depth);
+}
+
+// low resolution pre-pass, one ray per super pixel. rays stop at the first
+// voxel that is smaller than the cone the super pixel covers, so the distance
+// written is never past anything a full resolution ray in it could hit
+@fragment
+fn beam(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let clip_space = vec2(1.0, -1.0) * vec2<f32>(in.uv * 2.0 - 1.0);
+
+    let pos4 = uniforms.camera_inverse * vec4(clip_space.x, clip_space.y, 1.0, 1.0);
+    let dir4 = uniforms.camera_inverse * vec4(clip_space.x, clip_space.y, 0.01, 1.0);
+    let pos = pos4.xyz / pos4.w;
+    let dir = normalize(dir4.xyz / dir4.w - pos);
+
+    // angle between neighbouring super pixels, doubled to cover the 2x2
+    // neighbourhood the full resolution pass reads. the beam texture isn't
+    // bound in this pass, so its size comes from the uniforms
+    let super_pixel = 2.0 / f32(uniforms.beam_size.x);
+    let side4 = uniforms.camera_inverse * vec4(clip_space.x + super_pixel, clip_space.y, 0.01, 1.0);
+    let side_dir = normalize(side4.xyz / side4.w - pos);
+    let maximum_ratio = 2.0 * length(side_dir - dir);
+
+    let local_pos = (voxel_uniforms.inverse_transform * vec4(pos, 1.0)).xyz;
+    let local_dir = normalize((voxel_uniforms.inverse_transform * vec4(dir, 0.0)).xyz);
+    let hit = shoot_ray(Ray(local_pos, local_dir), maximum_ratio);
+    if !hit.hit {
+        discard;
+    }
+
+    let world_pos = (voxel_uniforms.transform * vec4(hit.pos, 1.0)).xyz;
+    return vec4(vec3(length(world_pos - pos)), 1.0);
 }
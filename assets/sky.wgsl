@@ -0,0 +1,52 @@
+#define_import_path alex::sky
+
+// a cheap analytic sky in linear hdr, not physically based but close enough
+// to read as day, sunset and night
+
+const day_zenith = vec3<f32>(0.18, 0.35, 0.75);
+const day_horizon = vec3<f32>(0.6, 0.75, 0.9);
+const night_zenith = vec3<f32>(0.004, 0.006, 0.015);
+const night_horizon = vec3<f32>(0.015, 0.02, 0.035);
+const sunset_colour = vec3<f32>(1.0, 0.4, 0.1);
+const sun_colour = vec3<f32>(1.0, 0.9, 0.75);
+const ground_colour = vec3<f32>(0.12, 0.1, 0.08);
+
+// how much of the day sky is lit for a sun at that height
+fn daylight(sun_dir: vec3<f32>) -> f32 {
+    return smoothstep(-0.15, 0.2, sun_dir.y);
+}
+
+// colour of the sky looking along dir. both directions are normalized and
+// in world space, sun_dir points towards the sun
+fn sky_colour(dir: vec3<f32>, sun_dir: vec3<f32>) -> vec3<f32> {
+    let day = daylight(sun_dir);
+    let zenith = mix(night_zenith, day_zenith, day);
+    let horizon = mix(night_horizon, day_horizon, day);
+
+    // brighter and hazier towards the horizon
+    let height = max(dir.y, 0.0);
+    var colour = mix(horizon, zenith, pow(height, 0.5));
+
+    // sunset glow around the sun while it's low
+    let sun_amount = max(dot(dir, sun_dir), 0.0);
+    let sunset = (1.0 - smoothstep(0.0, 0.4, abs(sun_dir.y))) * pow(1.0 - height, 4.0);
+    colour += sunset_colour * sunset * (0.2 + 0.8 * pow(sun_amount, 4.0));
+
+    // sun disc and a soft halo
+    let above_horizon = smoothstep(-0.02, 0.02, sun_dir.y);
+    colour += sun_colour * above_horizon * (smoothstep(0.9995, 0.9998, sun_amount) * 40.0 + pow(sun_amount, 64.0) * 0.5);
+
+    // darker ground below the horizon
+    if dir.y < 0.0 {
+        colour = mix(colour, ground_colour * (0.05 + day), smoothstep(0.0, 0.1, -dir.y));
+    }
+
+    return colour;
+}
+
+// light arriving from the whole sky, for ambient lighting
+fn sky_ambient(sun_dir: vec3<f32>) -> vec3<f32> {
+    let day = daylight(sun_dir);
+    let sunset = 1.0 - smoothstep(0.0, 0.4, abs(sun_dir.y));
+    return mix(night_horizon * 3.0, 0.3 * (day_zenith + day_horizon), day) + 0.1 * sunset * sunset_colour;
+}
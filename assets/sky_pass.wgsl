@@ -0,0 +1,20 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#import alex::sky::sky_colour
+
+struct SkyUniforms {
+    camera_inverse: mat4x4<f32>,
+    sun_direction: vec3<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> uniforms: SkyUniforms;
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let clip_space = vec2(1.0, -1.0) * vec2<f32>(in.uv * 2.0 - 1.0);
+    let pos4 = uniforms.camera_inverse * vec4(clip_space.x, clip_space.y, 1.0, 1.0);
+    let dir4 = uniforms.camera_inverse * vec4(clip_space.x, clip_space.y, 0.01, 1.0);
+    let dir = normalize(dir4.xyz / dir4.w - pos4.xyz / pos4.w);
+
+    return vec4(sky_colour(dir, uniforms.sun_direction), 1.0);
+}
@@ -0,0 +1,100 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+// accumulates the jittered low resolution frames of a camera into its full
+// resolution output. the history is moved along the motion vectors, or
+// reprojected with the depth where there are none, and clamped to the colours
+// around the new sample so it doesn't ghost
+
+struct UpscaleUniforms {
+    // from this frame's clip space to the last one's
+    reprojection: mat4x4<f32>,
+    // how far the projection was jittered, in pixels of the colour texture
+    jitter: vec2<f32>,
+    max_history: f32,
+    reset: u32,
+    // whether the prepass wrote the motion vectors of the voxels
+    motion_vectors: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> uniforms: UpscaleUniforms;
+@group(0) @binding(1)
+var colour_texture: texture_2d<f32>;
+@group(0) @binding(2)
+var depth_texture: texture_depth_2d;
+@group(0) @binding(3)
+var history_texture: texture_2d<f32>;
+@group(0) @binding(4)
+var history_sampler: sampler;
+@group(0) @binding(5)
+var motion_vector_texture: texture_2d<f32>;
+
+struct FragmentOutput {
+    @location(0) output: vec4<f32>,
+    // alpha holds how many frames worth of samples the history has
+    @location(1) history: vec4<f32>,
+}
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> FragmentOutput {
+    let size = vec2<f32>(textureDimensions(colour_texture));
+    let max_texel = vec2<i32>(size) - 1;
+
+    // the jitter moved the image by -jitter pixels, the texel whose sample
+    // landed closest to this pixel is found by moving back
+    let pos = in.uv * size - uniforms.jitter;
+    let texel = clamp(vec2<i32>(floor(pos)), vec2(0), max_texel);
+    let colour = textureLoad(colour_texture, texel, 0).rgb;
+
+    // samples further from the centre of this pixel count for less
+    let offset = vec2<f32>(texel) + 0.5 - pos;
+    let weight = exp(-2.0 * dot(offset, offset));
+
+    // the colours around the sample bound the history, and the closest depth
+    // keeps the edges of the foreground moving with it
+    var minimum = colour;
+    var maximum = colour;
+    var depth = 0.0;
+    var closest = texel;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let neighbour = clamp(texel + vec2(x, y), vec2(0), max_texel);
+            let neighbour_colour = textureLoad(colour_texture, neighbour, 0).rgb;
+            minimum = min(minimum, neighbour_colour);
+            maximum = max(maximum, neighbour_colour);
+            let neighbour_depth = textureLoad(depth_texture, neighbour, 0);
+            if neighbour_depth > depth {
+                depth = neighbour_depth;
+                closest = neighbour;
+            }
+        }
+    }
+
+    // where this pixel was last frame, the sky at depth 0 reprojects as a
+    // direction
+    let clip_pos = vec4((in.uv * 2.0 - 1.0) * vec2(1.0, -1.0), depth, 1.0);
+    let previous_clip_pos = uniforms.reprojection * clip_pos;
+    var previous_uv = (previous_clip_pos.xy / previous_clip_pos.w) * vec2(0.5, -0.5) + 0.5;
+    var in_front = previous_clip_pos.w > 0.0;
+
+    // the prepass only covers geometry, the sky keeps the reprojection
+    if uniforms.motion_vectors != 0u && depth > 0.0 {
+        previous_uv = in.uv - textureLoad(motion_vector_texture, closest, 0).xy;
+        in_front = true;
+    }
+
+    var history = vec4(0.0);
+    let on_screen = all(previous_uv >= vec2(0.0)) && all(previous_uv <= vec2(1.0));
+    if uniforms.reset == 0u && in_front && on_screen {
+        history = textureSampleLevel(history_texture, history_sampler, previous_uv, 0.0);
+        history = vec4(clamp(history.rgb, minimum, maximum), history.a);
+    }
+
+    let total = history.a + weight;
+    let result = (history.rgb * history.a + colour * weight) / total;
+
+    var out: FragmentOutput;
+    out.output = vec4(result, 1.0);
+    out.history = vec4(result, min(total, uniforms.max_history));
+    return out;
+}
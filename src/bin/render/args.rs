@@ -0,0 +1,201 @@
+use alex::render_pipeline::VoxelRenderer;
+use anyhow::{anyhow, Context, Result};
+use bevy::prelude::*;
+use std::path::PathBuf;
+
+const USAGE: &str = "usage: render [options]
+
+world, one of:
+  --anvil <dir>          minecraft region folder to load
+  --depth <n>            brickmap depth of the anvil world (default 9)
+  --world <file>         paged world written by `CpuVoxelWorld::load_anvil_paged`
+
+camera, either a path or a single pose:
+  --path <file>          json list of poses, [{\"position\": [x, y, z], \"look_at\": [x, y, z]}, ...]
+  --position <x,y,z>     (default 21.0,19.8,-31.1)
+  --look-at <x,y,z>      (default 0,0,0)
+  --fov <radians>        vertical field of view (default 1.57)
+
+output:
+  --size <width>x<height> (default 1920x1080)
+  --out <dir>            folder the pngs are written to (default renders)
+  --renderer <name>      instanced, fullscreen or reference (default instanced)
+  --hour <hour>          time of day (default 10)
+
+reference renderer, on the cpu and without tonemapping:
+  --samples <n>          rays per pixel (default 16)
+  --bounces <n>          path traced bounces, 0 for the gpu shading (default 0)
+
+  --software             render on the fallback (software) adapter
+  --max-frames <n>       frames to wait for streaming to converge at each pose
+                         before rendering anyway (default 2000)";
+
+/// A camera pose to render, in world space.
+#[derive(Clone, Copy)]
+pub struct Pose {
+    pub position: Vec3,
+    pub look_at: Vec3,
+}
+
+pub enum Renderer {
+    Gpu(VoxelRenderer),
+    /// the cpu reference renderer
+    Reference {
+        samples: u32,
+        bounces: u32,
+    },
+}
+
+pub enum WorldSource {
+    Anvil { path: PathBuf, depth: u32 },
+    Paged(PathBuf),
+}
+
+pub struct Args {
+    pub world: WorldSource,
+    pub poses: Vec<Pose>,
+    pub fov: f32,
+    pub size: UVec2,
+    pub out: PathBuf,
+    pub renderer: Renderer,
+    pub hour: f32,
+    pub software: bool,
+    pub max_frames: u32,
+}
+
+impl Args {
+    pub fn parse() -> Result<Self> {
+        let mut anvil = None;
+        let mut depth = 9;
+        let mut world = None;
+        let mut path = None;
+        let mut renderer = String::from("instanced");
+        let mut samples = 16;
+        let mut bounces = 0;
+        let mut pose = Pose {
+            position: Vec3::new(21.035963, 19.771912, -31.12883),
+            look_at: Vec3::ZERO,
+        };
+        let mut args = Args {
+            world: WorldSource::Paged(PathBuf::new()),
+            poses: Vec::new(),
+            fov: 1.57,
+            size: UVec2::new(1920, 1080),
+            out: PathBuf::from("renders"),
+            renderer: Renderer::Gpu(VoxelRenderer::Instanced),
+            hour: 10.0,
+            software: false,
+            max_frames: 2000,
+        };
+
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            let mut value = || {
+                iter.next()
+                    .ok_or_else(|| anyhow!("missing value for {}\n\n{}", arg, USAGE))
+            };
+            match arg.as_str() {
+                "--anvil" => anvil = Some(PathBuf::from(value()?)),
+                "--depth" => depth = value()?.parse().context("invalid --depth")?,
+                "--world" => world = Some(PathBuf::from(value()?)),
+                "--path" => path = Some(PathBuf::from(value()?)),
+                "--position" => pose.position = parse_vec3(&value()?)?,
+                "--look-at" => pose.look_at = parse_vec3(&value()?)?,
+                "--fov" => args.fov = value()?.parse().context("invalid --fov")?,
+                "--size" => {
+                    let value = value()?;
+                    let (width, height) = value
+                        .split_once('x')
+                        .ok_or_else(|| anyhow!("--size should look like 1920x1080"))?;
+                    args.size = UVec2::new(width.parse()?, height.parse()?);
+                }
+                "--out" => args.out = PathBuf::from(value()?),
+                "--renderer" => renderer = value()?,
+                "--samples" => samples = value()?.parse().context("invalid --samples")?,
+                "--bounces" => bounces = value()?.parse().context("invalid --bounces")?,
+                "--hour" => args.hour = value()?.parse().context("invalid --hour")?,
+                "--software" => args.software = true,
+                "--max-frames" => {
+                    args.max_frames = value()?.parse().context("invalid --max-frames")?
+                }
+                // asking for help isn't a failure, scripts shouldn't see it as one
+                "--help" | "-h" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                other => return Err(anyhow!("unknown argument {}\n\n{}", other, USAGE)),
+            }
+        }
+
+        args.world = match (anvil, world) {
+            (Some(path), None) => WorldSource::Anvil { path, depth },
+            (None, Some(path)) => WorldSource::Paged(path),
+            _ => return Err(anyhow!("pass one of --anvil or --world\n\n{}", USAGE)),
+        };
+        args.poses = match path {
+            Some(path) => load_path(&path)?,
+            None => vec![pose],
+        };
+        args.renderer = match renderer.as_str() {
+            "instanced" => Renderer::Gpu(VoxelRenderer::Instanced),
+            "fullscreen" => Renderer::Gpu(VoxelRenderer::Fullscreen),
+            "reference" => Renderer::Reference { samples, bounces },
+            other => return Err(anyhow!("unknown renderer {}", other)),
+        };
+        if args.size.x == 0 || args.size.y == 0 {
+            return Err(anyhow!("--size can't be empty"));
+        }
+
+        Ok(args)
+    }
+}
+
+fn parse_vec3(value: &str) -> Result<Vec3> {
+    let components = value
+        .split(',')
+        .map(|component| component.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid vector {}", value))?;
+    match components[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(anyhow!("expected 3 components in {}", value)),
+    }
+}
+
+fn load_path(path: &PathBuf) -> Result<Vec<Pose>> {
+    let file = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read camera path {}", path.display()))?;
+    let json: serde_json::Value = serde_json::from_str(&file)?;
+
+    let vec3 = |value: &serde_json::Value| -> Option<Vec3> {
+        let array = value.as_array()?;
+        match array[..] {
+            [ref x, ref y, ref z] => Some(Vec3::new(
+                x.as_f64()? as f32,
+                y.as_f64()? as f32,
+                z.as_f64()? as f32,
+            )),
+            _ => None,
+        }
+    };
+
+    let poses = json
+        .as_array()
+        .ok_or_else(|| anyhow!("camera path should be a list of poses"))?;
+    if poses.is_empty() {
+        return Err(anyhow!("camera path {} has no poses", path.display()));
+    }
+    poses
+        .iter()
+        .enumerate()
+        .map(|(i, pose)| {
+            let pose = || {
+                Some(Pose {
+                    position: vec3(pose.get("position")?)?,
+                    look_at: vec3(pose.get("look_at")?)?,
+                })
+            };
+            pose().ok_or_else(|| anyhow!("pose {} needs a position and look_at", i))
+        })
+        .collect()
+}
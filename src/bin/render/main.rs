@@ -0,0 +1,372 @@
+//! Renders a world from a list of camera poses into pngs without opening a
+//! window. Each pose is held until streaming has converged for it.
+
+use alex::{
+    readback::{ReadbackChannel, ReadbackPlugin, ReadbackRequest},
+    render_pipeline::{
+        CpuVoxelWorld, ExtractedSun, MainPassSettings, ReferenceCamera, ReferenceMode,
+        ReferenceRenderer, ReferenceSettings, SkyCamera, StreamingSettings, TimeOfDay, VoxelFog,
+        VoxelVolume, VoxelVolumeBundle, VoxelWorldStatsResource,
+    },
+};
+use anyhow::Result;
+use args::{Args, Pose, Renderer, WorldSource};
+use bevy::{
+    app::ScheduleRunnerPlugin,
+    prelude::*,
+    render::{
+        camera::RenderTarget,
+        render_resource::*,
+        renderer::{initialize_renderer, RenderInstance},
+        settings::{RenderCreation, WgpuSettings},
+        RenderPlugin,
+    },
+    tasks::block_on,
+    window::ExitCondition,
+    winit::WinitPlugin,
+};
+use std::{sync::Arc, time::Duration};
+
+mod args;
+
+/// frames streaming has to stay idle before a pose counts as converged
+const SETTLE_FRAMES: u32 = 5;
+
+fn main() {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // the reference renderer doesn't need bevy's renderer at all
+    if let Renderer::Reference { samples, bounces } = args.renderer {
+        if let Err(e) = render_reference(&args, samples, bounces) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    App::new()
+        .add_plugins((
+            DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    close_when_requested: false,
+                })
+                .set(RenderPlugin {
+                    render_creation: render_creation(args.software),
+                })
+                .disable::<WinitPlugin>(),
+            ScheduleRunnerPlugin::run_loop(Duration::ZERO),
+            alex::render_pipeline::VoxelPlugin,
+            ReadbackPlugin,
+        ))
+        .insert_resource(Msaa::Off)
+        // streaming doesn't need to hide anything, switch levels instantly
+        .insert_resource(StreamingSettings {
+            transition_time: 0.0,
+            ..default()
+        })
+        .insert_resource(TimeOfDay {
+            hour: args.hour,
+            ..default()
+        })
+        .insert_resource(Capture {
+            pose: 0,
+            frames: 0,
+            settled: 0,
+            requested: false,
+            failed: 0,
+        })
+        .insert_resource(Settings(args))
+        .add_systems(Startup, setup)
+        .add_systems(Update, (update_pose, write_images).chain())
+        .run();
+}
+
+/// the software adapter has to be picked before the render plugin starts
+fn render_creation(software: bool) -> RenderCreation {
+    let settings = WgpuSettings::default();
+    if !software {
+        return settings.into();
+    }
+
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: settings.backends.unwrap_or(wgpu::Backends::all()),
+        dx12_shader_compiler: settings.dx12_shader_compiler.clone(),
+    });
+    let (device, queue, adapter_info, adapter) = block_on(initialize_renderer(
+        &instance,
+        &settings,
+        &wgpu::RequestAdapterOptions {
+            power_preference: settings.power_preference,
+            force_fallback_adapter: true,
+            compatible_surface: None,
+        },
+    ));
+    RenderCreation::Manual(
+        device,
+        queue,
+        adapter_info,
+        adapter,
+        RenderInstance(Arc::new(instance)),
+    )
+}
+
+fn load_world(source: &WorldSource) -> Result<CpuVoxelWorld> {
+    match source {
+        WorldSource::Anvil { path, depth } => Ok(CpuVoxelWorld::load_anvil(path.clone(), *depth)),
+        WorldSource::Paged(path) => CpuVoxelWorld::load_paged(path, 1 << 14),
+    }
+}
+
+fn camera_transform(pose: Pose) -> Transform {
+    Transform::from_translation(pose.position).looking_at(pose.look_at, Vec3::Y)
+}
+
+/// renders every pose on the cpu, with the same camera and sun as the gpu
+/// renderers would use
+fn render_reference(args: &Args, samples: u32, bounces: u32) -> Result<()> {
+    let world = load_world(&args.world)?;
+    let time_of_day = TimeOfDay {
+        hour: args.hour,
+        ..default()
+    };
+    let renderer = ReferenceRenderer::new(
+        &world,
+        ReferenceSettings {
+            mode: match bounces {
+                0 => ReferenceMode::Direct,
+                bounces => ReferenceMode::PathTraced { bounces },
+            },
+            samples,
+            sun: ExtractedSun::from_time_of_day(&time_of_day, true),
+            ..default()
+        },
+    );
+
+    std::fs::create_dir_all(&args.out)?;
+    for (i, &pose) in args.poses.iter().enumerate() {
+        let camera = ReferenceCamera {
+            transform: camera_transform(pose),
+            projection: PerspectiveProjection {
+                fov: args.fov,
+                ..default()
+            },
+            size: args.size,
+        };
+        let image = to_srgb(&renderer.render(&camera));
+
+        let path = args.out.join(format!("{:04}.png", i));
+        image.try_into_dynamic()?.save(&path)?;
+        println!("wrote {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// clamps the linear hdr output of the reference renderer into an srgb image
+fn to_srgb(image: &Image) -> Image {
+    let pixels = bytemuck::pod_collect_to_vec::<u8, [f32; 4]>(&image.data);
+    let data = pixels
+        .iter()
+        .flat_map(|&[r, g, b, _]| Color::rgb_linear(r, g, b).as_rgba_u8())
+        .collect();
+    Image::new(
+        image.texture_descriptor.size,
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+#[derive(Resource, Deref)]
+struct Settings(Args);
+
+/// where the capture of the camera path is at
+#[derive(Resource)]
+struct Capture {
+    pose: usize,
+    /// frames spent at the current pose
+    frames: u32,
+    /// frames in a row streaming had nothing left to do
+    settled: u32,
+    requested: bool,
+    /// images that couldn't be written
+    failed: usize,
+}
+
+#[derive(Resource)]
+struct RenderTexture(Handle<Image>);
+
+fn setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut voxel_worlds: ResMut<Assets<CpuVoxelWorld>>,
+    settings: Res<Settings>,
+) {
+    let world =
+        load_world(&settings.world).unwrap_or_else(|e| panic!("failed to load the world: {}", e));
+    commands.spawn(VoxelVolumeBundle {
+        world: voxel_worlds.add(world),
+        ..default()
+    });
+
+    commands.spawn(DirectionalLightBundle {
+        directional_light: DirectionalLight {
+            shadows_enabled: true,
+            ..default()
+        },
+        ..default()
+    });
+
+    // rendered straight into an srgb image so it can be written as is
+    let mut render_texture = Image::new_fill(
+        Extent3d {
+            width: settings.size.x,
+            height: settings.size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0; 4],
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    render_texture.texture_descriptor.usage =
+        TextureUsages::TEXTURE_BINDING | TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC;
+    let render_texture = images.add(render_texture);
+
+    let Renderer::Gpu(renderer) = settings.renderer else {
+        unreachable!("the reference renderer doesn't start the app");
+    };
+    let pose = settings.poses[0];
+    commands.spawn((
+        Camera3dBundle {
+            transform: camera_transform(pose),
+            camera: Camera {
+                hdr: true,
+                target: RenderTarget::Image(render_texture.clone()),
+                ..default()
+            },
+            projection: Projection::Perspective(PerspectiveProjection {
+                fov: settings.fov,
+                near: 0.001,
+                far: 100.0,
+                ..default()
+            }),
+            camera_3d: Camera3d {
+                depth_texture_usages: (TextureUsages::RENDER_ATTACHMENT
+                    | TextureUsages::TEXTURE_BINDING)
+                    .into(),
+                ..default()
+            },
+            ..default()
+        },
+        renderer,
+        MainPassSettings::default(),
+        SkyCamera,
+        VoxelFog::default(),
+    ));
+    commands.insert_resource(RenderTexture(render_texture));
+}
+
+/// hold the camera at the current pose until streaming has converged, then
+/// ask for the frame
+fn update_pose(
+    mut capture: ResMut<Capture>,
+    mut request: ResMut<ReadbackRequest>,
+    mut cameras: Query<(&mut Transform, &bevy::render::primitives::Frustum), With<Camera3d>>,
+    mut voxel_volumes: Query<&mut VoxelVolume>,
+    render_texture: Res<RenderTexture>,
+    voxel_stats: Res<VoxelWorldStatsResource>,
+    settings: Res<Settings>,
+) {
+    let Some(&pose) = settings.poses.get(capture.pose) else {
+        return;
+    };
+    let (mut transform, frustum) = cameras.single_mut();
+    *transform = camera_transform(pose);
+    for mut voxel_volume in voxel_volumes.iter_mut() {
+        voxel_volume.streaming_pos = pose.position;
+        voxel_volume.streaming_velocity = Vec3::ZERO;
+        voxel_volume.streaming_frustum = Some(*frustum);
+    }
+
+    if capture.requested {
+        return;
+    }
+
+    capture.frames += 1;
+    let voxel_stats = voxel_stats.lock().unwrap();
+    if voxel_stats.nodes > 0 && voxel_stats.streaming_pending == 0 {
+        capture.settled += 1;
+    } else {
+        capture.settled = 0;
+    }
+
+    let converged = capture.settled >= SETTLE_FRAMES;
+    if converged || capture.frames >= settings.max_frames {
+        if !converged {
+            warn!(
+                "streaming didn't converge for pose {} after {} frames, {} nodes still pending",
+                capture.pose, capture.frames, voxel_stats.streaming_pending
+            );
+        }
+        capture.requested = true;
+        request.0 = Some((capture.pose, render_texture.0.clone()));
+    }
+}
+
+/// write the frames that came back, and move on to the next pose
+fn write_images(
+    mut capture: ResMut<Capture>,
+    mut request: ResMut<ReadbackRequest>,
+    mut exit: EventWriter<bevy::app::AppExit>,
+    channel: Res<ReadbackChannel>,
+    settings: Res<Settings>,
+) {
+    for (pose, image) in channel.receiver.try_iter() {
+        // the request stays up for a frame or two, ignore the extra copies
+        if pose != capture.pose || !capture.requested {
+            continue;
+        }
+
+        let path = settings.out.join(format!("{:04}.png", pose));
+        let result = std::fs::create_dir_all(&settings.out)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| Ok(image.try_into_dynamic()?.save(&path)?));
+        match result {
+            Ok(()) => info!("wrote {}", path.display()),
+            Err(e) => {
+                error!("failed to write {}: {}", path.display(), e);
+                capture.failed += 1;
+            }
+        }
+
+        request.0 = None;
+        *capture = Capture {
+            pose: pose + 1,
+            frames: 0,
+            settled: 0,
+            requested: false,
+            failed: capture.failed,
+        };
+    }
+
+    if capture.pose >= settings.poses.len() {
+        // batch jobs go by the exit code, `AppExit` can't carry one
+        if capture.failed > 0 {
+            error!(
+                "{} of {} images failed to write",
+                capture.failed,
+                settings.poses.len()
+            );
+            std::process::exit(1);
+        }
+        exit.send(bevy::app::AppExit);
+    }
+}
@@ -0,0 +1,4 @@
+//! The voxel renderer, shared by the game, the render binary and the tests.
+
+pub mod readback;
+pub mod render_pipeline;
@@ -1,19 +1,26 @@
+use alex::render_pipeline::{
+    CpuVoxelWorld, DebugView, MainPassSettings, SkyCamera, TemporalUpscale, VoxelFog,
+    VoxelRenderer, VoxelVolume, VoxelVolumeBundle,
+};
 use bevy::{
-    core_pipeline::{bloom::BloomSettings, fxaa::Fxaa, tonemapping::Tonemapping},
+    core_pipeline::{
+        bloom::BloomSettings,
+        fxaa::Fxaa,
+        prepass::{DepthPrepass, MotionVectorPrepass, NormalPrepass},
+        tonemapping::Tonemapping,
+    },
     prelude::*,
     render::{
         camera::RenderTarget,
+        primitives::Frustum,
         render_resource::*,
         texture::{ImageSampler, ImageSamplerDescriptor},
     },
     window::{PrimaryWindow, WindowResized, WindowScaleFactorChanged},
 };
-// use bevy_atmosphere::prelude::*;
 use character::CharacterEntity;
-use render_pipeline::{VoxelVolume, VoxelVolumeBundle};
 
 mod character;
-mod render_pipeline;
 mod ui;
 
 fn main() {
@@ -26,8 +33,7 @@ fn main() {
                 }),
                 ..default()
             }),
-            // AtmospherePlugin,
-            render_pipeline::VoxelPlugin,
+            alex::render_pipeline::VoxelPlugin,
             character::CharacterPlugin,
             ui::UiPlugin,
         ))
@@ -41,11 +47,17 @@ fn main() {
 #[derive(Resource)]
 struct CameraData {
     render_texture: Handle<Image>,
+    upscaled_texture: Handle<Image>,
     sprite: Entity,
 }
 
-fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
-    // we use a render texture to downscale the main pass
+fn setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut voxel_worlds: ResMut<Assets<CpuVoxelWorld>>,
+) {
+    // we use a render texture to downscale the main pass, `TemporalUpscale`
+    // keeps it at the render scale of the window sized upscaled texture
     let mut render_texture = Image::new_fill(
         Extent3d {
             width: 100,
@@ -59,10 +71,36 @@ fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
     render_texture.texture_descriptor.usage =
         TextureUsages::TEXTURE_BINDING | TextureUsages::RENDER_ATTACHMENT;
     render_texture.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor::nearest());
+    let upscaled_texture = images.add(render_texture.clone());
     let render_texture = images.add(render_texture);
 
-    // add voxel volume
-    commands.spawn(VoxelVolumeBundle::default());
+    // load world (slooowwww the first time) and add voxel volume. fine bricks
+    // are paged in from disk as streaming needs them
+    let world = CpuVoxelWorld::load_anvil_paged(
+        "assets/worlds/imperial_city",
+        9,
+        "assets/worlds/imperial_city.bmap",
+        3,
+        1 << 14,
+    )
+    .unwrap_or_else(|e| {
+        error!("failed to load paged world, loading it into memory: {}", e);
+        CpuVoxelWorld::load_anvil("assets/worlds/imperial_city", 9)
+    });
+    commands.spawn(VoxelVolumeBundle {
+        world: voxel_worlds.add(world),
+        ..default()
+    });
+
+    // the sun, moved by `TimeOfDay`. voxels trace their own shadows towards
+    // it when shadows are enabled
+    commands.spawn(DirectionalLightBundle {
+        directional_light: DirectionalLight {
+            shadows_enabled: true,
+            ..default()
+        },
+        ..default()
+    });
 
     // add camera with character controller
     let character_transform =
@@ -82,6 +120,13 @@ fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
                 far: 100.0,
                 ..default()
             }),
+            // gpu culling and upscaling read the depth back
+            camera_3d: Camera3d {
+                depth_texture_usages: (TextureUsages::RENDER_ATTACHMENT
+                    | TextureUsages::TEXTURE_BINDING)
+                    .into(),
+                ..default()
+            },
             // tonemapping: Tonemapping::None,
             ..default()
         },
@@ -89,15 +134,28 @@ fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
             look_at: -character_transform.local_z(),
             ..default()
         },
+        VoxelRenderer::default(),
+        MainPassSettings::default(),
         BloomSettings::default(),
         Fxaa::default(),
-        // AtmosphereCamera::default(),
+        SkyCamera,
+        VoxelFog::default(),
+        DebugView::default(),
+        TemporalUpscale {
+            output: upscaled_texture.clone(),
+            ..default()
+        },
+        // the upscaling follows the motion vectors of the voxels, ssao reads
+        // their depth and normals
+        DepthPrepass,
+        NormalPrepass,
+        MotionVectorPrepass,
     ));
 
     // add sprite and camera to render the render texture
     let sprite = commands
         .spawn(SpriteBundle {
-            texture: render_texture.clone(),
+            texture: upscaled_texture.clone(),
             ..default()
         })
         .id();
@@ -111,18 +169,22 @@ fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
     },));
     commands.insert_resource(CameraData {
         render_texture,
+        upscaled_texture,
         sprite,
     });
 }
 
 fn update_streaming_pos(
     mut voxel_volumes: Query<&mut VoxelVolume>,
-    character: Query<&Transform, With<CharacterEntity>>,
+    character: Query<(&Transform, &CharacterEntity, &Frustum)>,
 ) {
-    let character = character.single();
-    let mut voxel_volume = voxel_volumes.single_mut();
+    let (transform, character, frustum) = character.single();
 
-    voxel_volume.streaming_pos = character.translation;
+    for mut voxel_volume in voxel_volumes.iter_mut() {
+        voxel_volume.streaming_pos = transform.translation;
+        voxel_volume.streaming_velocity = character.velocity;
+        voxel_volume.streaming_frustum = Some(*frustum);
+    }
 }
 
 fn update_render_texture(
@@ -142,9 +204,9 @@ fn update_render_texture(
             depth_or_array_layers: 1,
         };
 
-        info!("Resizing render texture to {:?}", new_size);
+        info!("Resizing upscaled texture to {:?}", new_size);
 
-        let image = images.get_mut(&render_image.render_texture).unwrap();
+        let image = images.get_mut(&render_image.upscaled_texture).unwrap();
         image.resize(new_size);
     };
 
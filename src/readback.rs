@@ -0,0 +1,140 @@
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_asset::RenderAssets,
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+};
+use crossbeam::channel::{Receiver, Sender};
+
+/// Copies images back from the gpu after they've been rendered. Set
+/// `ReadbackRequest` and the image arrives on `ReadbackChannel` once a frame
+/// has been rendered with every pipeline compiled.
+pub struct ReadbackPlugin;
+
+impl Plugin for ReadbackPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        app.insert_resource(ReadbackChannel { sender, receiver })
+            .init_resource::<ReadbackRequest>()
+            .add_plugins((
+                ExtractResourcePlugin::<ReadbackRequest>::default(),
+                ExtractResourcePlugin::<ReadbackChannel>::default(),
+            ));
+
+        app.sub_app_mut(RenderApp).add_systems(
+            Render,
+            read_back
+                .after(RenderSet::Render)
+                .before(RenderSet::Cleanup),
+        );
+    }
+}
+
+/// The image to copy back after this frame, and an id to recognise it by.
+/// It's copied every frame until the request is cleared.
+#[derive(Resource, ExtractResource, Clone, Default)]
+pub struct ReadbackRequest(pub Option<(usize, Handle<Image>)>);
+
+#[derive(Resource, ExtractResource, Clone)]
+pub struct ReadbackChannel {
+    sender: Sender<(usize, Image)>,
+    pub receiver: Receiver<(usize, Image)>,
+}
+
+fn read_back(
+    request: Res<ReadbackRequest>,
+    channel: Res<ReadbackChannel>,
+    images: Res<RenderAssets<Image>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some((id, handle)) = &request.0 else {
+        return;
+    };
+    let Some(gpu_image) = images.get(handle) else {
+        return;
+    };
+
+    // shaders load in the background, frames drawn before they're all
+    // compiled are missing whatever uses them
+    let compiling = pipeline_cache.pipelines().any(|pipeline| {
+        matches!(
+            pipeline.state,
+            CachedPipelineState::Queued
+                | CachedPipelineState::Err(
+                    PipelineCacheError::ShaderNotLoaded(_)
+                        | PipelineCacheError::ShaderImportNotYetAvailable
+                )
+        )
+    });
+    if compiling {
+        return;
+    }
+
+    // rows of a texture to buffer copy have to be padded to 256 bytes
+    let size = gpu_image.size.as_uvec2();
+    let pixel_size = gpu_image.texture_format.block_size(None).unwrap_or(4);
+    let row_bytes = size.x * pixel_size;
+    let padded_row_bytes =
+        row_bytes.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+
+    let buffer = render_device.create_buffer(&BufferDescriptor {
+        label: Some("readback buffer"),
+        size: (padded_row_bytes * size.y) as u64,
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("readback encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        gpu_image.texture.as_image_copy(),
+        ImageCopyBuffer {
+            buffer: &buffer,
+            layout: ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row_bytes),
+                rows_per_image: None,
+            },
+        },
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+    );
+    render_queue.submit([encoder.finish()]);
+
+    // headless, so just wait for the copy
+    let slice = buffer.slice(..);
+    render_device.map_buffer(&slice, MapMode::Read, |result| {
+        if let Err(e) = result {
+            error!("failed to map readback buffer: {}", e);
+        }
+    });
+    render_device.poll(wgpu::Maintain::Wait);
+
+    let data = slice
+        .get_mapped_range()
+        .chunks(padded_row_bytes as usize)
+        .flat_map(|row| row[..row_bytes as usize].iter().copied())
+        .collect::<Vec<_>>();
+    buffer.unmap();
+
+    let image = Image::new(
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        gpu_image.texture_format,
+    );
+    let _ = channel.sender.send((*id, image));
+}
@@ -0,0 +1,192 @@
+use bevy::prelude::*;
+use bytemuck::{Pod, Zeroable};
+use std::{collections::HashMap, ops::Range};
+
+/// One brick drawn by the instanced renderer, in the volume's local space.
+#[derive(Clone, Copy, Default, Pod, Zeroable)]
+#[repr(C)]
+pub struct BrickInstance {
+    pub position: Vec3,
+    pub scale: f32,
+    pub brick: u32,
+    /// 1 when fully visible. bricks fading in during a level transition are
+    /// between 0 and 1, bricks fading out between -1 and 0
+    pub fade: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SlotOwner {
+    Free,
+    /// the resident leaf node with this index
+    Leaf(usize),
+    /// an old brick of the transition of the node with this index
+    FadingOut(usize),
+}
+
+/// The instances of a volume's resident leaves, and of the old bricks of
+/// their transitions. Every instance keeps its slot until it's removed, so
+/// only the changed slots have to be uploaded. Freed slots are left as empty
+/// instances that don't draw anything until they're reused.
+#[derive(Default)]
+pub struct BrickInstances {
+    instances: Vec<BrickInstance>,
+    owners: Vec<SlotOwner>,
+    /// slot of each leaf, by node index
+    leaves: HashMap<usize, usize>,
+    /// slots of the bricks fading out, by the node of their transition
+    fading_out: HashMap<usize, Vec<usize>>,
+    holes: Vec<usize>,
+    /// slots changed since the last upload
+    dirty: Vec<usize>,
+    all_dirty: bool,
+}
+
+impl BrickInstances {
+    pub fn as_slice(&self) -> &[BrickInstance] {
+        &self.instances
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    fn mark_dirty(&mut self, slot: usize) {
+        if self.all_dirty {
+            return;
+        }
+        self.dirty.push(slot);
+        if self.dirty.len() > self.instances.len() {
+            self.all_dirty = true;
+            self.dirty.clear();
+        }
+    }
+
+    fn insert(&mut self, instance: BrickInstance, owner: SlotOwner) -> usize {
+        let slot = match self.holes.pop() {
+            Some(slot) => {
+                self.instances[slot] = instance;
+                self.owners[slot] = owner;
+                slot
+            }
+            None => {
+                self.instances.push(instance);
+                self.owners.push(owner);
+                self.instances.len() - 1
+            }
+        };
+        self.mark_dirty(slot);
+        slot
+    }
+
+    fn remove(&mut self, slot: usize) {
+        self.instances[slot] = BrickInstance::default();
+        self.owners[slot] = SlotOwner::Free;
+        self.holes.push(slot);
+        self.mark_dirty(slot);
+    }
+
+    fn set_fade(&mut self, slot: usize, fade: f32) {
+        if self.instances[slot].fade != fade {
+            self.instances[slot].fade = fade;
+            self.mark_dirty(slot);
+        }
+    }
+
+    pub fn add_leaf(&mut self, index: usize, instance: BrickInstance) {
+        if let Some(slot) = self.leaves.remove(&index) {
+            self.remove(slot);
+        }
+        let slot = self.insert(instance, SlotOwner::Leaf(index));
+        self.leaves.insert(index, slot);
+    }
+
+    /// hand a leaf's instance over to the transition of node `transition`,
+    /// to fade out until the transition ends
+    pub fn fade_out_leaf(&mut self, index: usize, transition: usize) {
+        if let Some(slot) = self.leaves.remove(&index) {
+            self.owners[slot] = SlotOwner::FadingOut(transition);
+            self.fading_out.entry(transition).or_default().push(slot);
+        }
+    }
+
+    pub fn set_leaf_fade(&mut self, index: usize, fade: f32) {
+        if let Some(&slot) = self.leaves.get(&index) {
+            self.set_fade(slot, fade);
+        }
+    }
+
+    pub fn set_fading_out(&mut self, transition: usize, fade: f32) {
+        let slots = self
+            .fading_out
+            .get(&transition)
+            .cloned()
+            .unwrap_or_default();
+        for slot in slots {
+            self.set_fade(slot, fade);
+        }
+    }
+
+    pub fn remove_fading_out(&mut self, transition: usize) {
+        for slot in self.fading_out.remove(&transition).unwrap_or_default() {
+            self.remove(slot);
+        }
+    }
+
+    /// reorder the instances by `key`, which also drops the freed slots.
+    /// every slot moves, so everything is uploaded again
+    pub fn sort_by_cached_key(&mut self, mut key: impl FnMut(&BrickInstance) -> f32) {
+        let mut slots = self
+            .owners
+            .iter()
+            .zip(&self.instances)
+            .filter(|(owner, _)| **owner != SlotOwner::Free)
+            .map(|(owner, instance)| (*owner, *instance))
+            .collect::<Vec<_>>();
+        radsort::sort_by_cached_key(&mut slots, |(_, instance)| key(instance));
+
+        self.leaves.clear();
+        self.fading_out.clear();
+        self.holes.clear();
+        for (slot, (owner, _)) in slots.iter().enumerate() {
+            match *owner {
+                SlotOwner::Leaf(index) => {
+                    self.leaves.insert(index, slot);
+                }
+                SlotOwner::FadingOut(transition) => {
+                    self.fading_out.entry(transition).or_default().push(slot);
+                }
+                SlotOwner::Free => {}
+            }
+        }
+        (self.owners, self.instances) = slots.into_iter().unzip();
+
+        self.all_dirty = true;
+        self.dirty.clear();
+    }
+
+    /// the ranges of slots changed since the last call
+    pub fn take_dirty(&mut self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        if std::mem::take(&mut self.all_dirty) {
+            self.dirty.clear();
+            ranges.push(0..self.instances.len());
+            return ranges;
+        }
+
+        self.dirty.sort_unstable();
+        self.dirty.dedup();
+        for slot in self.dirty.drain(..) {
+            match ranges.last_mut() {
+                Some(range) if range.end == slot => range.end += 1,
+                _ => ranges.push(slot..slot + 1),
+            }
+        }
+        ranges
+    }
+
+    /// upload everything on the next call to `take_dirty`
+    pub fn mark_all_dirty(&mut self) {
+        self.all_dirty = true;
+        self.dirty.clear();
+    }
+}
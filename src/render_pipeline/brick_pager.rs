@@ -0,0 +1,155 @@
+use super::cpu_brickmap::Brick;
+use anyhow::Result;
+use bevy::{prelude::*, utils::HashMap};
+use crossbeam::channel::{Receiver, Sender};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Loads fine bricks of a paged brickmap from disk on a background thread and
+/// keeps the most recently used ones cached.
+pub struct BrickPager {
+    path: PathBuf,
+    /// bricks with an index below this are resident and never paged
+    first_paged: u32,
+    /// file offset of the first paged brick
+    offset: u64,
+    /// max number of cached bricks
+    capacity: usize,
+    cache: Mutex<BrickCache>,
+    request_sender: Sender<u32>,
+    loaded_receiver: Receiver<(u32, Result<Brick>)>,
+}
+
+#[derive(Default)]
+struct BrickCache {
+    bricks: HashMap<u32, (Arc<Brick>, u64)>,
+    requested: HashMap<u32, u64>,
+    frame: u64,
+}
+
+impl BrickPager {
+    pub fn new(path: &Path, first_paged: u32, offset: u64, capacity: usize) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let (request_sender, request_receiver) = crossbeam::channel::unbounded::<u32>();
+        let (loaded_sender, loaded_receiver) = crossbeam::channel::unbounded();
+
+        // the thread stops once the pager (and with it the sender) is dropped
+        std::thread::Builder::new()
+            .name("brick pager".to_string())
+            .spawn(move || {
+                while let Ok(index) = request_receiver.recv() {
+                    let brick = read_brick(&mut file, offset, first_paged, index);
+                    if loaded_sender.send((index, brick)).is_err() {
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            first_paged,
+            offset,
+            capacity,
+            cache: Mutex::new(BrickCache::default()),
+            request_sender,
+            loaded_receiver,
+        })
+    }
+
+    pub fn first_paged(&self) -> u32 {
+        self.first_paged
+    }
+
+    /// returns the brick if it is cached, otherwise requests it from the
+    /// background thread and returns `None`
+    pub fn get(&self, index: u32) -> Option<Arc<Brick>> {
+        let mut cache = self.cache.lock().unwrap();
+        self.receive(&mut cache);
+
+        let frame = cache.frame;
+        if let Some((brick, last_used)) = cache.bricks.get_mut(&index) {
+            *last_used = frame;
+            return Some(brick.clone());
+        }
+
+        if !cache.requested.contains_key(&index) {
+            cache.requested.insert(index, frame);
+            self.request_sender.send(index).unwrap();
+        }
+        None
+    }
+
+    /// reads the brick on the calling thread if it isn't cached
+    pub fn get_blocking(&self, index: u32) -> Result<Arc<Brick>> {
+        if let Some(brick) = self.get(index) {
+            return Ok(brick);
+        }
+
+        let mut file = File::open(&self.path)?;
+        let brick = Arc::new(read_brick(&mut file, self.offset, self.first_paged, index)?);
+        let mut cache = self.cache.lock().unwrap();
+        let frame = cache.frame;
+        cache.bricks.insert(index, (brick.clone(), frame));
+        Ok(brick)
+    }
+
+    /// evicts the least recently used bricks until the cache fits its
+    /// capacity. streaming only touches bricks around the camera, so these
+    /// are the ones furthest from it
+    pub fn evict(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        self.receive(&mut cache);
+        cache.frame += 1;
+
+        let excess = cache.bricks.len().saturating_sub(self.capacity);
+        if excess == 0 {
+            return;
+        }
+
+        let mut last_used = cache
+            .bricks
+            .iter()
+            .map(|(index, (_, last_used))| (*last_used, *index))
+            .collect::<Vec<_>>();
+        last_used.select_nth_unstable(excess - 1);
+        for (_, index) in &last_used[..excess] {
+            cache.bricks.remove(index);
+        }
+    }
+
+    /// number of cached bricks and the cache capacity
+    pub fn cached(&self) -> (usize, usize) {
+        (self.cache.lock().unwrap().bricks.len(), self.capacity)
+    }
+
+    fn receive(&self, cache: &mut BrickCache) {
+        for (index, brick) in self.loaded_receiver.try_iter() {
+            let Some(requested) = cache.requested.remove(&index) else {
+                continue;
+            };
+            match brick {
+                Ok(brick) => {
+                    cache.bricks.insert(index, (Arc::new(brick), requested));
+                }
+                Err(e) => error!("failed to page in brick {}: {}", index, e),
+            }
+        }
+    }
+}
+
+fn read_brick(file: &mut File, offset: u64, first_paged: u32, index: u32) -> Result<Brick> {
+    if index < first_paged {
+        return Err(anyhow::anyhow!("brick {} is not paged", index));
+    }
+
+    let mut bytes = vec![0; Brick::BYTES];
+    file.seek(SeekFrom::Start(
+        offset + (index - first_paged) as u64 * Brick::BYTES as u64,
+    ))?;
+    file.read_exact(&mut bytes)?;
+    Ok(Brick::from_bytes(&bytes))
+}
@@ -1,8 +1,92 @@
-use super::BRICK_SIZE;
-use bevy::prelude::*;
-
+use super::{brick_pager::BrickPager, BRICK_SIZE};
+use anyhow::Result;
+use bevy::{prelude::*, utils::HashMap};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const PAGED_MAGIC: &[u8; 4] = b"BMAP";
+const PAGED_VERSION: u32 = 7;
+
+/// marks the bricks a `PagedWriter` has written to disk until it knows how
+/// many resident bricks come before them
+const PAGED_BIT: u32 = 1 << 31;
+
+/// how much block light drops per voxel away from an emitter
+const LIGHT_FALLOFF: u8 = 17;
+
+#[derive(Clone)]
 pub struct Brick {
     data: [[u8; 4]; (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize],
+    /// block light in red, how much of the sky is hidden in green, and the
+    /// emission and packed surface of the voxel's `Material` in blue and alpha
+    light: [[u8; 4]; (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize],
+    /// the material id and `TRANSPARENT` bit of each voxel
+    material: [u8; (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize],
+}
+
+/// set in a voxel's material byte if it's transparent, the rest is its id
+const TRANSPARENT: u8 = 1 << 7;
+
+/// What a voxel is made of, beyond its colour.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Material {
+    /// what the voxel is, for anything that needs to tell blocks apart. the
+    /// ids are up to whoever builds the brickmap, 0 is unknown and 127 is the
+    /// largest
+    pub id: u8,
+    /// light goes through transparent voxels, tinted by their colour. they
+    /// need an alpha below 255, opaque voxels should have 255
+    pub transparent: bool,
+    /// how much light the voxel gives off
+    pub emission: u8,
+    /// how much reflections are blurred, 0 is a mirror and 1 doesn't reflect
+    pub roughness: f32,
+    /// metals reflect in their own colour and have no diffuse light
+    pub metalness: f32,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            transparent: false,
+            emission: 0,
+            roughness: 1.0,
+            metalness: 0.0,
+        }
+    }
+}
+
+impl Material {
+    /// the id and transparent bit, the byte of the material pool
+    fn pack_kind(self) -> u8 {
+        let transparent = if self.transparent { TRANSPARENT } else { 0 };
+        self.id & !TRANSPARENT | transparent
+    }
+
+    /// emission, and roughness and metalness in a nibble each, for the blue
+    /// and alpha of the light
+    fn pack_light(self) -> [u8; 2] {
+        let roughness = (self.roughness.clamp(0.0, 1.0) * 15.0).round() as u8;
+        let metalness = (self.metalness.clamp(0.0, 1.0) * 15.0).round() as u8;
+        [self.emission, roughness << 4 | metalness]
+    }
+
+    fn unpack(kind: u8, light: [u8; 4]) -> Self {
+        Self {
+            id: kind & !TRANSPARENT,
+            transparent: kind & TRANSPARENT != 0,
+            emission: light[2],
+            roughness: (light[3] >> 4) as f32 / 15.0,
+            metalness: (light[3] & 15) as f32 / 15.0,
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -21,7 +105,222 @@ impl Node {
 pub struct CpuBrickmap {
     pub brickmap: Vec<Node>,
     pub brickmap_depth: u32,
+    /// resident bricks. when paged, bricks past the end of this are on disk
     pub bricks: Vec<Brick>,
+    pub pager: Option<BrickPager>,
+}
+
+/// writes the magic, version, `[brickmap_depth, node count, first paged
+/// brick, brick count]` and the sources of a paged brickmap
+fn write_paged_header(writer: &mut impl Write, header: [u32; 4], sources: &[u64]) -> Result<()> {
+    writer.write_all(PAGED_MAGIC)?;
+    writer.write_all(&PAGED_VERSION.to_le_bytes())?;
+    for value in header {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.write_all(&(sources.len() as u32).to_le_bytes())?;
+    for source in sources {
+        writer.write_all(&source.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// inverse of `write_paged_header`, fails on other files and versions
+fn read_paged_header(reader: &mut impl Read) -> std::io::Result<([u32; 4], Vec<u64>)> {
+    fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+        let mut bytes = [0; 4];
+        reader.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != PAGED_MAGIC || read_u32(reader)? != PAGED_VERSION {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "wrong magic or version",
+        ));
+    }
+    let mut header = [0; 4];
+    for value in header.iter_mut() {
+        *value = read_u32(reader)?;
+    }
+    let mut sources = vec![0; read_u32(reader)? as usize];
+    for source in sources.iter_mut() {
+        let mut bytes = [0; 8];
+        reader.read_exact(&mut bytes)?;
+        *source = u64::from_le_bytes(bytes);
+    }
+    Ok((header, sources))
+}
+
+fn write_brick(writer: &mut impl Write, brick: &Brick) -> Result<()> {
+    writer.write_all(brick.to_gpu())?;
+    writer.write_all(brick.light_to_gpu())?;
+    writer.write_all(brick.material_bytes())?;
+    Ok(())
+}
+
+/// Writes a paged brickmap a piece at a time, for worlds that don't fit in
+/// memory. Every node at `split_depth` and below has to come from a single
+/// piece, their fine bricks go straight to disk. Only the node tree, the
+/// resident bricks and the bricks at `split_depth` are kept, `finish` mips the
+/// levels above `split_depth` from those.
+pub struct PagedWriter {
+    path: PathBuf,
+    brickmap: Vec<Node>,
+    brickmap_depth: u32,
+    resident_depth: u32,
+    split_depth: u32,
+    resident: Vec<Brick>,
+    split_bricks: HashMap<usize, Brick>,
+    /// the paged bricks, copied after the resident ones by `finish`
+    paged: BufWriter<File>,
+    paged_path: PathBuf,
+    paged_count: u32,
+}
+
+impl PagedWriter {
+    pub fn new(
+        path: &Path,
+        brickmap_depth: u32,
+        resident_depth: u32,
+        split_depth: u32,
+    ) -> Result<Self> {
+        let mut paged_path = path.as_os_str().to_owned();
+        paged_path.push(".part");
+        let paged_path = PathBuf::from(paged_path);
+        Ok(Self {
+            path: path.to_path_buf(),
+            brickmap: vec![Node::ZERO; 8],
+            brickmap_depth,
+            resident_depth,
+            split_depth,
+            resident: vec![Brick::empty()],
+            split_bricks: HashMap::new(),
+            paged: BufWriter::new(File::create(&paged_path)?),
+            paged_path,
+            paged_count: 0,
+        })
+    }
+
+    /// adds the nodes and bricks of a piece with its light spread and
+    /// mipmaps built. its bricks above `split_depth` are ignored
+    pub fn add(&mut self, piece: CpuBrickmap) -> Result<()> {
+        if piece.brickmap_depth != self.brickmap_depth || piece.pager.is_some() {
+            return Err(anyhow::anyhow!("piece doesn't match the paged brickmap"));
+        }
+
+        let mut bricks = piece.bricks.into_iter().map(Some).collect::<Vec<_>>();
+        let mut stack = (0..8).map(|i| (i, i, 1)).collect::<Vec<_>>();
+        while let Some((index, piece_index, depth)) = stack.pop() {
+            let node = piece.brickmap[piece_index];
+            if depth >= self.split_depth && node.brick != 0 {
+                if self.brickmap[index].brick != 0 {
+                    return Err(anyhow::anyhow!("pieces overlap below the split depth"));
+                }
+                let brick = bricks[node.brick as usize].take().unwrap();
+                if depth == self.split_depth {
+                    self.split_bricks.insert(index, brick.clone());
+                }
+                self.brickmap[index].brick = self.store(brick, depth)?;
+            }
+            if node.children != 0 {
+                if self.brickmap[index].children == 0 {
+                    self.brickmap[index].children = self.brickmap.len() as u32 / 8;
+                    self.brickmap.extend([Node::ZERO; 8]);
+                }
+                let children = 8 * self.brickmap[index].children as usize;
+                let piece_children = 8 * node.children as usize;
+                stack.extend((0..8).map(|i| (children + i, piece_children + i, depth + 1)));
+            }
+        }
+        Ok(())
+    }
+
+    /// mips the levels above `split_depth` and writes the brickmap. `sources`
+    /// are stored in the header for `CpuBrickmap::is_current_paged`
+    pub fn finish(mut self, sources: &[u64]) -> Result<()> {
+        for i in 0..8 {
+            self.mip(i, 1)?;
+        }
+
+        let first_paged = self.resident.len() as u32;
+        let remap = |brick: u32| match brick & PAGED_BIT {
+            0 => brick,
+            _ => first_paged + (brick & !PAGED_BIT),
+        };
+
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        let header = [
+            self.brickmap_depth,
+            self.brickmap.len() as u32,
+            first_paged,
+            first_paged + self.paged_count,
+        ];
+        write_paged_header(&mut writer, header, sources)?;
+        for node in self.brickmap.iter() {
+            writer.write_all(&node.children.to_le_bytes())?;
+            writer.write_all(&remap(node.brick).to_le_bytes())?;
+        }
+        for brick in self.resident.iter() {
+            write_brick(&mut writer, brick)?;
+        }
+        self.paged.flush()?;
+        drop(self.paged);
+        std::io::copy(&mut File::open(&self.paged_path)?, &mut writer)?;
+        writer.flush()?;
+        std::fs::remove_file(&self.paged_path)?;
+
+        Ok(())
+    }
+
+    /// keeps the brick in memory if it's resident, otherwise writes it out
+    fn store(&mut self, brick: Brick, depth: u32) -> Result<u32> {
+        if depth <= self.resident_depth {
+            self.resident.push(brick);
+            return Ok(self.resident.len() as u32 - 1);
+        }
+        write_brick(&mut self.paged, &brick)?;
+        self.paged_count += 1;
+        Ok(PAGED_BIT | (self.paged_count - 1))
+    }
+
+    /// builds the brick of a node above `split_depth` from its children
+    fn mip(&mut self, index: usize, depth: u32) -> Result<Option<Brick>> {
+        if depth >= self.split_depth {
+            return Ok(self.split_bricks.remove(&index));
+        }
+        let children_index = 8 * self.brickmap[index].children as usize;
+        if children_index == 0 {
+            return Ok(None);
+        }
+
+        let mut children: [Option<Brick>; 8] = Default::default();
+        for (i, child) in children.iter_mut().enumerate() {
+            *child = self.mip(children_index + i, depth + 1)?;
+        }
+        let brick = Brick::mip(children.each_ref().map(Option::as_ref));
+        self.brickmap[index].brick = self.store(brick.clone(), depth)?;
+        Ok(Some(brick))
+    }
+}
+
+/// A brick that is either resident or paged in from disk.
+pub enum BrickRef<'a> {
+    Resident(&'a Brick),
+    Paged(Arc<Brick>),
+}
+
+impl Deref for BrickRef<'_> {
+    type Target = Brick;
+
+    fn deref(&self) -> &Brick {
+        match self {
+            BrickRef::Resident(brick) => brick,
+            BrickRef::Paged(brick) => brick,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -31,7 +330,75 @@ impl CpuBrickmap {
             brickmap: vec![Node::ZERO; 8],
             brickmap_depth,
             bricks: vec![Brick::empty()],
+            pager: None,
+        }
+    }
+
+    /// returns the brick if it's resident or paged in, otherwise requests it
+    /// from disk and returns `None`
+    pub fn brick(&self, index: u32) -> Option<BrickRef<'_>> {
+        match &self.pager {
+            Some(pager) if index >= pager.first_paged() => pager.get(index).map(BrickRef::Paged),
+            _ => Some(BrickRef::Resident(&self.bricks[index as usize])),
+        }
+    }
+
+    /// like `brick` but reads paged bricks on the calling thread
+    pub fn brick_blocking(&self, index: u32) -> Result<BrickRef<'_>> {
+        match &self.pager {
+            Some(pager) if index >= pager.first_paged() => {
+                pager.get_blocking(index).map(BrickRef::Paged)
+            }
+            _ => Ok(BrickRef::Resident(&self.bricks[index as usize])),
+        }
+    }
+
+    /// whether `path` holds a paged brickmap written by this version from
+    /// `sources`, see `PagedWriter::finish`
+    pub fn is_current_paged(path: &Path, sources: &[u64]) -> bool {
+        File::open(path)
+            .and_then(|file| read_paged_header(&mut BufReader::new(file)))
+            .is_ok_and(|(_, written_sources)| written_sources == sources)
+    }
+
+    /// opens a brickmap written by `PagedWriter`, keeping at most
+    /// `cache_size` fine bricks in memory
+    pub fn load_paged(path: &Path, cache_size: usize) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let (header, sources) = read_paged_header(&mut reader)
+            .map_err(|e| anyhow::anyhow!("{} is not a paged brickmap: {}", path.display(), e))?;
+        let [brickmap_depth, node_count, first_paged, _brick_count] = header;
+
+        let mut brickmap = Vec::with_capacity(node_count as usize);
+        let mut bytes = [0; 8];
+        for _ in 0..node_count {
+            reader.read_exact(&mut bytes)?;
+            brickmap.push(Node {
+                children: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+                brick: u32::from_le_bytes(bytes[4..].try_into().unwrap()),
+            });
+        }
+
+        let mut bricks = Vec::with_capacity(first_paged as usize);
+        let mut bytes = vec![0; Brick::BYTES];
+        for _ in 0..first_paged {
+            reader.read_exact(&mut bytes)?;
+            bricks.push(Brick::from_bytes(&bytes));
         }
+
+        let offset = 4
+            + 4 * (header.len() as u64 + 2)
+            + 8 * sources.len() as u64
+            + 8 * node_count as u64
+            + first_paged as u64 * Brick::BYTES as u64;
+        let pager = BrickPager::new(path, first_paged, offset, cache_size)?;
+
+        Ok(Self {
+            brickmap,
+            brickmap_depth,
+            bricks,
+            pager: Some(pager),
+        })
     }
 
     pub fn place_brick(&mut self, brick: Brick, pos: UVec3) -> Result<(), String> {
@@ -143,7 +510,108 @@ impl CpuBrickmap {
         (brickmap, bricks)
     }
 
+    /// index of the brick at `pos` (in bricks) if it's a leaf of the finest
+    /// level
+    fn leaf_brick(&self, pos: UVec3) -> Option<usize> {
+        let (index, _, depth) = self.get_node(pos, None);
+        let brick = self.brickmap[index].brick as usize;
+        (depth == self.brickmap_depth && brick != 0).then_some(brick)
+    }
+
+    /// spreads the emission of voxels into the block light of the voxels
+    /// around them, dropping off with distance. light only travels through
+    /// bricks of the finest level, so run this before `recreate_mipmaps`
+    pub fn spread_light(&mut self) {
+        if self.pager.is_some() {
+            error!("can't spread the light of a paged brickmap");
+            return;
+        }
+
+        // find the leaf bricks and their positions
+        let mut leaves = Vec::new();
+        let mut stack = vec![(0, UVec3::ZERO, 1)];
+        while let Some((children_index, pos, depth)) = stack.pop() {
+            let half_size = 1 << (self.brickmap_depth - depth);
+            for i in 0..8 {
+                let node = self.brickmap[children_index + i as usize];
+                let pos = pos + UVec3::new(i >> 2 & 1, i >> 1 & 1, i & 1) * half_size;
+                if node.children != 0 {
+                    stack.push((8 * node.children as usize, pos, depth + 1));
+                } else if depth == self.brickmap_depth && node.brick != 0 {
+                    leaves.push((pos, node.brick as usize));
+                }
+            }
+        }
+
+        // emissive voxels light themselves
+        let mut queue = VecDeque::new();
+        for (brick_pos, brick_index) in leaves {
+            let brick = &mut self.bricks[brick_index];
+            for (index, light) in brick.light.iter_mut().enumerate() {
+                let emission = light[2];
+                if emission > 0 {
+                    light[0] = light[0].max(emission);
+                    let index = index as u32;
+                    let pos = UVec3::new(
+                        index % BRICK_SIZE,
+                        index / BRICK_SIZE % BRICK_SIZE,
+                        index / (BRICK_SIZE * BRICK_SIZE),
+                    );
+                    queue.push_back((brick_pos * BRICK_SIZE + pos, emission));
+                }
+            }
+        }
+        info!("spreading the light of {} emissive voxels", queue.len());
+
+        // flood fill outwards, keeping the brightest light each voxel gets
+        let side_length = (BRICK_SIZE << self.brickmap_depth) as i32;
+        while let Some((pos, level)) = queue.pop_front() {
+            if level <= LIGHT_FALLOFF {
+                continue;
+            }
+            let level = level - LIGHT_FALLOFF;
+
+            for offset in [
+                IVec3::X,
+                IVec3::NEG_X,
+                IVec3::Y,
+                IVec3::NEG_Y,
+                IVec3::Z,
+                IVec3::NEG_Z,
+            ] {
+                let neighbour = pos.as_ivec3() + offset;
+                if neighbour.cmplt(IVec3::ZERO).any()
+                    || neighbour.cmpge(IVec3::splat(side_length)).any()
+                {
+                    continue;
+                }
+                let neighbour = neighbour.as_uvec3();
+                let Some(brick_index) = self.leaf_brick(neighbour / BRICK_SIZE) else {
+                    continue;
+                };
+
+                let brick = &mut self.bricks[brick_index];
+                let pos_in_brick = neighbour % BRICK_SIZE;
+                let mut light = brick.get_light(pos_in_brick);
+                if light[0] >= level {
+                    continue;
+                }
+                light[0] = level;
+                brick.write_light(pos_in_brick, light);
+
+                // opaque voxels are lit but stop the light
+                if brick.get(pos_in_brick)[3] == 0 || brick.get_material(pos_in_brick).transparent {
+                    queue.push_back((neighbour, level));
+                }
+            }
+        }
+    }
+
     pub fn recreate_mipmaps(&mut self) {
+        if self.pager.is_some() {
+            error!("can't recreate the mipmaps of a paged brickmap");
+            return;
+        }
         info!("recreating mipmaps for {} bricks", self.bricks.len());
 
         // mip-mapping
@@ -159,68 +627,22 @@ impl CpuBrickmap {
             }
 
             // mip the brick
-            let brick_index = brickmap.brickmap[node_index].brick;
+            let brick_index = brickmap.brickmap[node_index].brick as usize;
 
             #[cfg(debug_assertions)]
-            if brick_index as usize >= brickmap.bricks.len() {
+            if brick_index >= brickmap.bricks.len() {
                 error!("brick index out of bounds");
             }
             #[cfg(debug_assertions)]
-            if brick_index as usize == 0 {
+            if brick_index == 0 {
                 error!("tried to mip empty brick");
             }
 
-            for x in 0..BRICK_SIZE {
-                for y in 0..BRICK_SIZE {
-                    for z in 0..BRICK_SIZE {
-                        let pos = UVec3::new(x, y, z);
-
-                        // get the average of the 8 children
-                        let mut colour = Vec3::ZERO;
-                        let mut total_alpha = 0.0;
-                        let mask = pos.cmpge(UVec3::splat(BRICK_SIZE / 2));
-                        let child_node_index = children_index
-                            + mask.x as usize * 4
-                            + mask.y as usize * 2
-                            + mask.z as usize;
-                        let child_brick_index = brickmap.brickmap[child_node_index].brick;
-                        if child_brick_index as usize == 0 {
-                            continue;
-                        }
-                        #[cfg(debug_assertions)]
-                        if child_brick_index as usize >= brickmap.bricks.len() {
-                            error!("child brick index out of bounds");
-                        }
-                        for j in 0..8 {
-                            let child_pos_in_brick = 2 * (pos % (BRICK_SIZE / 2))
-                                + UVec3::new(j & 1, j >> 1 & 1, j >> 2 & 1);
-                            let child_colour =
-                                brickmap.bricks[child_brick_index as usize].get(child_pos_in_brick);
-
-                            let alpha = child_colour[3] as f32;
-                            let child_colour = Vec3::new(
-                                child_colour[0] as f32,
-                                child_colour[1] as f32,
-                                child_colour[2] as f32,
-                            );
-
-                            colour += child_colour * alpha;
-                            total_alpha += alpha;
-                        }
-                        colour /= total_alpha;
-                        total_alpha /= 8.0;
-
-                        // write the average to the brick
-                        let new_colour = [
-                            colour.x as u8,
-                            colour.y as u8,
-                            colour.z as u8,
-                            total_alpha as u8,
-                        ];
-                        brickmap.bricks[brick_index as usize].write(pos, new_colour);
-                    }
-                }
-            }
+            let children = std::array::from_fn(|i| {
+                let child_brick_index = brickmap.brickmap[children_index + i].brick as usize;
+                (child_brick_index != 0).then(|| &brickmap.bricks[child_brick_index])
+            });
+            brickmap.bricks[brick_index] = Brick::mip(children);
         }
 
         for i in 0..8 {
@@ -231,10 +653,129 @@ impl CpuBrickmap {
 
 #[allow(dead_code)]
 impl Brick {
+    /// size of a brick's colour, light and material data in bytes
+    pub const BYTES: usize = 9 * (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize;
+
     pub fn empty() -> Self {
         Self {
             data: [[0; 4]; (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize],
+            light: [[0; 4]; (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize],
+            material: [0; (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize],
+        }
+    }
+
+    /// inverse of `to_gpu` followed by `light_to_gpu` and `material_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut brick = Self::empty();
+        let (colours, bytes) = bytes.split_at(4 * brick.data.len());
+        let (light, material) = bytes.split_at(4 * brick.light.len());
+        bytemuck::cast_slice_mut(&mut brick.data).copy_from_slice(colours);
+        bytemuck::cast_slice_mut(&mut brick.light).copy_from_slice(light);
+        brick.material.copy_from_slice(material);
+        brick
+    }
+
+    /// the mip of 8 child bricks, indexed x * 4 + y * 2 + z. voxels of
+    /// missing children are left empty
+    pub fn mip(children: [Option<&Brick>; 8]) -> Self {
+        let mut brick = Self::empty();
+        for x in 0..BRICK_SIZE {
+            for y in 0..BRICK_SIZE {
+                for z in 0..BRICK_SIZE {
+                    let pos = UVec3::new(x, y, z);
+
+                    // get the average of the 8 children
+                    let mut colour = Vec3::ZERO;
+                    let mut light = Vec2::ZERO;
+                    let mut emission = 0.0;
+                    let mut roughness = 0.0;
+                    let mut metalness = 0.0;
+                    let mut kinds = [(0, 0); 8];
+                    let mut total_alpha = 0.0;
+                    let mut transparent_alpha = 0.0;
+                    let mut transparent_count = 0.0;
+                    let mask = pos.cmpge(UVec3::splat(BRICK_SIZE / 2));
+                    let child_index = mask.x as usize * 4 + mask.y as usize * 2 + mask.z as usize;
+                    let Some(child_brick) = children[child_index] else {
+                        continue;
+                    };
+                    for j in 0..8 {
+                        let child_pos_in_brick = 2 * (pos % (BRICK_SIZE / 2))
+                            + UVec3::new(j & 1, j >> 1 & 1, j >> 2 & 1);
+                        let child_colour = child_brick.get(child_pos_in_brick);
+                        let child_light = child_brick.get_light(child_pos_in_brick);
+                        let child_material = child_brick.get_material(child_pos_in_brick);
+
+                        let alpha = child_colour[3] as f32;
+                        if alpha == 0.0 {
+                            continue;
+                        }
+                        let child_colour = Vec3::new(
+                            child_colour[0] as f32,
+                            child_colour[1] as f32,
+                            child_colour[2] as f32,
+                        );
+                        let child_light = Vec2::new(child_light[0] as f32, child_light[1] as f32);
+
+                        colour += child_colour * alpha;
+                        light += child_light * alpha;
+                        emission += child_material.emission as f32 * alpha;
+                        roughness += child_material.roughness * alpha;
+                        metalness += child_material.metalness * alpha;
+                        total_alpha += alpha;
+                        if child_material.transparent {
+                            transparent_alpha += alpha;
+                            transparent_count += 1.0;
+                        }
+
+                        // ids and transparency can't be averaged, the most
+                        // common of the voxels that aren't empty wins
+                        let kind = child_material.pack_kind();
+                        let slot = kinds
+                            .iter()
+                            .position(|(k, count)| *k == kind || *count == 0)
+                            .unwrap();
+                        kinds[slot] = (kind, kinds[slot].1 + 1);
+                    }
+                    if total_alpha == 0.0 {
+                        continue;
+                    }
+                    colour /= total_alpha;
+                    light /= total_alpha;
+                    emission /= total_alpha;
+                    roughness /= total_alpha;
+                    metalness /= total_alpha;
+                    let kind = kinds
+                        .iter()
+                        .rev()
+                        .max_by_key(|(_, count)| *count)
+                        .unwrap()
+                        .0;
+
+                    // opaque voxels stay opaque however few children they
+                    // have, so solid blocks don't turn see through far away
+                    let transparent = kind & TRANSPARENT != 0;
+                    let alpha = match transparent {
+                        true => transparent_alpha / transparent_count,
+                        false => 255.0,
+                    };
+
+                    // write the average to the brick
+                    let new_colour = [colour.x as u8, colour.y as u8, colour.z as u8, alpha as u8];
+                    brick.write(pos, new_colour);
+                    brick.write_light(pos, [light.x as u8, light.y as u8]);
+                    let new_material = Material {
+                        id: kind & !TRANSPARENT,
+                        transparent,
+                        emission: emission as u8,
+                        roughness,
+                        metalness,
+                    };
+                    brick.write_material(pos, new_material);
+                }
+            }
         }
+        brick
     }
 
     pub fn get(&self, pos: UVec3) -> [u8; 4] {
@@ -257,22 +798,46 @@ impl Brick {
         self.data[index] = colour;
     }
 
-    pub unsafe fn to_gpu(&self) -> &[u8] {
-        let (_head, data, _tail) = unsafe { self.data.align_to::<u8>() };
-        #[cfg(debug_assertions)]
-        {
-            assert!(_head.is_empty());
-            assert!(_tail.is_empty());
-        }
-        data
+    /// block light and how much of the sky is hidden
+    pub fn get_light(&self, pos: UVec3) -> [u8; 2] {
+        let index = (pos.z * BRICK_SIZE * BRICK_SIZE + pos.y * BRICK_SIZE + pos.x) as usize;
+        let light = self.light[index];
+        [light[0], light[1]]
+    }
+
+    pub fn write_light(&mut self, pos: UVec3, light: [u8; 2]) {
+        let index = (pos.z * BRICK_SIZE * BRICK_SIZE + pos.y * BRICK_SIZE + pos.x) as usize;
+        self.light[index][..2].copy_from_slice(&light);
+    }
+
+    pub fn light_to_gpu(&self) -> &[u8] {
+        bytemuck::cast_slice(&self.light)
+    }
+
+    pub fn get_material(&self, pos: UVec3) -> Material {
+        let index = (pos.z * BRICK_SIZE * BRICK_SIZE + pos.y * BRICK_SIZE + pos.x) as usize;
+        Material::unpack(self.material[index], self.light[index])
+    }
+
+    pub fn write_material(&mut self, pos: UVec3, material: Material) {
+        let index = (pos.z * BRICK_SIZE * BRICK_SIZE + pos.y * BRICK_SIZE + pos.x) as usize;
+        self.material[index] = material.pack_kind();
+        self.light[index][2..].copy_from_slice(&material.pack_light());
+    }
+
+    pub fn material_bytes(&self) -> &[u8] {
+        &self.material
+    }
+
+    pub fn to_gpu(&self) -> &[u8] {
+        bytemuck::cast_slice(&self.data)
     }
 
     pub fn brick_ints() -> usize {
-        ((1..=BRICK_SIZE.trailing_zeros())
+        (1..=BRICK_SIZE.trailing_zeros())
             .map(|v| (1usize << v).pow(3))
             .sum::<usize>()
-            + 31)
-            / 32
+            .div_ceil(32)
     }
 
     fn size_offset() -> Vec<(u32, usize)> {
@@ -0,0 +1,107 @@
+use super::{CpuVoxelWorld, StreamingSettings, VoxelVolume};
+use bevy::{
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        render_resource::ShaderType,
+    },
+};
+
+/// Fades the voxel volumes of cameras with `VoxelFog` into the sky.
+pub struct FogPlugin;
+
+impl Plugin for FogPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ExtractComponentPlugin::<VoxelFog>::default())
+            .register_type::<VoxelFog>()
+            .add_systems(Update, match_streaming_distance);
+    }
+}
+
+/// Distance and height fog for a camera. Fogged voxels fade towards the sky
+/// near the horizon, so distant terrain blends into it. Distances are in
+/// world space.
+#[derive(Component, ExtractComponent, Clone, Reflect)]
+pub struct VoxelFog {
+    pub enabled: bool,
+    /// distance fog starts here
+    pub start: f32,
+    /// and hides everything past this
+    pub end: f32,
+    /// keep `end` at the distance past which streaming only keeps the coarsest
+    /// level of the volumes resident
+    pub match_streaming: bool,
+    /// height fog is densest below this height
+    pub height: f32,
+    /// height fog density at `height`
+    pub height_density: f32,
+    /// how quickly height fog thins out above `height`
+    pub height_falloff: f32,
+}
+
+impl Default for VoxelFog {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            start: 30.0,
+            end: 100.0,
+            match_streaming: false,
+            height: 0.0,
+            height_density: 0.01,
+            height_falloff: 0.2,
+        }
+    }
+}
+
+/// `VoxelFog` as the shaders see it, zeroed when there is no fog
+#[derive(Clone, Copy, Default, ShaderType)]
+pub struct FogUniforms {
+    start: f32,
+    end: f32,
+    height: f32,
+    height_density: f32,
+    height_falloff: f32,
+    enabled: u32,
+}
+
+impl From<&VoxelFog> for FogUniforms {
+    fn from(fog: &VoxelFog) -> Self {
+        Self {
+            start: fog.start,
+            end: fog.end,
+            height: fog.height,
+            height_density: fog.height_density,
+            height_falloff: fog.height_falloff,
+            enabled: fog.enabled as u32,
+        }
+    }
+}
+
+/// move the fog end of cameras with `match_streaming` to the furthest distance
+/// at which any volume still divides its coarsest nodes
+fn match_streaming_distance(
+    streaming_settings: Res<StreamingSettings>,
+    voxel_worlds: Res<Assets<CpuVoxelWorld>>,
+    voxel_volumes: Query<(&Handle<CpuVoxelWorld>, &GlobalTransform), With<VoxelVolume>>,
+    mut fogs: Query<&mut VoxelFog>,
+) {
+    let distance = voxel_volumes
+        .iter()
+        .filter_map(|(handle, transform)| {
+            let voxel_world = voxel_worlds.get(handle)?;
+            let node_size = (1 << (voxel_world.brickmap_depth - 1)) as f32;
+            let scale = transform.compute_transform().scale.max_element();
+            Some(streaming_settings.divide_distance(node_size, 1) * scale)
+        })
+        .fold(0.0, f32::max);
+    if distance == 0.0 {
+        return;
+    }
+
+    for mut fog in fogs.iter_mut() {
+        if fog.match_streaming && fog.end != distance {
+            fog.end = distance;
+            fog.start = fog.start.min(distance);
+        }
+    }
+}
@@ -1,18 +1,41 @@
 use anyhow::Result;
-use bevy::{prelude::*, render::renderer::RenderQueue};
-use std::collections::VecDeque;
+use bevy::{
+    prelude::*,
+    render::renderer::{RenderDevice, RenderQueue},
+};
+use std::collections::{HashMap, VecDeque};
 use wgpu::ImageCopyTexture;
 
 use super::{
+    brick_instances::{BrickInstance, BrickInstances},
     cpu_brickmap::Brick,
-    voxel_world::{CpuVoxelWorld, VoxelData},
+    voxel_world::{create_pools, CpuVoxelWorld, VoxelData},
     BRICK_OFFSET, BRICK_SIZE,
 };
 
+/// A node that just changed level. The bricks it was drawn with before stay
+/// resident until the transition ends, so the renderer can cross-fade them.
+pub struct NodeTransition {
+    /// time the node was divided or culled at
+    pub started: f32,
+    /// bricks the node or its children were drawn with before
+    old_bricks: Vec<u32>,
+    /// leaves whose instances fade in, the node's children or the node itself
+    fading_in: Vec<usize>,
+}
+
 #[derive(Resource)]
 pub struct GpuVoxelWorld {
     pub brickmap: Vec<u32>,
     pub gpu_to_cpu: Vec<u32>,
+    /// time each node was last divided at, used to delay culling
+    pub divided_at: Vec<f32>,
+    /// `StreamingState` of each node, for debugging
+    pub streaming_state: Vec<u32>,
+    /// nodes that recently changed level, by node index
+    pub transitions: HashMap<usize, NodeTransition>,
+    /// instances of the resident leaves, for the instanced renderer
+    pub instances: BrickInstances,
     pub brickmap_holes: VecDeque<usize>,
     pub brick_holes: VecDeque<usize>,
     pub color_texture_size: UVec3,
@@ -21,6 +44,105 @@ pub struct GpuVoxelWorld {
 
 #[allow(dead_code)]
 impl GpuVoxelWorld {
+    pub fn new(color_texture_size: UVec3, max_nodes: usize, brickmap_depth: u32) -> Self {
+        let dim = color_texture_size / BRICK_SIZE;
+        let brick_count = (dim.x * dim.y * dim.z) as usize;
+        Self {
+            brickmap: vec![BRICK_OFFSET; 8 * max_nodes],
+            gpu_to_cpu: vec![0; 8 * max_nodes],
+            divided_at: vec![0.0; 8 * max_nodes],
+            streaming_state: vec![0; 8 * max_nodes],
+            transitions: HashMap::new(),
+            instances: BrickInstances::default(),
+            brickmap_holes: (1..max_nodes).collect::<VecDeque<usize>>(),
+            brick_holes: (1..brick_count).collect::<VecDeque<usize>>(),
+            color_texture_size,
+            brickmap_depth,
+        }
+    }
+
+    /// position of a brick in the color texture
+    fn brick_pos(&self, brick_index: usize) -> UVec3 {
+        let dim = self.color_texture_size / BRICK_SIZE;
+        UVec3::new(
+            brick_index as u32 / (dim.x * dim.y),
+            brick_index as u32 / dim.x % dim.y,
+            brick_index as u32 % dim.x,
+        ) * BRICK_SIZE
+    }
+    /// instance of a leaf node at `pos` and `depth`, in the volume's local space
+    fn leaf_instance(&self, pos: UVec3, depth: u32, brick: u32, fade: f32) -> BrickInstance {
+        BrickInstance {
+            position: pos.as_vec3() - (1 << (self.brickmap_depth - 1)) as f32,
+            scale: (1 << (self.brickmap_depth - depth)) as f32,
+            brick,
+            fade,
+        }
+    }
+
+    /// recreate the instance of every non empty leaf, dropping transitions
+    pub fn rebuild_instances(&mut self) {
+        let mut leaves = Vec::new();
+        self.recursive_search(&mut |index, pos, depth| {
+            let node = self.brickmap[index];
+            if node > BRICK_OFFSET {
+                leaves.push((
+                    index,
+                    self.leaf_instance(pos, depth, node - BRICK_OFFSET, 1.0),
+                ));
+            }
+        });
+
+        self.instances = BrickInstances::default();
+        for (index, instance) in leaves {
+            self.instances.add_leaf(index, instance);
+        }
+        self.instances.mark_all_dirty();
+    }
+
+    /// free the old bricks of a node's transition, if it has one
+    fn end_transition(&mut self, index: usize) {
+        if let Some(transition) = self.transitions.remove(&index) {
+            self.brick_holes.extend(
+                transition
+                    .old_bricks
+                    .into_iter()
+                    .map(|brick| brick as usize),
+            );
+            self.instances.remove_fading_out(index);
+            for leaf in transition.fading_in {
+                self.instances.set_leaf_fade(leaf, 1.0);
+            }
+        }
+    }
+
+    /// update the fades of the instances in a transition
+    pub fn update_fades(&mut self, elapsed_seconds: f32, transition_time: f32) {
+        for (index, transition) in self.transitions.iter() {
+            let progress = match transition_time > 0.0 {
+                true => ((elapsed_seconds - transition.started) / transition_time).min(1.0),
+                false => 1.0,
+            };
+            self.instances.set_fading_out(*index, progress - 1.0);
+            for leaf in transition.fading_in.iter() {
+                self.instances.set_leaf_fade(*leaf, progress);
+            }
+        }
+    }
+
+    /// end the transitions that started before `before`
+    pub fn end_transitions(&mut self, before: f32) {
+        let ended = self
+            .transitions
+            .iter()
+            .filter(|(_, transition)| transition.started < before)
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>();
+        for index in ended {
+            self.end_transition(index);
+        }
+    }
+
     /// recurse the brickmap and call f on each *node* (not just leaf nodes)
     pub fn recursive_search(&self, f: &mut dyn FnMut(usize, UVec3, u32)) {
         for i in 0..8 {
@@ -67,46 +189,54 @@ impl GpuVoxelWorld {
             &brick.get_bitmask(),
         );
 
-        let dim = self.color_texture_size / BRICK_SIZE;
-        let brick_pos = UVec3::new(
-            brick_index.unwrap() as u32 / (dim.x * dim.y),
-            brick_index.unwrap() as u32 / dim.x % dim.y,
-            brick_index.unwrap() as u32 % dim.x,
-        ) * BRICK_SIZE;
-        render_queue.write_texture(
-            ImageCopyTexture {
-                texture: &voxel_data.color,
-                origin: wgpu::Origin3d {
-                    x: brick_pos.x,
-                    y: brick_pos.y,
-                    z: brick_pos.z,
+        let brick_pos = self.brick_pos(brick_index.unwrap());
+        // colour and light are 4 bytes a voxel, the material 1
+        for (texture, data, texel_bytes) in [
+            (&voxel_data.color, brick.to_gpu(), 4),
+            (&voxel_data.light, brick.light_to_gpu(), 4),
+            (&voxel_data.material, brick.material_bytes(), 1),
+        ] {
+            render_queue.write_texture(
+                ImageCopyTexture {
+                    texture,
+                    origin: wgpu::Origin3d {
+                        x: brick_pos.x,
+                        y: brick_pos.y,
+                        z: brick_pos.z,
+                    },
+                    mip_level: 0,
+                    aspect: wgpu::TextureAspect::All,
                 },
-                mip_level: 0,
-                aspect: wgpu::TextureAspect::All,
-            },
-            unsafe { brick.to_gpu() },
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(BRICK_SIZE * 4),
-                rows_per_image: Some(BRICK_SIZE),
-            },
-            wgpu::Extent3d {
-                width: BRICK_SIZE,
-                height: BRICK_SIZE,
-                depth_or_array_layers: BRICK_SIZE,
-            },
-        );
+                data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(BRICK_SIZE * texel_bytes),
+                    rows_per_image: Some(BRICK_SIZE),
+                },
+                wgpu::Extent3d {
+                    width: BRICK_SIZE,
+                    height: BRICK_SIZE,
+                    depth_or_array_layers: BRICK_SIZE,
+                },
+            );
+        }
 
         Ok(brick_index.unwrap())
     }
 
+    /// returns `false` without dividing if the child bricks are still being
+    /// paged in from disk. the node's old brick is kept for its transition
+    #[allow(clippy::too_many_arguments)]
     pub fn divide_node(
         &mut self,
         index: usize,
+        pos: UVec3,
+        depth: u32,
         voxel_data: &VoxelData,
         cpu_voxel_world: &CpuVoxelWorld,
         render_queue: &RenderQueue,
-    ) -> Result<()> {
+        elapsed_seconds: f32,
+    ) -> Result<bool> {
         let node = self.brickmap[index];
         if node < BRICK_OFFSET {
             return Err(anyhow::anyhow!("node {} already divided", index));
@@ -123,71 +253,257 @@ impl GpuVoxelWorld {
             ));
         }
 
+        // make sure every child brick is available before touching anything
+        let child_bricks = (0..8)
+            .map(|i| {
+                let cpu_child_node = cpu_voxel_world.brickmap[cpu_node.children as usize * 8 + i];
+                match cpu_child_node.brick {
+                    0 => Some(None),
+                    brick => cpu_voxel_world.brick(brick).map(Some),
+                }
+            })
+            .collect::<Vec<_>>();
+        if child_bricks.iter().any(Option::is_none) {
+            return Ok(false);
+        }
+
+        // the node can't be in two transitions at once
+        self.end_transition(index);
+
         // allocate space for child nodes
         let hole = match self.brickmap_holes.pop_front() {
             Some(hole) => hole,
             None => return Err(anyhow::anyhow!("ran out of space in brickmap")),
         };
 
-        // allocate bricks for child nodes
-        for i in 0..8 {
+        // allocate bricks for child nodes, their instances fade in
+        let half_size = 1 << (self.brickmap_depth - depth - 1);
+        let mut fading_in = Vec::new();
+        for (i, child_brick) in child_bricks.into_iter().enumerate() {
             self.brickmap[hole * 8 + i] = BRICK_OFFSET;
 
             let cpu_child_node_index = cpu_node.children as usize * 8 + i;
-            let cpu_child_node = cpu_voxel_world.brickmap[cpu_child_node_index];
-            if cpu_child_node.brick != 0 {
-                let brick_index = self.allocate_brick(
-                    &cpu_voxel_world.bricks[cpu_child_node.brick as usize],
-                    voxel_data,
-                    render_queue,
-                )?;
+            if let Some(child_brick) = child_brick.unwrap() {
+                let brick_index = self.allocate_brick(&child_brick, voxel_data, render_queue)?;
                 self.brickmap[hole * 8 + i] = BRICK_OFFSET + brick_index as u32;
+
+                let i = i as u32;
+                let child_pos = pos + UVec3::new(i >> 2 & 1, i >> 1 & 1, i & 1) * half_size;
+                let instance = self.leaf_instance(child_pos, depth + 1, brick_index as u32, 0.0);
+                self.instances.add_leaf(hole * 8 + i as usize, instance);
+                fading_in.push(hole * 8 + i as usize);
             }
             self.gpu_to_cpu[hole * 8 + i] = cpu_child_node_index as u32;
         }
 
-        // update node and free old brick
+        // update node, the old brick fades out until the transition ends
         self.brickmap[index] = hole as u32;
-        self.brick_holes.push_back((node - BRICK_OFFSET) as usize); // shouldn't be empty brick
+        self.instances.fade_out_leaf(index, index);
+        self.transitions.insert(
+            index,
+            NodeTransition {
+                started: elapsed_seconds,
+                old_bricks: vec![node - BRICK_OFFSET], // shouldn't be empty brick
+                fading_in,
+            },
+        );
 
-        Ok(())
+        Ok(true)
     }
 
+    /// returns `false` without culling if the node's brick is still being
+    /// paged in from disk. the child bricks are kept for the node's transition
+    #[allow(clippy::too_many_arguments)]
     pub fn cull_node(
         &mut self,
         index: usize,
+        pos: UVec3,
+        depth: u32,
         voxel_data: &VoxelData,
         cpu_voxel_world: &CpuVoxelWorld,
         render_queue: &RenderQueue,
-    ) -> Result<()> {
+        elapsed_seconds: f32,
+    ) -> Result<bool> {
         let node = self.brickmap[index];
         if node >= BRICK_OFFSET {
             return Err(anyhow::anyhow!("node {} already culled", index));
         }
 
-        // free non empty child bricks
+        let cpu_node_index = self.gpu_to_cpu[index] as usize;
+        let cpu_node = cpu_voxel_world.brickmap[cpu_node_index];
+        let Some(brick) = cpu_voxel_world.brick(cpu_node.brick) else {
+            return Ok(false);
+        };
+
+        // allocate a new brick
+        let brick_index = self.allocate_brick(&brick, voxel_data, render_queue)?;
+
+        // the child nodes are freed, but their non empty bricks fade out
+        // until the transition ends
         let children_index = 8 * node as usize;
+        self.end_transition(index);
+        let mut old_bricks = Vec::new();
         for i in 0..8 {
+            self.end_transition(children_index + i);
             let child_node = self.brickmap[children_index + i];
             if child_node > BRICK_OFFSET {
-                self.brick_holes
-                    .push_back((child_node - BRICK_OFFSET) as usize);
+                old_bricks.push(child_node - BRICK_OFFSET);
             }
+            self.instances.fade_out_leaf(children_index + i, index);
         }
 
-        // allocate a new brick
-        let cpu_node_index = self.gpu_to_cpu[index] as usize;
-        let cpu_node = cpu_voxel_world.brickmap[cpu_node_index];
-        let brick_index = self.allocate_brick(
-            &cpu_voxel_world.bricks[cpu_node.brick as usize],
-            voxel_data,
-            render_queue,
-        )?;
-
-        // update node and free child nodes
+        // update node and free child nodes, the node's instance fades in
         self.brickmap[index] = BRICK_OFFSET + brick_index as u32;
         self.brickmap_holes.push_back(children_index / 8);
+        let instance = self.leaf_instance(pos, depth, brick_index as u32, 0.0);
+        self.instances.add_leaf(index, instance);
+        self.transitions.insert(
+            index,
+            NodeTransition {
+                started: elapsed_seconds,
+                old_bricks,
+                fading_in: vec![index],
+            },
+        );
+
+        Ok(true)
+    }
+
+    /// reallocate the gpu pools and migrate the resident bricks into them.
+    /// when shrinking, nodes that no longer fit are collapsed back into their
+    /// (coarser) parent brick, starting from the finest levels
+    pub fn resize(
+        &mut self,
+        color_texture_size: UVec3,
+        max_nodes: usize,
+        voxel_data: &mut VoxelData,
+        cpu_voxel_world: &CpuVoxelWorld,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) {
+        let old = std::mem::replace(
+            self,
+            GpuVoxelWorld::new(color_texture_size, max_nodes, self.brickmap_depth),
+        );
+
+        let (brickmap, counters, streaming_state, bricks, color, light, material) =
+            create_pools(render_device, color_texture_size, max_nodes);
+        voxel_data.brickmap = brickmap;
+        voxel_data.counters = counters;
+        voxel_data.streaming_state = streaming_state;
+        let old_bricks = std::mem::replace(&mut voxel_data.bricks, bricks);
+        let old_color = std::mem::replace(&mut voxel_data.color, color);
+        let old_light = std::mem::replace(&mut voxel_data.light, light);
+        let old_material = std::mem::replace(&mut voxel_data.material, material);
+
+        let mut encoder = render_device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("brick pool migration"),
+        });
+        let brick_bytes = (4 * Brick::brick_ints()) as u64;
+
+        // walk the old tree breadth first so coarse levels are kept first.
+        // `pending` counts the queued nodes that may still need a brick, there
+        // are always at least that many brick holes left
+        let mut queue = (0..8).map(|i| (i, i)).collect::<VecDeque<_>>();
+        let mut pending = (0..8).filter(|i| old.brickmap[*i] != BRICK_OFFSET).count();
+        while let Some((old_index, new_index)) = queue.pop_front() {
+            let node = old.brickmap[old_index];
+            let cpu_node_index = old.gpu_to_cpu[old_index] as usize;
+            self.gpu_to_cpu[new_index] = cpu_node_index as u32;
+            if node == BRICK_OFFSET {
+                continue;
+            }
+            pending -= 1;
+
+            if node > BRICK_OFFSET {
+                // leaf node, copy its brick over on the gpu
+                let old_brick = (node - BRICK_OFFSET) as usize;
+                let new_brick = self.brick_holes.pop_front().unwrap();
+                encoder.copy_buffer_to_buffer(
+                    &old_bricks,
+                    old_brick as u64 * brick_bytes,
+                    &voxel_data.bricks,
+                    new_brick as u64 * brick_bytes,
+                    brick_bytes,
+                );
+                let old_pos = old.brick_pos(old_brick);
+                let new_pos = self.brick_pos(new_brick);
+                for (old_texture, new_texture) in [
+                    (&old_color, &voxel_data.color),
+                    (&old_light, &voxel_data.light),
+                    (&old_material, &voxel_data.material),
+                ] {
+                    encoder.copy_texture_to_texture(
+                        ImageCopyTexture {
+                            texture: old_texture,
+                            mip_level: 0,
+                            origin: wgpu::Origin3d {
+                                x: old_pos.x,
+                                y: old_pos.y,
+                                z: old_pos.z,
+                            },
+                            aspect: wgpu::TextureAspect::All,
+                        },
+                        ImageCopyTexture {
+                            texture: new_texture,
+                            mip_level: 0,
+                            origin: wgpu::Origin3d {
+                                x: new_pos.x,
+                                y: new_pos.y,
+                                z: new_pos.z,
+                            },
+                            aspect: wgpu::TextureAspect::All,
+                        },
+                        wgpu::Extent3d {
+                            width: BRICK_SIZE,
+                            height: BRICK_SIZE,
+                            depth_or_array_layers: BRICK_SIZE,
+                        },
+                    );
+                }
+                self.brickmap[new_index] = BRICK_OFFSET + new_brick as u32;
+                continue;
+            }
+
+            // divided node, keep it divided if its children fit
+            let old_children = 8 * node as usize;
+            let children = (0..8)
+                .filter(|i| old.brickmap[old_children + i] != BRICK_OFFSET)
+                .count();
+            let hole = if self.brick_holes.len() >= pending + children {
+                self.brickmap_holes.pop_front()
+            } else {
+                None
+            };
+            match hole {
+                Some(hole) => {
+                    for i in 0..8 {
+                        queue.push_back((old_children + i, hole * 8 + i));
+                    }
+                    pending += children;
+                    self.brickmap[new_index] = hole as u32;
+                    self.divided_at[new_index] = old.divided_at[old_index];
+                }
+                None => {
+                    // collapse the node back into its own brick
+                    let cpu_node = cpu_voxel_world.brickmap[cpu_node_index];
+                    let brick = cpu_voxel_world.brick_blocking(cpu_node.brick);
+                    match brick
+                        .and_then(|brick| self.allocate_brick(&brick, voxel_data, render_queue))
+                    {
+                        Ok(brick_index) => {
+                            self.brickmap[new_index] = BRICK_OFFSET + brick_index as u32;
+                        }
+                        Err(e) => error!("failed to collapse node: {}", e),
+                    }
+                }
+            }
+        }
+
+        self.rebuild_instances();
 
-        Ok(())
+        let (_, data, _) = unsafe { self.brickmap.align_to::<u8>() };
+        render_queue.write_buffer(&voxel_data.brickmap, 0, data);
+        render_queue.submit([encoder.finish()]);
     }
 }
@@ -0,0 +1,559 @@
+use super::{
+    brick_instances::BrickInstance,
+    main_pass::VOXEL_MAIN_PASS,
+    voxel_world::{ExtractedVoxelWorld, GpuVoxelVolumes},
+    VoxelRenderer, BRICK_SIZE,
+};
+use bevy::{
+    core_pipeline::core_3d::{self, CORE_3D},
+    pbr::RenderMeshInstances,
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        mesh::GpuBufferInfo,
+        render_asset::RenderAssets,
+        render_graph::{RenderGraphApp, ViewNodeRunner},
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        view::{ExtractedView, ViewDepthTexture},
+        Render, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+use node::{CullNode, HiZNode};
+
+mod node;
+
+pub const VOXEL_CULL_PASS: &str = "voxel_cull_pass";
+pub const VOXEL_HI_Z_PASS: &str = "voxel_hi_z_pass";
+
+/// Builds the brick instances of cameras with `GpuCulling` in a compute pass,
+/// so the cpu doesn't walk the brickmap every frame.
+pub struct GpuCullingPlugin;
+
+impl Plugin for GpuCullingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ExtractComponentPlugin::<GpuCulling>::default())
+            .register_type::<GpuCulling>();
+
+        app.sub_app_mut(RenderApp)
+            .init_resource::<CullingBuffers>()
+            .init_resource::<HiZTextures>()
+            .add_render_graph_node::<ViewNodeRunner<CullNode>>(CORE_3D, VOXEL_CULL_PASS)
+            .add_render_graph_node::<ViewNodeRunner<HiZNode>>(CORE_3D, VOXEL_HI_Z_PASS)
+            // the prepass draws the same instances as the main pass
+            .add_render_graph_edges(CORE_3D, &[VOXEL_CULL_PASS, core_3d::graph::node::PREPASS])
+            .add_render_graph_edges(
+                CORE_3D,
+                &[
+                    core_3d::graph::node::MAIN_OPAQUE_PASS,
+                    VOXEL_HI_Z_PASS,
+                    VOXEL_MAIN_PASS,
+                ],
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .init_resource::<CullingPipelines>()
+            .add_systems(
+                Render,
+                (
+                    prepare_culling_buffers.in_set(RenderSet::PrepareResources),
+                    prepare_bind_groups.in_set(RenderSet::PrepareBindGroups),
+                ),
+            );
+    }
+}
+
+/// Culls the brick instances of an instanced camera against its frustum on
+/// the gpu and draws them indirectly. Bricks don't cross-fade between levels
+/// of detail with gpu culling.
+#[derive(Component, ExtractComponent, Clone, Default, Reflect)]
+pub struct GpuCulling {
+    /// also cull bricks hidden behind the previous frame's depth, which needs
+    /// a depth texture with `TEXTURE_BINDING` usage and no msaa. there's no
+    /// second pass against this frame's depth, so bricks that come out from
+    /// behind something show up a frame late. off by default for that
+    pub occlusion_culling: bool,
+}
+
+/// Buffers the cull pass fills for a volume. Views are culled and drawn one
+/// after another, so they all share them.
+pub struct VolumeCullingBuffers {
+    /// nodes to visit, one level after another
+    queue: Buffer,
+    counters: Buffer,
+    dispatch_args: Buffer,
+    pub draw_args: Buffer,
+    pub instances: Buffer,
+    nodes: usize,
+    bricks: usize,
+}
+
+/// Every volume's cull buffers, keyed by the volume entity.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct CullingBuffers(HashMap<Entity, VolumeCullingBuffers>);
+
+/// Depth pyramid of a view's last frame.
+pub struct HiZ {
+    view: TextureView,
+    /// one view per mip, to write them one at a time
+    mips: Vec<TextureView>,
+    size: UVec2,
+    /// view projection of the frame the pyramid was built from
+    view_proj: Option<Mat4>,
+}
+
+impl HiZ {
+    fn new(render_device: &RenderDevice, size: UVec2) -> Self {
+        let mip_count = 32 - size.max_element().leading_zeros();
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("hi z texture"),
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: mip_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R32Float,
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        let mips = (0..mip_count)
+            .map(|mip| {
+                texture.create_view(&TextureViewDescriptor {
+                    label: Some("hi z mip"),
+                    base_mip_level: mip,
+                    mip_level_count: Some(1),
+                    ..default()
+                })
+            })
+            .collect();
+
+        Self {
+            view,
+            mips,
+            size,
+            view_proj: None,
+        }
+    }
+}
+
+/// Every view's depth pyramid, kept between frames and keyed by the view
+/// entity.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct HiZTextures(HashMap<Entity, HiZ>);
+
+/// Marks views that build their depth pyramid this frame.
+#[derive(Component)]
+pub struct BuildHiZ;
+
+#[derive(Clone, ShaderType)]
+struct CullUniforms {
+    view_proj: Mat4,
+    previous_view_proj: Mat4,
+    transform: Mat4,
+    brickmap_depth: u32,
+    index_count: u32,
+    hi_z_mips: u32,
+    hi_z_size: UVec2,
+}
+
+pub struct VolumeCulling {
+    volume: Entity,
+    brickmap_depth: u32,
+    bind_group: BindGroup,
+    dispatch_bind_group: BindGroup,
+}
+
+/// The cull bind groups of every volume for a view.
+#[derive(Component, Deref)]
+pub struct ViewCullingBindGroups(Vec<VolumeCulling>);
+
+fn prepare_culling_buffers(
+    mut culling_buffers: ResMut<CullingBuffers>,
+    gpu_voxel_volumes: Res<GpuVoxelVolumes>,
+    views: Query<(), With<GpuCulling>>,
+    render_device: Res<RenderDevice>,
+) {
+    if views.is_empty() {
+        culling_buffers.clear();
+        return;
+    }
+
+    culling_buffers.retain(|entity, _| gpu_voxel_volumes.contains_key(entity));
+    for (entity, gpu_voxel_volume) in gpu_voxel_volumes.iter() {
+        let gpu_voxel_world = &gpu_voxel_volume.gpu_world;
+        let nodes = gpu_voxel_world.brickmap.len();
+        let dim = gpu_voxel_world.color_texture_size / BRICK_SIZE;
+        let bricks = (dim.x * dim.y * dim.z) as usize;
+        if culling_buffers
+            .get(entity)
+            .is_some_and(|buffers| buffers.nodes == nodes && buffers.bricks == bricks)
+        {
+            continue;
+        }
+
+        // every node is queued at most once and every brick drawn at most once
+        let storage_buffer = |label, size, usage| {
+            render_device.create_buffer(&BufferDescriptor {
+                label: Some(label),
+                size,
+                usage: BufferUsages::STORAGE | usage,
+                mapped_at_creation: false,
+            })
+        };
+        let instance_size = std::mem::size_of::<BrickInstance>() as u64;
+        culling_buffers.insert(
+            *entity,
+            VolumeCullingBuffers {
+                queue: storage_buffer("cull queue", 16 * nodes as u64, BufferUsages::empty()),
+                counters: storage_buffer("cull counters", 16, BufferUsages::empty()),
+                dispatch_args: storage_buffer("cull dispatch args", 12, BufferUsages::INDIRECT),
+                draw_args: storage_buffer("cull draw args", 20, BufferUsages::INDIRECT),
+                instances: storage_buffer(
+                    "culled instance buffer",
+                    instance_size * bricks as u64,
+                    BufferUsages::VERTEX,
+                ),
+                nodes,
+                bricks,
+            },
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn prepare_bind_groups(
+    mut commands: Commands,
+    views: Query<(
+        Entity,
+        &ExtractedView,
+        &GpuCulling,
+        &ViewDepthTexture,
+        Option<&VoxelRenderer>,
+    )>,
+    voxel_volumes: Query<(Entity, &ExtractedVoxelWorld)>,
+    gpu_voxel_volumes: Res<GpuVoxelVolumes>,
+    culling_buffers: Res<CullingBuffers>,
+    mut hi_z_textures: ResMut<HiZTextures>,
+    culling_pipelines: Res<CullingPipelines>,
+    meshes: Res<RenderAssets<Mesh>>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    hi_z_textures.retain(|entity, _| views.contains(*entity));
+
+    for (entity, view, gpu_culling, depth, renderer) in views.iter() {
+        if renderer.is_some_and(|renderer| *renderer != VoxelRenderer::Instanced) {
+            continue;
+        }
+
+        let view_proj = view.projection * view.transform.compute_matrix().inverse();
+
+        // occlusion culling tests against the pyramid built last frame, and
+        // this frame's depth builds the next one
+        let size = UVec2::new(depth.texture.width(), depth.texture.height());
+        let occlusion_culling = gpu_culling.occlusion_culling
+            && depth
+                .texture
+                .usage()
+                .contains(TextureUsages::TEXTURE_BINDING)
+            && depth.texture.sample_count() == 1;
+        let mut previous = None;
+        if occlusion_culling {
+            let hi_z = hi_z_textures
+                .entry(entity)
+                .or_insert_with(|| HiZ::new(&render_device, size));
+            if hi_z.size != size {
+                *hi_z = HiZ::new(&render_device, size);
+            }
+            previous = hi_z
+                .view_proj
+                .map(|previous_view_proj| (previous_view_proj, hi_z.mips.len() as u32));
+            hi_z.view_proj = Some(view_proj);
+            commands.entity(entity).insert(BuildHiZ);
+        } else {
+            hi_z_textures.remove(&entity);
+        }
+        let hi_z_view = hi_z_textures
+            .get(&entity)
+            .map_or(&culling_pipelines.fallback_hi_z, |hi_z| &hi_z.view);
+        let (previous_view_proj, hi_z_mips) = previous.unwrap_or((view_proj, 0));
+
+        let mut bind_groups = Vec::new();
+        for (volume, extracted) in voxel_volumes.iter() {
+            let Some(gpu_voxel_volume) = gpu_voxel_volumes.get(&volume) else {
+                continue;
+            };
+            let Some(buffers) = culling_buffers.get(&volume) else {
+                continue;
+            };
+            let Some(index_count) = render_mesh_instances
+                .get(&volume)
+                .and_then(|mesh_instance| meshes.get(mesh_instance.mesh_asset_id))
+                .and_then(|mesh| match mesh.buffer_info {
+                    GpuBufferInfo::Indexed { count, .. } => Some(count),
+                    GpuBufferInfo::NonIndexed => None,
+                })
+            else {
+                continue;
+            };
+
+            let brickmap_depth = gpu_voxel_volume.gpu_world.brickmap_depth;
+            let mut uniform_buffer = UniformBuffer::from(CullUniforms {
+                view_proj,
+                previous_view_proj,
+                transform: extracted.transform.compute_matrix(),
+                brickmap_depth,
+                index_count,
+                hi_z_mips,
+                hi_z_size: size,
+            });
+            uniform_buffer.write_buffer(&render_device, &render_queue);
+
+            let bind_group = render_device.create_bind_group(
+                Some("cull bind group"),
+                &culling_pipelines.bind_group_layout,
+                &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: uniform_buffer.binding().unwrap(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: gpu_voxel_volume.data.brickmap.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: buffers.queue.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: buffers.counters.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 4,
+                        resource: buffers.draw_args.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 5,
+                        resource: buffers.instances.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 6,
+                        resource: BindingResource::TextureView(hi_z_view),
+                    },
+                ],
+            );
+            let dispatch_bind_group = render_device.create_bind_group(
+                Some("cull dispatch bind group"),
+                &culling_pipelines.dispatch_bind_group_layout,
+                &[BindGroupEntry {
+                    binding: 0,
+                    resource: buffers.dispatch_args.as_entire_binding(),
+                }],
+            );
+
+            bind_groups.push(VolumeCulling {
+                volume,
+                brickmap_depth,
+                bind_group,
+                dispatch_bind_group,
+            });
+        }
+
+        commands
+            .entity(entity)
+            .insert(ViewCullingBindGroups(bind_groups));
+    }
+}
+
+#[derive(Resource)]
+pub struct CullingPipelines {
+    bind_group_layout: BindGroupLayout,
+    dispatch_bind_group_layout: BindGroupLayout,
+    copy_depth_bind_group_layout: BindGroupLayout,
+    downsample_bind_group_layout: BindGroupLayout,
+    reset: CachedComputePipelineId,
+    next_level: CachedComputePipelineId,
+    cull: CachedComputePipelineId,
+    copy_depth: CachedComputePipelineId,
+    downsample: CachedComputePipelineId,
+    /// bound in place of the depth pyramid when occlusion culling is off
+    fallback_hi_z: TextureView,
+}
+
+fn storage_entry(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn texture_entry(binding: u32, sample_type: TextureSampleType) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Texture {
+            sample_type,
+            view_dimension: TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+fn hi_z_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::StorageTexture {
+            access: StorageTextureAccess::WriteOnly,
+            format: TextureFormat::R32Float,
+            view_dimension: TextureViewDimension::D2,
+        },
+        count: None,
+    }
+}
+
+impl FromWorld for CullingPipelines {
+    fn from_world(render_world: &mut World) -> Self {
+        let render_device = render_world.resource::<RenderDevice>();
+        let unfilterable = TextureSampleType::Float { filterable: false };
+
+        let bind_group_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("cull bind group layout"),
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(CullUniforms::SHADER_SIZE.into()),
+                        },
+                        count: None,
+                    },
+                    storage_entry(1, true),
+                    storage_entry(2, false),
+                    storage_entry(3, false),
+                    storage_entry(4, false),
+                    storage_entry(5, false),
+                    texture_entry(6, unfilterable),
+                ],
+            });
+        let dispatch_bind_group_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("cull dispatch bind group layout"),
+                entries: &[storage_entry(0, false)],
+            });
+        let copy_depth_bind_group_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("hi z copy bind group layout"),
+                entries: &[texture_entry(0, TextureSampleType::Depth), hi_z_entry(1)],
+            });
+        let downsample_bind_group_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("hi z downsample bind group layout"),
+                entries: &[texture_entry(0, unfilterable), hi_z_entry(1)],
+            });
+
+        let fallback_hi_z = render_device
+            .create_texture(&TextureDescriptor {
+                label: Some("fallback hi z texture"),
+                size: Extent3d::default(),
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::R32Float,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            })
+            .create_view(&TextureViewDescriptor::default());
+
+        let asset_server = render_world.resource::<AssetServer>();
+        let cull_shader = asset_server.load("cull_bricks.wgsl");
+        let hi_z_shader = asset_server.load("hi_z.wgsl");
+
+        let pipeline_cache = render_world.resource::<PipelineCache>();
+        let queue_pipeline = |label: &'static str,
+                              layout: Vec<BindGroupLayout>,
+                              shader: &Handle<Shader>,
+                              shader_defs: Vec<ShaderDefVal>,
+                              entry_point: &'static str| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(label.into()),
+                layout,
+                push_constant_ranges: Vec::new(),
+                shader: shader.clone(),
+                shader_defs,
+                entry_point: entry_point.into(),
+            })
+        };
+        let with_dispatch = vec![
+            bind_group_layout.clone(),
+            dispatch_bind_group_layout.clone(),
+        ];
+        let reset = queue_pipeline(
+            "cull reset pipeline",
+            with_dispatch.clone(),
+            &cull_shader,
+            Vec::new(),
+            "reset",
+        );
+        let next_level = queue_pipeline(
+            "cull next level pipeline",
+            with_dispatch,
+            &cull_shader,
+            Vec::new(),
+            "next_level",
+        );
+        let cull = queue_pipeline(
+            "cull pipeline",
+            vec![bind_group_layout.clone()],
+            &cull_shader,
+            Vec::new(),
+            "cull",
+        );
+        let copy_depth = queue_pipeline(
+            "hi z copy pipeline",
+            vec![copy_depth_bind_group_layout.clone()],
+            &hi_z_shader,
+            vec!["COPY_DEPTH".into()],
+            "copy_depth",
+        );
+        let downsample = queue_pipeline(
+            "hi z downsample pipeline",
+            vec![downsample_bind_group_layout.clone()],
+            &hi_z_shader,
+            Vec::new(),
+            "downsample",
+        );
+
+        Self {
+            bind_group_layout,
+            dispatch_bind_group_layout,
+            copy_depth_bind_group_layout,
+            downsample_bind_group_layout,
+            reset,
+            next_level,
+            cull,
+            copy_depth,
+            downsample,
+            fallback_hi_z,
+        }
+    }
+}
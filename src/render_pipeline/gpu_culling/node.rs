@@ -0,0 +1,133 @@
+use super::{BuildHiZ, CullingBuffers, CullingPipelines, HiZTextures, ViewCullingBindGroups};
+use bevy::{
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        render_graph::{self, RenderGraphContext, ViewNode},
+        render_resource::*,
+        renderer::RenderContext,
+        view::ViewDepthTexture,
+    },
+};
+
+#[derive(Default)]
+pub struct CullNode;
+
+impl ViewNode for CullNode {
+    type ViewQuery = &'static ViewCullingBindGroups;
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        bind_groups: QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipelines = world.resource::<CullingPipelines>();
+        let culling_buffers = world.resource::<CullingBuffers>();
+
+        let (Some(reset), Some(next_level), Some(cull)) = (
+            pipeline_cache.get_compute_pipeline(pipelines.reset),
+            pipeline_cache.get_compute_pipeline(pipelines.next_level),
+            pipeline_cache.get_compute_pipeline(pipelines.cull),
+        ) else {
+            return Ok(());
+        };
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("brick cull pass"),
+                });
+
+        // one dispatch per level of the brickmap, each sized by the nodes the
+        // level above queued
+        for volume in bind_groups.iter() {
+            let Some(buffers) = culling_buffers.get(&volume.volume) else {
+                continue;
+            };
+            pass.set_bind_group(0, &volume.bind_group, &[]);
+            pass.set_bind_group(1, &volume.dispatch_bind_group, &[]);
+            pass.set_pipeline(reset);
+            pass.dispatch_workgroups(1, 1, 1);
+            for _ in 0..volume.brickmap_depth {
+                pass.set_pipeline(cull);
+                pass.dispatch_workgroups_indirect(&buffers.dispatch_args, 0);
+                pass.set_pipeline(next_level);
+                pass.dispatch_workgroups(1, 1, 1);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct HiZNode;
+
+impl ViewNode for HiZNode {
+    type ViewQuery = (Entity, &'static ViewDepthTexture, &'static BuildHiZ);
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (entity, depth, _): QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipelines = world.resource::<CullingPipelines>();
+
+        let Some(hi_z) = world.resource::<HiZTextures>().get(&entity) else {
+            return Ok(());
+        };
+        let (Some(copy_depth), Some(downsample)) = (
+            pipeline_cache.get_compute_pipeline(pipelines.copy_depth),
+            pipeline_cache.get_compute_pipeline(pipelines.downsample),
+        ) else {
+            return Ok(());
+        };
+
+        // copy the depth into the first mip, then halve it down to 1x1
+        let bind_groups = (0..hi_z.mips.len())
+            .map(|mip| {
+                let (layout, source) = match mip {
+                    0 => (&pipelines.copy_depth_bind_group_layout, &depth.view),
+                    _ => (&pipelines.downsample_bind_group_layout, &hi_z.mips[mip - 1]),
+                };
+                render_context.render_device().create_bind_group(
+                    Some("hi z bind group"),
+                    layout,
+                    &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: BindingResource::TextureView(source),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: BindingResource::TextureView(&hi_z.mips[mip]),
+                        },
+                    ],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("hi z pass"),
+                });
+
+        for (mip, bind_group) in bind_groups.iter().enumerate() {
+            let size = (hi_z.size >> mip as u32).max(UVec2::ONE);
+            pass.set_pipeline(if mip == 0 { copy_depth } else { downsample });
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups(size.x.div_ceil(8), size.y.div_ceil(8), 1);
+        }
+
+        Ok(())
+    }
+}
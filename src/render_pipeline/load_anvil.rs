@@ -1,132 +1,503 @@
 use super::{
-    cpu_brickmap::{Brick, CpuBrickmap},
+    cpu_brickmap::{Brick, CpuBrickmap, Material, PagedWriter},
     BRICK_SIZE,
 };
+use anyhow::Result;
 use bevy::{prelude::*, utils::HashMap};
-use std::path::PathBuf;
+use fastanvil::Block;
+use fastnbt::ByteArray;
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// What kind of block a voxel of a loaded world is, stored as its material id.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum BlockKind {
+    Unknown = 0,
+    Stone,
+    Soil,
+    Sand,
+    Wood,
+    Leaves,
+    Plant,
+    Glass,
+    Ice,
+    Snow,
+    Metal,
+    Water,
+    Lava,
+}
+
+impl BlockKind {
+    const ALL: [Self; 13] = [
+        Self::Unknown,
+        Self::Stone,
+        Self::Soil,
+        Self::Sand,
+        Self::Wood,
+        Self::Leaves,
+        Self::Plant,
+        Self::Glass,
+        Self::Ice,
+        Self::Snow,
+        Self::Metal,
+        Self::Water,
+        Self::Lava,
+    ];
+
+    /// the kind a material id of a loaded world stands for
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+}
+
+/// blocks whose name contains the pattern are of this kind, the first match
+/// wins
+const BLOCK_KINDS: &[(&str, BlockKind)] = &[
+    ("water", BlockKind::Water),
+    ("lava", BlockKind::Lava),
+    ("glass", BlockKind::Glass),
+    ("ice", BlockKind::Ice),
+    ("snow", BlockKind::Snow),
+    ("leaves", BlockKind::Leaves),
+    ("log", BlockKind::Wood),
+    ("wood", BlockKind::Wood),
+    ("planks", BlockKind::Wood),
+    ("iron_block", BlockKind::Metal),
+    ("gold_block", BlockKind::Metal),
+    ("copper", BlockKind::Metal),
+    ("netherite_block", BlockKind::Metal),
+    ("sandstone", BlockKind::Stone),
+    ("sand", BlockKind::Sand),
+    ("grass_block", BlockKind::Soil),
+    ("dirt", BlockKind::Soil),
+    ("gravel", BlockKind::Soil),
+    ("mud", BlockKind::Soil),
+    ("grass", BlockKind::Plant),
+    ("fern", BlockKind::Plant),
+    ("kelp", BlockKind::Plant),
+    ("stone", BlockKind::Stone),
+    ("deepslate", BlockKind::Stone),
+    ("granite", BlockKind::Stone),
+    ("diorite", BlockKind::Stone),
+    ("andesite", BlockKind::Stone),
+    ("tuff", BlockKind::Stone),
+    ("ore", BlockKind::Stone),
+];
+
+fn block_kind(name: &str) -> BlockKind {
+    BLOCK_KINDS
+        .iter()
+        .find(|(pattern, _)| name.contains(pattern))
+        .map_or(BlockKind::Unknown, |(_, kind)| *kind)
+}
+
+/// The colour of a block and what it's made of.
+#[derive(Clone, Copy)]
+pub struct PaletteEntry {
+    pub colour: [u8; 4],
+    pub material: Material,
+}
+
+impl From<[u8; 4]> for PaletteEntry {
+    fn from(colour: [u8; 4]) -> Self {
+        Self {
+            colour,
+            material: Material::default(),
+        }
+    }
+}
+
+/// blocks whose name contains the pattern reflect this sharply, unless the
+/// palette says otherwise
+const ROUGHNESS: &[(&str, f32)] = &[
+    ("polished", 0.2),
+    ("smooth", 0.4),
+    ("glass", 0.0),
+    ("ice", 0.1),
+];
+
+/// The colours and materials of minecraft blocks. Entries are keyed by block
+/// name, or by name and state like `Block::encoded_description`.
+pub struct Palette {
+    entries: HashMap<String, PaletteEntry>,
+    /// the states of each block that has entries by state, for blocks with
+    /// properties the keys leave out, like a campfire's `signal_fire`
+    states: HashMap<String, Vec<String>>,
+}
+
+impl Palette {
+    pub fn load() -> Self {
+        let entries = load_palette();
+        let mut states = HashMap::<String, Vec<String>>::new();
+        for key in entries.keys() {
+            if let Some((name, _)) = key.split_once('|') {
+                states
+                    .entry(name.to_string())
+                    .or_default()
+                    .push(key.clone());
+            }
+        }
+        // so the same state wins every time
+        for keys in states.values_mut() {
+            keys.sort();
+        }
+        Self { entries, states }
+    }
 
-fn load_palette() -> HashMap<String, [u8; 4]> {
+    /// the entry of the block in its state, falling back to its name alone,
+    /// then to any state whose properties all match the block's
+    pub fn get(&self, block: &Block) -> &PaletteEntry {
+        let encoded = block.encoded_description();
+        if let Some(entry) = self
+            .entries
+            .get(encoded)
+            .or_else(|| self.entries.get(block.name()))
+        {
+            return entry;
+        }
+
+        let properties = encoded
+            .split_once('|')
+            .map_or("", |(_, properties)| properties);
+        let state = self.states.get(block.name()).and_then(|states| {
+            states.iter().find(|key| {
+                let (_, state) = key.split_once('|').unwrap();
+                state
+                    .split(',')
+                    .all(|property| properties.split(',').any(|p| p == property))
+            })
+        });
+        match state {
+            Some(key) => &self.entries[key],
+            None => &self.entries[""],
+        }
+    }
+}
+
+fn load_palette() -> HashMap<String, PaletteEntry> {
     let file = std::fs::File::open("assets/palette/blockstates.json");
-    let mut json: HashMap<String, [u8; 4]> = serde_json::from_reader(file.unwrap()).unwrap();
-    json.insert("".to_string(), [200, 200, 200, 127]);
-    json.insert("minecraft:grass".to_string(), [0, 0, 0, 0]);
-    json.insert("minecraft:tall_grass".to_string(), [0, 0, 0, 0]);
-    json.insert("minecraft:grass_block".to_string(), [62, 204, 18, 255]);
-    json.insert("minecraft:water".to_string(), [20, 105, 201, 30]);
-    json.insert("minecraft:cave_air".to_string(), [0, 0, 0, 0]);
-    json.insert("minecraft:lava".to_string(), [255, 123, 0, 255]);
-    json.insert("minecraft:seagrass".to_string(), [62, 204, 18, 255]);
-    json.insert("minecraft:deepslate".to_string(), [77, 77, 77, 255]);
-    json.insert("minecraft:oak_log".to_string(), [112, 62, 8, 255]);
-    json.insert("minecraft:oak_stairs".to_string(), [112, 62, 8, 255]);
+    // entries are rgba with optional values for emission, then roughness,
+    // then metalness
+    let entries: HashMap<String, Vec<u8>> = serde_json::from_reader(file.unwrap()).unwrap();
+    let mut json = entries
+        .into_iter()
+        .filter_map(|(name, values)| match values[..] {
+            [r, g, b, a] | [r, g, b, a, _] => {
+                let mut entry = PaletteEntry::from([r, g, b, a]);
+                entry.material.emission = values.get(4).copied().unwrap_or(0);
+                if let Some((_, roughness)) =
+                    ROUGHNESS.iter().find(|(pattern, _)| name.contains(pattern))
+                {
+                    entry.material.roughness = *roughness;
+                }
+                if block_kind(&name) == BlockKind::Metal {
+                    entry.material.roughness = 0.3;
+                    entry.material.metalness = 1.0;
+                }
+                Some((name, entry))
+            }
+            [r, g, b, a, emission, roughness] | [r, g, b, a, emission, roughness, _] => Some((
+                name,
+                PaletteEntry {
+                    colour: [r, g, b, a],
+                    material: Material {
+                        emission,
+                        roughness: roughness as f32 / 255.0,
+                        metalness: values.get(6).map_or(0.0, |m| *m as f32 / 255.0),
+                        ..default()
+                    },
+                },
+            )),
+            _ => {
+                warn!("palette entry {} has {} values", name, values.len());
+                None
+            }
+        })
+        .collect::<HashMap<String, PaletteEntry>>();
+    // unknown blocks, alpha below 255 renders as transparent so keep it opaque
+    json.insert("".to_string(), [200, 200, 200, 255].into());
+    json.insert("minecraft:grass".to_string(), [0, 0, 0, 0].into());
+    json.insert("minecraft:tall_grass".to_string(), [0, 0, 0, 0].into());
+    json.insert(
+        "minecraft:grass_block".to_string(),
+        [62, 204, 18, 255].into(),
+    );
+    json.insert(
+        "minecraft:water".to_string(),
+        PaletteEntry {
+            colour: [20, 105, 201, 30],
+            material: Material {
+                roughness: 0.05,
+                ..default()
+            },
+        },
+    );
+    json.insert("minecraft:cave_air".to_string(), [0, 0, 0, 0].into());
+    json.insert(
+        "minecraft:lava".to_string(),
+        PaletteEntry {
+            colour: [255, 123, 0, 255],
+            material: Material {
+                emission: 255,
+                ..default()
+            },
+        },
+    );
+    json.insert("minecraft:seagrass".to_string(), [62, 204, 18, 255].into());
+    json.insert("minecraft:deepslate".to_string(), [77, 77, 77, 255].into());
+    json.insert("minecraft:oak_log".to_string(), [112, 62, 8, 255].into());
+    json.insert("minecraft:oak_stairs".to_string(), [112, 62, 8, 255].into());
+
+    for (name, entry) in json.iter_mut() {
+        entry.material.id = block_kind(name) as u8;
+        entry.material.transparent = entry.colour[3] < 255;
+    }
 
     json
 }
 
-pub fn load_anvil(region_path: PathBuf, world_depth: u32) -> CpuBrickmap {
-    let side_length = 1 << world_depth;
-    let mut brickmap = CpuBrickmap::new(world_depth - BRICK_SIZE.trailing_zeros());
+/// The light arrays of a chunk's nbt, the rest of it is skipped.
+#[derive(Deserialize)]
+struct ChunkLightNbt {
+    #[serde(default)]
+    sections: Vec<SectionLightNbt>,
+}
 
-    // load mc palette
-    let palette = load_palette();
+#[derive(Deserialize)]
+struct SectionLightNbt {
+    #[serde(rename = "Y")]
+    y: i8,
+    #[serde(rename = "SkyLight")]
+    sky_light: Option<ByteArray>,
+    #[serde(rename = "BlockLight")]
+    block_light: Option<ByteArray>,
+}
 
-    // load chunks into the texture
-    use fastanvil::{CurrentJavaChunk, Region};
-    use fastnbt::from_bytes;
+/// Sky and block light of the sections of a chunk, keyed by section y. Each
+/// array packs the light of 16x16x16 blocks into nibbles, y then z then x.
+struct ChunkLight(HashMap<i8, (Option<ByteArray>, Option<ByteArray>)>);
 
-    let side_length_chunks = side_length / 16;
-    let side_length_regions = (side_length_chunks / 32).max(1);
+impl ChunkLight {
+    /// fastanvil skips the light arrays, so they are read from the raw nbt
+    fn from_nbt(data: &[u8]) -> Self {
+        let Ok(chunk) = fastnbt::from_bytes::<ChunkLightNbt>(data) else {
+            return Self(HashMap::new());
+        };
+        let full = |array: Option<ByteArray>| array.filter(|array| array.len() == 2048);
+        let sections = chunk
+            .sections
+            .into_iter()
+            .map(|section| {
+                let light = (full(section.sky_light), full(section.block_light));
+                (section.y, light)
+            })
+            .collect();
+        Self(sections)
+    }
+
+    /// sky and block light from 0 to 15 of the block at pos in section y.
+    /// pos may leave the section vertically but not horizontally. sections
+    /// without a sky light array are open to the sky
+    fn get(&self, section_y: i8, pos: IVec3) -> (u8, u8) {
+        let section_y = section_y as i32 + pos.y.div_euclid(16);
+        let pos = IVec3::new(pos.x, pos.y.rem_euclid(16), pos.z);
+        let nibble = |array: &ByteArray| {
+            let index = (pos.y * 256 + pos.z * 16 + pos.x) as usize;
+            (array[index / 2] as u8 >> (4 * (index % 2))) & 15
+        };
+        match self.0.get(&(section_y as i8)) {
+            Some((sky, block)) => (
+                sky.as_ref().map_or(15, nibble),
+                block.as_ref().map_or(0, nibble),
+            ),
+            None => (15, 0),
+        }
+    }
+
+    /// light of a voxel's surface, the brightest of the block itself and its
+    /// neighbours in the chunk, as opaque blocks have no light of their own
+    fn surface(&self, section_y: i8, pos: IVec3) -> (u8, u8) {
+        let mut light = self.get(section_y, pos);
+        for offset in [
+            IVec3::X,
+            IVec3::NEG_X,
+            IVec3::Y,
+            IVec3::NEG_Y,
+            IVec3::Z,
+            IVec3::NEG_Z,
+        ] {
+            let neighbour = pos + offset;
+            if neighbour.x < 0 || neighbour.x >= 16 || neighbour.z < 0 || neighbour.z >= 16 {
+                continue;
+            }
+            let (sky, block) = self.get(section_y, neighbour);
+            light = (light.0.max(sky), light.1.max(block));
+        }
+        light
+    }
+}
+
+/// the region files of a world `1 << world_depth` blocks wide that exist,
+/// with their region coordinates
+fn region_files(region_path: &Path, world_depth: u32) -> Vec<(IVec2, PathBuf)> {
+    let side_length_regions = ((1 << world_depth) / 16 / 32).max(1);
     let half_side_length_regions: i32 = side_length_regions / 2;
-    'outer: for region_x in -half_side_length_regions..half_side_length_regions.max(1) {
+    let mut files = Vec::new();
+    for region_x in -half_side_length_regions..half_side_length_regions.max(1) {
         for region_z in -half_side_length_regions..half_side_length_regions.max(1) {
             let path = region_path.join(format!("r.{}.{}.mca", region_x, region_z));
-            if let Ok(file) = std::fs::File::open(path.clone()) {
-                info!("loading region {}", path.display());
-                let mut region = match Region::from_stream(file) {
-                    Ok(region) => region,
-                    Err(e) => {
-                        error!("failed to load region {}: {}", path.display(), e);
+            if path.is_file() {
+                files.push((IVec2::new(region_x, region_z), path));
+            } else {
+                info!("skipping region {}", path.display());
+            }
+        }
+    }
+    files
+}
+
+/// the coordinates, size and modification time of each region file, to tell
+/// when a converted world is out of date
+pub fn region_stamps(region_path: &Path, world_depth: u32) -> Vec<u64> {
+    let mut stamps = Vec::new();
+    for (region, path) in region_files(region_path, world_depth) {
+        let metadata = std::fs::metadata(&path).ok();
+        let modified = metadata
+            .as_ref()
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_nanos() as u64);
+        stamps.extend([
+            region.x as u64,
+            region.y as u64,
+            metadata.map_or(0, |metadata| metadata.len()),
+            modified,
+        ]);
+    }
+    stamps
+}
+
+/// places the chunks of a region file in the brickmap
+fn load_region(
+    brickmap: &mut CpuBrickmap,
+    palette: &Palette,
+    path: &Path,
+    region: IVec2,
+    world_depth: u32,
+) -> Result<(), String> {
+    use fastanvil::{CurrentJavaChunk, Region};
+    use fastnbt::from_bytes;
+
+    let side_length_chunks = (1 << world_depth) / 16;
+    let half_side_length_regions: i32 = (side_length_chunks / 32).max(1) / 2;
+    let (region_x, region_z) = (region.x, region.y);
+
+    info!("loading region {}", path.display());
+    let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let mut region = match Region::from_stream(file) {
+        Ok(region) => region,
+        Err(e) => {
+            error!("failed to load region {}: {}", path.display(), e);
+            return Ok(());
+        }
+    };
+
+    for chunk_x in 0..side_length_chunks.min(32) {
+        for chunk_z in 0..side_length_chunks.min(32) {
+            if let Some(data) = region
+                .read_chunk(chunk_x as usize, chunk_z as usize)
+                .unwrap()
+            {
+                let chunk: CurrentJavaChunk = from_bytes(data.as_slice()).unwrap();
+                let chunk_light = ChunkLight::from_nbt(data.as_slice());
+                let section_tower = chunk.sections.unwrap();
+
+                for section in section_tower.sections() {
+                    if section.block_states.palette().len() <= 1 {
                         continue;
                     }
-                };
-
-                for chunk_x in 0..side_length_chunks.min(32) {
-                    for chunk_z in 0..side_length_chunks.min(32) {
-                        if let Some(data) = region
-                            .read_chunk(chunk_x as usize, chunk_z as usize)
-                            .unwrap()
-                        {
-                            let chunk: CurrentJavaChunk = from_bytes(data.as_slice()).unwrap();
-                            let section_tower = chunk.sections.unwrap();
-
-                            for section in section_tower.sections() {
-                                if section.block_states.palette().len() <= 1 {
-                                    continue;
-                                }
 
-                                let block_data = &section.block_states;
-                                let pos = UVec3::new(
-                                    32 * (region_x + half_side_length_regions) as u32
-                                        + chunk_x as u32,
-                                    (section.y as i32 + side_length_chunks / 2) as u32,
-                                    32 * (region_z + half_side_length_regions) as u32
-                                        + chunk_z as u32,
-                                );
-
-                                let chunk_side_length_bricks = 16 / BRICK_SIZE;
-                                for brick_x in 0..chunk_side_length_bricks {
-                                    for brick_y in 0..chunk_side_length_bricks {
-                                        for brick_z in 0..chunk_side_length_bricks {
-                                            let mut brick = Brick::empty();
-                                            for x in 0..BRICK_SIZE {
-                                                for y in 0..BRICK_SIZE {
-                                                    for z in 0..BRICK_SIZE {
-                                                        let block = block_data.at(
-                                                            (brick_x * BRICK_SIZE + x) as usize,
-                                                            (brick_y * BRICK_SIZE + y) as usize,
-                                                            (brick_z * BRICK_SIZE + z) as usize,
-                                                        );
-                                                        if block.unwrap().name() == "minecraft:air"
-                                                        {
-                                                            continue;
-                                                        }
-
-                                                        let block_name = block.unwrap().name();
-                                                        let defualt_col = palette.get("").unwrap();
-                                                        let colour = palette
-                                                            .get(block_name)
-                                                            .unwrap_or(defualt_col);
-
-                                                        let pos = UVec3::new(x, y, z);
-                                                        brick.write(pos, *colour);
-                                                    }
-                                                }
+                    let block_data = &section.block_states;
+                    let pos = UVec3::new(
+                        32 * (region_x + half_side_length_regions) as u32 + chunk_x as u32,
+                        (section.y as i32 + side_length_chunks / 2) as u32,
+                        32 * (region_z + half_side_length_regions) as u32 + chunk_z as u32,
+                    );
+
+                    let chunk_side_length_bricks = 16 / BRICK_SIZE;
+                    for brick_x in 0..chunk_side_length_bricks {
+                        for brick_y in 0..chunk_side_length_bricks {
+                            for brick_z in 0..chunk_side_length_bricks {
+                                let mut brick = Brick::empty();
+                                for x in 0..BRICK_SIZE {
+                                    for y in 0..BRICK_SIZE {
+                                        for z in 0..BRICK_SIZE {
+                                            let block = block_data.at(
+                                                (brick_x * BRICK_SIZE + x) as usize,
+                                                (brick_y * BRICK_SIZE + y) as usize,
+                                                (brick_z * BRICK_SIZE + z) as usize,
+                                            );
+                                            if block.unwrap().name() == "minecraft:air" {
+                                                continue;
                                             }
 
-                                            match brickmap.place_brick(
-                                                brick,
-                                                chunk_side_length_bricks * pos
-                                                    + UVec3::new(brick_x, brick_y, brick_z),
-                                            ) {
-                                                Ok(_) => {}
-                                                Err(e) => {
-                                                    println!("{}", e);
-                                                    break 'outer;
-                                                }
-                                            };
+                                            let entry = palette.get(block.unwrap());
+
+                                            // sky light is stored inverted
+                                            // so voxels without any light
+                                            // data are open to the sky
+                                            let (sky, block) = chunk_light.surface(
+                                                section.y,
+                                                UVec3::new(
+                                                    brick_x * BRICK_SIZE + x,
+                                                    brick_y * BRICK_SIZE + y,
+                                                    brick_z * BRICK_SIZE + z,
+                                                )
+                                                .as_ivec3(),
+                                            );
+
+                                            let pos = UVec3::new(x, y, z);
+                                            brick.write(pos, entry.colour);
+                                            brick.write_light(pos, [block * 17, 255 - sky * 17]);
+                                            brick.write_material(pos, entry.material);
                                         }
                                     }
                                 }
+
+                                brickmap.place_brick(
+                                    brick,
+                                    chunk_side_length_bricks * pos
+                                        + UVec3::new(brick_x, brick_y, brick_z),
+                                )?;
                             }
                         }
                     }
                 }
-            } else {
-                info!("skipping region {}", path.display());
             }
         }
     }
 
+    Ok(())
+}
+
+pub fn load_anvil(region_path: PathBuf, world_depth: u32) -> CpuBrickmap {
+    let mut brickmap = CpuBrickmap::new(world_depth - BRICK_SIZE.trailing_zeros());
+
+    // load mc palette
+    let palette = Palette::load();
+
+    // load chunks into the texture
+    for (region, path) in region_files(&region_path, world_depth) {
+        if let Err(e) = load_region(&mut brickmap, &palette, &path, region, world_depth) {
+            println!("{}", e);
+            break;
+        }
+    }
+
     // let file = std::fs::File::open("assets/region/r.0.0.mca").unwrap();
     // let mut region = Region::from_stream(file).unwrap();
     // let data = region.read_chunk(0, 0).unwrap().unwrap();
@@ -141,6 +512,35 @@ pub fn load_anvil(region_path: PathBuf, world_depth: u32) -> CpuBrickmap {
     brickmap
 }
 
+/// converts an anvil world to a paged brickmap at `paged_path` a region at a
+/// time, so only one region is in memory besides the coarse bricks. light is
+/// spread within each region, so it doesn't reach across region borders
+pub fn convert_anvil(
+    region_path: &Path,
+    world_depth: u32,
+    paged_path: &Path,
+    resident_depth: u32,
+    sources: &[u64],
+) -> Result<()> {
+    let brickmap_depth = world_depth - BRICK_SIZE.trailing_zeros();
+    // regions are 512 blocks wide, so the nodes that size and smaller each
+    // belong to one region
+    let region_depth = 9 - BRICK_SIZE.trailing_zeros();
+    let split_depth = brickmap_depth.saturating_sub(region_depth).max(1);
+    let mut writer = PagedWriter::new(paged_path, brickmap_depth, resident_depth, split_depth)?;
+
+    let palette = Palette::load();
+    for (region, path) in region_files(region_path, world_depth) {
+        let mut brickmap = CpuBrickmap::new(brickmap_depth);
+        load_region(&mut brickmap, &palette, &path, region, world_depth)
+            .map_err(anyhow::Error::msg)?;
+        brickmap.spread_light();
+        brickmap.recreate_mipmaps();
+        writer.add(brickmap)?;
+    }
+    writer.finish(sources)
+}
+
 // pub fn from_block_data(block_data: &BlockData<Block>, palette: &HashMap<String, [u8; 4]>) -> Brick {
 //     let mut brick = Brick::empty();
 //     for x in 0..BRICK_SIZE {
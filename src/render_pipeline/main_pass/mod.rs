@@ -1,14 +1,23 @@
-use std::sync::{Arc, Mutex};
-
-use super::voxel_world::VoxelData;
+use super::{
+    fog::{FogUniforms, VoxelFog},
+    sky::ExtractedSun,
+    voxel_world::VoxelBindGroupLayout,
+    DebugView, VoxelRenderer,
+};
 use bevy::{
-    core_pipeline::fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+    core_pipeline::{
+        core_3d::{self, CORE_3D, CORE_3D_DEPTH_FORMAT},
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+    },
     prelude::*,
     render::{
+        camera::TemporalJitter,
         extract_component::{ExtractComponent, ExtractComponentPlugin},
         extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_graph::{RenderGraphApp, ViewNodeRunner},
         render_resource::*,
         renderer::{RenderDevice, RenderQueue},
+        texture::BevyDefault,
         view::{ExtractedView, ViewTarget},
         Render, RenderApp, RenderSet,
     },
@@ -17,6 +26,10 @@ pub use node::MainPassNode;
 
 mod node;
 
+pub const VOXEL_MAIN_PASS: &str = "voxel_main_pass";
+
+/// Renders the voxel volumes of cameras with `VoxelRenderer::Fullscreen` by
+/// tracing every pixel through the brickmap in a fullscreen pass.
 pub struct MainPassPlugin;
 
 impl Plugin for MainPassPlugin {
@@ -25,35 +38,64 @@ impl Plugin for MainPassPlugin {
             .add_plugins(ExtractComponentPlugin::<BeamTexture>::default())
             .add_plugins(ExtractResourcePlugin::<FallbackBeamTexture>::default())
             .init_resource::<FallbackBeamTexture>()
+            .register_type::<MainPassSettings>()
             .add_systems(PostUpdate, update_textures);
+
+        app.sub_app_mut(RenderApp)
+            .add_render_graph_node::<ViewNodeRunner<MainPassNode>>(CORE_3D, VOXEL_MAIN_PASS)
+            .add_render_graph_edges(
+                CORE_3D,
+                &[
+                    core_3d::graph::node::MAIN_OPAQUE_PASS,
+                    VOXEL_MAIN_PASS,
+                    core_3d::graph::node::MAIN_TRANSPARENT_PASS,
+                ],
+            );
     }
 
     fn finish(&self, app: &mut App) {
         // setup custom render pipeline
         app.sub_app_mut(RenderApp)
-            .init_resource::<MainPassPipelineData>()
-            .add_systems(Render, prepare_uniforms.in_set(RenderSet::Prepare));
+            .init_resource::<MainPassPipeline>()
+            .init_resource::<SpecializedRenderPipelines<MainPassPipeline>>()
+            .add_systems(
+                Render,
+                (
+                    prepare_uniforms.in_set(RenderSet::Prepare),
+                    prepare_pipelines.in_set(RenderSet::Prepare),
+                ),
+            );
     }
 }
 
 #[derive(Component, ExtractComponent, Clone)]
 pub struct BeamTexture {
     image: Handle<Image>,
-    filled: Arc<Mutex<bool>>,
 }
 
 #[derive(Resource, ExtractResource, Clone, Deref, DerefMut)]
 struct FallbackBeamTexture(Handle<Image>);
 
 #[derive(Resource)]
-struct MainPassPipelineData {
-    pipeline_id: CachedRenderPipelineId,
+struct MainPassPipeline {
+    shader: Handle<Shader>,
+    voxel_bind_group_layout: BindGroupLayout,
     bind_group_layout: BindGroupLayout,
+    /// the beam pre-pass always renders into an `Rgba16Float` beam texture
+    beam_pipeline_id: CachedRenderPipelineId,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct MainPassPipelineKey {
+    hdr: bool,
+    samples: u32,
+}
+
+#[derive(Component, Deref)]
+pub struct MainPassPipelineId(CachedRenderPipelineId);
+
 #[derive(Component, Clone, ExtractComponent, Reflect)]
 pub struct MainPassSettings {
-    pub show_ray_steps: bool,
     pub indirect_lighting: bool,
     pub shadows: bool,
     pub beam_optimization: bool,
@@ -65,7 +107,6 @@ pub struct MainPassSettings {
 impl Default for MainPassSettings {
     fn default() -> Self {
         Self {
-            show_ray_steps: false,
             indirect_lighting: true,
             shadows: true,
             beam_optimization: true,
@@ -81,29 +122,49 @@ pub struct MainPassUniforms {
     camera: Mat4,
     camera_inverse: Mat4,
     time: f32,
-    show_ray_steps: u32,
+    debug_view: u32,
     indirect_lighting: u32,
     shadows: u32,
     super_pixel_size: u32,
+    /// size of the beam texture the beam pass renders into
+    beam_size: UVec2,
     misc_bool: u32,
     misc_float: f32,
+    sun_direction: Vec3,
+    sun_colour: Vec3,
+    // structs in uniforms have to start on 16 bytes
+    #[align(16)]
+    fog: FogUniforms,
 }
 
 #[derive(Component, Deref, DerefMut)]
 pub struct ViewMainPassUniformBuffer(UniformBuffer<MainPassUniforms>);
 
+#[allow(clippy::type_complexity)]
 fn prepare_uniforms(
     mut commands: Commands,
-    query: Query<(Entity, &MainPassSettings, &ExtractedView)>,
+    query: Query<(
+        Entity,
+        &MainPassSettings,
+        &ExtractedView,
+        Option<&VoxelFog>,
+        Option<&DebugView>,
+        Option<&TemporalJitter>,
+    )>,
     time: Res<Time>,
+    sun: Res<ExtractedSun>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
 ) {
     let elapsed = time.elapsed_seconds_f64();
 
-    for (entity, settings, view) in query.iter() {
-        let projection = view.projection;
+    for (entity, settings, view, fog, debug_view, jitter) in query.iter() {
+        let mut projection = view.projection;
+        if let Some(jitter) = jitter {
+            jitter.jitter_projection(&mut projection, view.viewport.zw().as_vec2());
+        }
         let inverse_projection = projection.inverse();
+        let beam_size = beam_size(view.viewport.zw(), settings);
         let view = view.transform.compute_matrix();
         let inverse_view = view.inverse();
 
@@ -114,12 +175,16 @@ fn prepare_uniforms(
             camera,
             camera_inverse,
             time: elapsed as f32,
-            show_ray_steps: settings.show_ray_steps as u32,
+            debug_view: debug_view.copied().unwrap_or_default() as u32,
             indirect_lighting: settings.indirect_lighting as u32,
-            shadows: settings.shadows as u32,
+            shadows: (settings.shadows && sun.shadows) as u32,
             super_pixel_size: settings.super_pixel_size,
+            beam_size,
             misc_bool: settings.misc_bool as u32,
             misc_float: settings.misc_float,
+            sun_direction: sun.direction,
+            sun_colour: sun.colour,
+            fog: fog.map(FogUniforms::from).unwrap_or_default(),
         };
 
         let mut uniform_buffer = UniformBuffer::from(uniforms);
@@ -131,12 +196,36 @@ fn prepare_uniforms(
     }
 }
 
-impl FromWorld for MainPassPipelineData {
+fn prepare_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    main_pass_pipeline: Res<MainPassPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<MainPassPipeline>>,
+    msaa: Res<Msaa>,
+    views: Query<(Entity, &ExtractedView, &VoxelRenderer), With<MainPassSettings>>,
+) {
+    for (entity, view, renderer) in views.iter() {
+        if *renderer != VoxelRenderer::Fullscreen {
+            continue;
+        }
+
+        let key = MainPassPipelineKey {
+            hdr: view.hdr,
+            samples: msaa.samples(),
+        };
+        let pipeline_id = pipelines.specialize(&pipeline_cache, &main_pass_pipeline, key);
+        commands
+            .entity(entity)
+            .insert(MainPassPipelineId(pipeline_id));
+    }
+}
+
+impl FromWorld for MainPassPipeline {
     fn from_world(render_world: &mut World) -> Self {
-        let voxel_data = render_world.get_resource::<VoxelData>().unwrap();
+        let voxel_bind_group_layout = render_world.resource::<VoxelBindGroupLayout>().0.clone();
         let asset_server = render_world.get_resource::<AssetServer>().unwrap();
+        let shader = asset_server.load("shader.wgsl");
 
-        let voxel_bind_group_layout = voxel_data.bind_group_layout.clone();
         let bind_group_layout = render_world
             .resource::<RenderDevice>()
             .create_bind_group_layout(&BindGroupLayoutDescriptor {
@@ -165,19 +254,26 @@ impl FromWorld for MainPassPipelineData {
                 ],
             });
 
-        let trace_shader = asset_server.load("shader.wgsl");
-
-        let trace_pipeline_descriptor = RenderPipelineDescriptor {
-            label: Some("trace pipeline".into()),
-            layout: vec![voxel_bind_group_layout, bind_group_layout.clone()],
+        // writes the distance each super pixel can safely skip, volumes are
+        // combined by keeping the minimum
+        let beam_pipeline_descriptor = RenderPipelineDescriptor {
+            label: Some("beam pipeline".into()),
+            layout: vec![voxel_bind_group_layout.clone(), bind_group_layout.clone()],
             vertex: fullscreen_shader_vertex_state(),
             fragment: Some(FragmentState {
-                shader: trace_shader,
+                shader: shader.clone(),
                 shader_defs: Vec::new(),
-                entry_point: "fragment".into(),
+                entry_point: "beam".into(),
                 targets: vec![Some(ColorTargetState {
-                    format: ViewTarget::TEXTURE_FORMAT_HDR,
-                    blend: None,
+                    format: TextureFormat::Rgba16Float,
+                    blend: Some(BlendState {
+                        color: BlendComponent {
+                            src_factor: BlendFactor::One,
+                            dst_factor: BlendFactor::One,
+                            operation: BlendOperation::Min,
+                        },
+                        alpha: BlendComponent::REPLACE,
+                    }),
                     write_mask: ColorWrites::ALL,
                 })],
             }),
@@ -186,17 +282,70 @@ impl FromWorld for MainPassPipelineData {
             multisample: MultisampleState::default(),
             push_constant_ranges: Vec::new(),
         };
+        let beam_pipeline_id = render_world
+            .resource::<PipelineCache>()
+            .queue_render_pipeline(beam_pipeline_descriptor);
 
-        let cache = render_world.resource::<PipelineCache>();
-        let pipeline_id = cache.queue_render_pipeline(trace_pipeline_descriptor);
-
-        MainPassPipelineData {
-            pipeline_id,
+        MainPassPipeline {
+            shader,
+            voxel_bind_group_layout,
             bind_group_layout,
+            beam_pipeline_id,
         }
     }
 }
 
+impl SpecializedRenderPipeline for MainPassPipeline {
+    type Key = MainPassPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let format = if key.hdr {
+            ViewTarget::TEXTURE_FORMAT_HDR
+        } else {
+            TextureFormat::bevy_default()
+        };
+
+        RenderPipelineDescriptor {
+            label: Some("trace pipeline".into()),
+            layout: vec![
+                self.voxel_bind_group_layout.clone(),
+                self.bind_group_layout.clone(),
+            ],
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs: Vec::new(),
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState::default(),
+            // every volume is traced in its own draw, the traced depth sorts
+            // them against each other and the rest of the scene
+            depth_stencil: Some(DepthStencilState {
+                format: CORE_3D_DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState {
+                count: key.samples,
+                ..default()
+            },
+            push_constant_ranges: Vec::new(),
+        }
+    }
+}
+
+/// one texel per super pixel of a view
+fn beam_size(view_size: UVec2, settings: &MainPassSettings) -> UVec2 {
+    (view_size / settings.super_pixel_size.max(1)).max(UVec2::ONE)
+}
+
 fn update_textures(
     mut commands: Commands,
     mut images: ResMut<Assets<Image>>,
@@ -213,7 +362,7 @@ fn update_textures(
                 depth_or_array_layers: 1,
             },
             TextureDimension::D2,
-            &[0, 230, 0, 255],
+            &[0; 8],
             TextureFormat::Rgba16Float,
         );
         beam_texture.texture_descriptor.usage =
@@ -221,14 +370,16 @@ fn update_textures(
         let beam_texture = images.add(beam_texture);
         let beam_texture = BeamTexture {
             image: beam_texture,
-            filled: Arc::new(Mutex::new(false)),
         };
 
         commands.entity(entity).insert(beam_texture);
     }
 
     for (texture, camera, main_pass_settings) in textures.iter() {
-        let size = camera.physical_viewport_size().unwrap() / main_pass_settings.super_pixel_size;
+        let Some(size) = camera.physical_viewport_size() else {
+            continue;
+        };
+        let size = beam_size(size, main_pass_settings);
         let texture = images.get_mut(texture.image.clone()).unwrap();
 
         if size != texture.size() {
@@ -248,6 +399,7 @@ impl FromWorld for FallbackBeamTexture {
     fn from_world(world: &mut World) -> Self {
         let mut images = world.get_resource_mut::<Assets<Image>>().unwrap();
 
+        // a 1x1 beam texture tells the shader to start rays at the camera
         let mut fallback_texture = Image::new_fill(
             Extent3d {
                 width: 1,
@@ -255,7 +407,7 @@ impl FromWorld for FallbackBeamTexture {
                 depth_or_array_layers: 1,
             },
             TextureDimension::D2,
-            &[255, 255, 255, 255],
+            &[0; 8],
             TextureFormat::Rgba16Float,
         );
         fallback_texture.texture_descriptor.usage =
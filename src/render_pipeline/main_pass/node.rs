@@ -1,8 +1,8 @@
 use super::{
-    BeamTexture, FallbackBeamTexture, MainPassPipelineData, MainPassSettings,
+    BeamTexture, FallbackBeamTexture, MainPassPipeline, MainPassPipelineId, MainPassSettings,
     ViewMainPassUniformBuffer,
 };
-use crate::render_pipeline::voxel_world::VoxelData;
+use crate::render_pipeline::{voxel_world::GpuVoxelVolumes, VoxelRenderer};
 use bevy::{
     ecs::query::QueryItem,
     prelude::*,
@@ -11,58 +11,113 @@ use bevy::{
         render_graph::{self, RenderGraphContext, ViewNode},
         render_resource::*,
         renderer::RenderContext,
-        view::ViewTarget,
+        view::{ViewDepthTexture, ViewTarget},
     },
 };
 
+/// beam distance of super pixels that don't hit any volume, close to the
+/// largest `f16`
+const BEAM_MISS_DISTANCE: f64 = 60000.0;
+
 #[derive(Default)]
 pub struct MainPassNode;
 
 impl ViewNode for MainPassNode {
     type ViewQuery = (
         &'static ViewTarget,
+        &'static ViewDepthTexture,
         &'static ViewMainPassUniformBuffer,
         &'static MainPassSettings,
         &'static BeamTexture,
+        &'static VoxelRenderer,
+        &'static MainPassPipelineId,
     );
 
     fn run(
         &self,
         _graph: &mut RenderGraphContext,
         render_context: &mut RenderContext,
-        (target, uniform_buffer, main_pass_settings, beam_texture): QueryItem<Self::ViewQuery>,
+        (target, depth, uniform_buffer, main_pass_settings, beam_texture, renderer, pipeline_id): QueryItem<Self::ViewQuery>,
         world: &World,
     ) -> Result<(), render_graph::NodeRunError> {
+        if *renderer != VoxelRenderer::Fullscreen {
+            return Ok(());
+        }
+
         let pipeline_cache = world.resource::<PipelineCache>();
-        let voxel_data = world.resource::<VoxelData>();
-        let pipeline_data = world.resource::<MainPassPipelineData>();
+        let gpu_voxel_volumes = world.resource::<GpuVoxelVolumes>();
+        let pipeline_data = world.resource::<MainPassPipeline>();
         let gpu_images = world.resource::<RenderAssets<Image>>();
         let fallback_beam_texture = world.resource::<FallbackBeamTexture>();
 
-        let beam_texture_filled = *beam_texture.filled.lock().unwrap();
-        if !main_pass_settings.beam_optimization && !beam_texture_filled {
-            *beam_texture.filled.lock().unwrap() = true;
-            return Ok(());
-        }
-
-        let trace_pipeline = match pipeline_cache.get_render_pipeline(pipeline_data.pipeline_id) {
+        let trace_pipeline = match pipeline_cache.get_render_pipeline(**pipeline_id) {
             Some(pipeline) => pipeline,
             None => return Ok(()),
         };
 
-        let fallback_image = gpu_images.get(&fallback_beam_texture.0).unwrap();
-        let (beam_texture, target) = if !beam_texture_filled {
-            *beam_texture.filled.lock().unwrap() = true;
-            (
-                &fallback_image.texture_view,
-                &gpu_images.get(&beam_texture.image).unwrap().texture_view,
-            )
-        } else {
-            *beam_texture.filled.lock().unwrap() = false;
-            (
-                &gpu_images.get(&beam_texture.image).unwrap().texture_view,
-                target.out_texture(),
-            )
+        let Some(fallback_image) = gpu_images.get(&fallback_beam_texture.0) else {
+            return Ok(());
+        };
+        let beam_image = match main_pass_settings.beam_optimization {
+            true => gpu_images.get(&beam_texture.image),
+            false => None,
+        };
+        let beam_pipeline = pipeline_cache.get_render_pipeline(pipeline_data.beam_pipeline_id);
+
+        // beam pre-pass, trace one conservative ray per super pixel and keep
+        // the closest distance any volume could be hit at
+        let beam_image = match (beam_image, beam_pipeline) {
+            (Some(beam_image), Some(beam_pipeline)) => {
+                let bind_group = render_context.render_device().create_bind_group(
+                    Some("beam pass bind group"),
+                    &pipeline_data.bind_group_layout,
+                    &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: uniform_buffer.binding().unwrap(),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: BindingResource::TextureView(&fallback_image.texture_view),
+                        },
+                    ],
+                );
+
+                let render_pass_descriptor = RenderPassDescriptor {
+                    label: Some("beam pass"),
+                    color_attachments: &[Some(RenderPassColorAttachment {
+                        view: &beam_image.texture_view,
+                        resolve_target: None,
+                        ops: Operations {
+                            load: LoadOp::Clear(wgpu::Color {
+                                r: BEAM_MISS_DISTANCE,
+                                g: BEAM_MISS_DISTANCE,
+                                b: BEAM_MISS_DISTANCE,
+                                a: 1.0,
+                            }),
+                            store: true,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                };
+
+                let mut render_pass = render_context
+                    .command_encoder()
+                    .begin_render_pass(&render_pass_descriptor);
+
+                render_pass.set_pipeline(beam_pipeline);
+                render_pass.set_bind_group(1, &bind_group, &[]);
+                for gpu_voxel_volume in gpu_voxel_volumes.values() {
+                    let Some(voxel_bind_group) = &gpu_voxel_volume.data.bind_group else {
+                        continue;
+                    };
+                    render_pass.set_bind_group(0, voxel_bind_group, &[]);
+                    render_pass.draw(0..3, 0..1);
+                }
+
+                beam_image
+            }
+            _ => fallback_image,
         };
 
         let bind_group = render_context.render_device().create_bind_group(
@@ -75,33 +130,42 @@ impl ViewNode for MainPassNode {
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: BindingResource::TextureView(beam_texture),
+                    resource: BindingResource::TextureView(&beam_image.texture_view),
                 },
             ],
         );
 
         let render_pass_descriptor = RenderPassDescriptor {
             label: Some("main pass"),
-            color_attachments: &[Some(RenderPassColorAttachment {
-                view: target,
-                resolve_target: None,
-                ops: Operations {
+            color_attachments: &[Some(target.get_color_attachment(Operations {
+                load: LoadOp::Load,
+                store: true,
+            }))],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                view: &depth.view,
+                depth_ops: Some(Operations {
                     load: LoadOp::Load,
                     store: true,
-                },
-            })],
-            depth_stencil_attachment: None,
+                }),
+                stencil_ops: None,
+            }),
         };
 
         let mut render_pass = render_context
             .command_encoder()
             .begin_render_pass(&render_pass_descriptor);
 
-        render_pass.set_bind_group(0, &voxel_data.bind_group, &[]);
+        render_pass.set_pipeline(trace_pipeline);
         render_pass.set_bind_group(1, &bind_group, &[]);
 
-        render_pass.set_pipeline(trace_pipeline);
-        render_pass.draw(0..3, 0..1);
+        // one fullscreen triangle per volume
+        for gpu_voxel_volume in gpu_voxel_volumes.values() {
+            let Some(voxel_bind_group) = &gpu_voxel_volume.data.bind_group else {
+                continue;
+            };
+            render_pass.set_bind_group(0, voxel_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
 
         Ok(())
     }
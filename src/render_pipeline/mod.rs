@@ -1,20 +1,45 @@
-pub use self::{voxel_streaming::StreamingSettings, voxel_world::VoxelWorldStatsResource};
+pub use self::{
+    fog::VoxelFog,
+    gpu_culling::GpuCulling,
+    main_pass::MainPassSettings,
+    sky::{SkyCamera, TimeOfDay},
+    upscale::TemporalUpscale,
+    voxel_streaming::StreamingSettings,
+    voxel_world::{CpuVoxelWorld, VoxelPoolSettings, VoxelWorldStatsResource},
+};
+
+pub use self::{
+    cpu_brickmap::{Brick, CpuBrickmap, Material, PagedWriter},
+    load_anvil::{BlockKind, Palette, PaletteEntry},
+    reference_renderer::{ReferenceCamera, ReferenceMode, ReferenceRenderer, ReferenceSettings},
+    sky::{sky_ambient, sky_colour, ExtractedSun},
+};
 
 use self::{
-    voxel_render::VoxelRenderPlugin, voxel_streaming::VoxelStreamingPlugin,
+    fog::FogPlugin, gpu_culling::GpuCullingPlugin, main_pass::MainPassPlugin, sky::SkyPlugin,
+    upscale::UpscalePlugin, voxel_render::VoxelRenderPlugin, voxel_streaming::VoxelStreamingPlugin,
     voxel_world::VoxelWorldPlugin,
 };
 use bevy::{
     prelude::*,
     render::{
         extract_component::{ExtractComponent, ExtractComponentPlugin},
+        primitives::Frustum,
         view::NoFrustumCulling,
     },
 };
 
+mod brick_instances;
+mod brick_pager;
 mod cpu_brickmap;
+mod fog;
 mod gpu_brickmap;
+mod gpu_culling;
 mod load_anvil;
+mod main_pass;
+mod reference_renderer;
+mod sky;
+mod upscale;
 mod voxel_render;
 mod voxel_streaming;
 mod voxel_world;
@@ -24,27 +49,41 @@ pub const BRICK_OFFSET: u32 = 1 << 31;
 pub const COUNTER_BITS: usize = 32;
 
 /// A voxel volume that can be rendered. `streaming_pos` has to be kept updated
-/// to the camera position for streaming to work.
+/// to the camera position (in world space) for streaming to work. `streaming_velocity` and
+/// `streaming_frustum` are optional and prioritise streaming along the camera's
+/// path and view.
 #[derive(Component, ExtractComponent, Clone, Reflect)]
 pub struct VoxelVolume {
     pub streaming_pos: Vec3,
+    pub streaming_velocity: Vec3,
+    #[reflect(ignore)]
+    pub streaming_frustum: Option<Frustum>,
     pub sort: bool,
     pub sort_reverse: bool,
+    /// seconds between sorts of the instances. sorting moves every instance,
+    /// so the whole instance buffer is uploaded again
+    pub sort_interval: f32,
 }
 
 impl Default for VoxelVolume {
     fn default() -> Self {
         Self {
             streaming_pos: Default::default(),
+            streaming_velocity: Default::default(),
+            streaming_frustum: None,
             sort: true,
             sort_reverse: false,
+            sort_interval: 0.5,
         }
     }
 }
 
+/// A voxel volume rendering `world`. Each volume streams its world into its
+/// own gpu pools and is placed by its `transform`.
 #[derive(Bundle, Default)]
 pub struct VoxelVolumeBundle {
     pub voxel_volume: VoxelVolume,
+    pub world: Handle<CpuVoxelWorld>,
     pub visibility: Visibility,
     pub inherited_visibility: InheritedVisibility,
     pub view_visibility: ViewVisibility,
@@ -53,6 +92,45 @@ pub struct VoxelVolumeBundle {
     pub no_frustum_culling: NoFrustumCulling,
 }
 
+/// Which renderer draws the voxel volumes for a camera. Cameras without this
+/// use the instanced brick renderer.
+#[derive(Component, ExtractComponent, Clone, Copy, Default, PartialEq, Eq, Reflect)]
+pub enum VoxelRenderer {
+    /// rasterize the resident bricks as cubes and trace inside them
+    #[default]
+    Instanced,
+    /// trace every pixel through the brickmap, needs `MainPassSettings`. it
+    /// doesn't draw into the prepass, so ssao and motion vectors miss it
+    Fullscreen,
+}
+
+/// Replaces the shading of the voxels with a visualisation of the renderer's
+/// state. Put it on a camera to debug every volume it sees, or on a
+/// `VoxelVolume` to debug just that volume, which takes priority.
+#[derive(Component, ExtractComponent, Clone, Copy, Default, PartialEq, Eq, Reflect)]
+pub enum DebugView {
+    #[default]
+    None,
+    /// depth of the node the brick belongs to, from blue at the root to red
+    /// at the finest level
+    Depth,
+    /// outlines of the bricks on top of the normal shading
+    BrickWireframe,
+    /// the gpu brick index hashed to a colour
+    BrickIndex,
+    /// how many steps the ray took to hit the voxel
+    Steps,
+    Normals,
+    /// just the voxel ambient occlusion
+    AmbientOcclusion,
+    /// green where streaming is happy with the resident level, red where it
+    /// wants a finer level that isn't resident yet and blue where it's about
+    /// to cull back to a coarser one
+    Streaming,
+    /// the material id hashed to a colour, darker where it's transparent
+    Material,
+}
+
 pub struct VoxelPlugin;
 
 impl Plugin for VoxelPlugin {
@@ -61,7 +139,32 @@ impl Plugin for VoxelPlugin {
             VoxelWorldPlugin,
             VoxelRenderPlugin,
             VoxelStreamingPlugin,
+            MainPassPlugin,
+            GpuCullingPlugin,
+            SkyPlugin,
+            FogPlugin,
+            UpscalePlugin,
             ExtractComponentPlugin::<VoxelVolume>::default(),
-        ));
+            ExtractComponentPlugin::<VoxelRenderer>::default(),
+            ExtractComponentPlugin::<DebugView>::default(),
+        ))
+        .init_resource::<ShaderImports>()
+        .register_type::<VoxelRenderer>()
+        .register_type::<DebugView>();
+    }
+}
+
+/// keeps the shader imports loaded for the shaders that use them
+#[derive(Resource)]
+struct ShaderImports(#[allow(dead_code)] Vec<Handle<Shader>>);
+
+impl FromWorld for ShaderImports {
+    fn from_world(world: &mut World) -> Self {
+        let asset_server = world.resource::<AssetServer>();
+        Self(vec![
+            asset_server.load("sky.wgsl"),
+            asset_server.load("fog.wgsl"),
+            asset_server.load("debug.wgsl"),
+        ])
     }
 }
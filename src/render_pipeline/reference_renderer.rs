@@ -0,0 +1,652 @@
+use super::{
+    cpu_brickmap::{Brick, CpuBrickmap, Material},
+    sky::{sky_ambient, sky_colour, ExtractedSun},
+    BRICK_SIZE,
+};
+use bevy::{
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+use std::sync::Mutex;
+
+// shading constants, the same as in instancing.wgsl
+const EMISSION_STRENGTH: f32 = 4.0;
+const BLOCK_LIGHT_COLOUR: Vec3 = Vec3::new(1.0, 0.8, 0.6);
+const MIN_TRANSMITTANCE: f32 = 0.02;
+const DIELECTRIC_REFLECTIVITY: f32 = 0.04;
+
+/// how far secondary rays start off the surface they leave
+const SURFACE_OFFSET: f32 = 0.0001;
+
+/// How the reference renderer lights what it sees.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReferenceMode {
+    /// the same shading as the instanced renderer, sun light, ambient light
+    /// from the sky, the baked block and sky light, transparent voxels tinting
+    /// what's behind them and reflections off smooth voxels. ambient
+    /// occlusion is traced instead of estimated from the neighbouring voxels,
+    /// and rough reflections are scattered per sample instead of per frame
+    Direct,
+    /// diffuse path tracing with `bounces` bounces after the first hit. the
+    /// sun is sampled at every bounce, rays that escape pick up the sky and
+    /// transparent voxels tint the rays going through them. nothing reflects
+    PathTraced { bounces: u32 },
+}
+
+#[derive(Clone, Copy)]
+pub struct ReferenceSettings {
+    pub mode: ReferenceMode,
+    /// rays per pixel. with a single sample rays go through the pixel
+    /// centres like on the gpu, otherwise they are jittered over the pixel
+    pub samples: u32,
+    pub shadows: bool,
+    /// hemisphere rays per hit for the ambient occlusion of `Direct`, 0
+    /// leaves the ambient light unoccluded
+    pub ao_samples: u32,
+    /// how far ambient occlusion rays look, in the volume's local units
+    pub ao_distance: f32,
+    pub sun: ExtractedSun,
+    /// transform of the voxel volume
+    pub transform: Mat4,
+    /// finest node depth to trace, `None` traces the finest bricks
+    pub max_depth: Option<u32>,
+}
+
+impl Default for ReferenceSettings {
+    fn default() -> Self {
+        Self {
+            mode: ReferenceMode::Direct,
+            samples: 1,
+            shadows: true,
+            ao_samples: 16,
+            ao_distance: 0.5,
+            sun: ExtractedSun::from_time_of_day(&default(), true),
+            transform: Mat4::IDENTITY,
+            max_depth: None,
+        }
+    }
+}
+
+/// A perspective camera like a `Camera3dBundle`, looking down -z of its
+/// transform with y up and a vertical field of view.
+#[derive(Clone)]
+pub struct ReferenceCamera {
+    pub transform: Transform,
+    pub projection: PerspectiveProjection,
+    /// image size in pixels, it sets the aspect ratio
+    pub size: UVec2,
+}
+
+impl ReferenceCamera {
+    /// world space ray through `pixel`, counted from the top left corner
+    pub fn ray(&self, pixel: Vec2) -> (Vec3, Vec3) {
+        let ndc = pixel / self.size.as_vec2() * 2.0 - 1.0;
+        let half_height = (self.projection.fov / 2.0).tan();
+        let half_width = half_height * self.size.x as f32 / self.size.y as f32;
+        let dir = Vec3::new(ndc.x * half_width, -ndc.y * half_height, -1.0);
+        (
+            self.transform.translation,
+            (self.transform.rotation * dir).normalize(),
+        )
+    }
+}
+
+/// A hit of a ray with the voxels, in the volume's local space.
+#[derive(Clone, Copy, Debug)]
+pub struct ReferenceHit {
+    /// distance along the ray, in local units
+    pub distance: f32,
+    pub pos: Vec3,
+    /// normal of the face that was hit, zero if the ray started in a voxel
+    pub normal: Vec3,
+    pub colour: Vec4,
+    /// block light and sky occlusion
+    pub light: Vec2,
+    pub material: Material,
+    /// depth of the node the voxel belongs to
+    pub depth: u32,
+}
+
+/// What the transparent voxels a ray went through did to it, `Tint` in
+/// instancing.wgsl.
+struct Tint {
+    /// light the transparent voxels added
+    colour: Vec3,
+    /// how much of the light from behind them gets through
+    transmittance: f32,
+    /// the first of them, it's the one that reflects
+    surface: Option<ReferenceHit>,
+}
+
+impl Default for Tint {
+    fn default() -> Self {
+        Self {
+            colour: Vec3::ZERO,
+            transmittance: 1.0,
+            surface: None,
+        }
+    }
+}
+
+impl ReferenceHit {
+    /// emission of the voxel from 0 to 1
+    fn emission(&self) -> f32 {
+        self.material.emission as f32 / 255.0
+    }
+}
+
+/// Renders a `CpuBrickmap` on the cpu, as ground truth for the gpu
+/// renderers. It's slow, but it doesn't need a gpu and only reads the nodes
+/// and bricks, so it doesn't depend on streaming or the gpu pools either.
+pub struct ReferenceRenderer<'a> {
+    brickmap: &'a CpuBrickmap,
+    settings: ReferenceSettings,
+    inverse_transform: Mat4,
+    /// transforms normals from local to world space
+    normal_transform: Mat3,
+}
+
+impl<'a> ReferenceRenderer<'a> {
+    pub fn new(brickmap: &'a CpuBrickmap, settings: ReferenceSettings) -> Self {
+        let inverse_transform = settings.transform.inverse();
+        Self {
+            brickmap,
+            settings,
+            inverse_transform,
+            normal_transform: Mat3::from_mat4(inverse_transform).transpose(),
+        }
+    }
+
+    /// renders the image `camera` sees as linear hdr `Rgba32Float`. pixels
+    /// that miss every voxel get the sky and an alpha of 0. fog and
+    /// tonemapping aren't applied
+    pub fn render(&self, camera: &ReferenceCamera) -> Image {
+        let width = camera.size.x as usize;
+        let mut pixels = vec![[0.0f32; 4]; width * camera.size.y as usize];
+
+        // threads take rows one at a time, the sky is a lot cheaper than
+        // the ground
+        let rows = Mutex::new(pixels.chunks_mut(width).enumerate());
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| loop {
+                    let Some((y, row)) = rows.lock().unwrap().next() else {
+                        break;
+                    };
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        *pixel = self.render_pixel(camera, UVec2::new(x as u32, y as u32));
+                    }
+                });
+            }
+        });
+
+        // rays read paged bricks straight from disk, drop them again
+        if let Some(pager) = &self.brickmap.pager {
+            pager.evict();
+        }
+
+        Image::new(
+            Extent3d {
+                width: camera.size.x,
+                height: camera.size.y,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            bytemuck::cast_slice(&pixels).to_vec(),
+            TextureFormat::Rgba32Float,
+        )
+    }
+
+    fn render_pixel(&self, camera: &ReferenceCamera, pixel: UVec2) -> [f32; 4] {
+        let samples = self.settings.samples.max(1);
+        let mut rng = Rng::new(pixel.y * camera.size.x + pixel.x);
+
+        let mut colour = Vec3::ZERO;
+        let mut coverage = 0.0;
+        for _ in 0..samples {
+            let offset = match samples {
+                1 => Vec2::splat(0.5),
+                _ => Vec2::new(rng.next_f32(), rng.next_f32()),
+            };
+            let (origin, dir) = camera.ray(pixel.as_vec2() + offset);
+            let (sample, hit) = self.radiance(origin, dir, &mut rng);
+            colour += sample;
+            coverage += hit as u32 as f32;
+        }
+
+        let samples = samples as f32;
+        (colour / samples).extend(coverage / samples).into()
+    }
+
+    /// light arriving at `origin` from along `dir`, and whether a voxel was hit
+    fn radiance(&self, origin: Vec3, dir: Vec3, rng: &mut Rng) -> (Vec3, bool) {
+        let local_origin = self.inverse_transform.transform_point3(origin);
+        let local_dir = self.inverse_transform.transform_vector3(dir).normalize();
+        let mut tint = Tint::default();
+        let hit = self.trace_through(local_origin, local_dir, f32::INFINITY, Some(&mut tint));
+
+        let colour = match hit {
+            Some(hit) => match self.settings.mode {
+                ReferenceMode::Direct => self.shade_direct(&hit, local_dir, rng),
+                ReferenceMode::PathTraced { bounces } => self.shade_path(hit, bounces, rng),
+            },
+            // the ray left the volume, maybe through transparent voxels
+            None => sky_colour(dir, self.settings.sun.direction),
+        };
+
+        // blend in whatever transparent voxels the ray passed through, the
+        // first of them reflects on top of what's behind it
+        let mut colour = tint.colour + tint.transmittance * colour;
+        if let (ReferenceMode::Direct, Some(surface)) = (self.settings.mode, &tint.surface) {
+            if surface.material.roughness < 1.0 {
+                let reflection = self.reflection(surface, local_dir, rng);
+                let reflectivity = Vec3::splat(DIELECTRIC_REFLECTIVITY);
+                colour = mix(
+                    colour,
+                    reflection,
+                    fresnel(reflectivity, local_dir, surface.normal),
+                );
+            }
+        }
+        (colour, hit.is_some() || tint.surface.is_some())
+    }
+
+    /// the gpu shading, see `fragment` in instancing.wgsl. `dir` is the ray
+    /// in local space
+    fn shade_direct(&self, hit: &ReferenceHit, dir: Vec3, rng: &mut Rng) -> Vec3 {
+        let ao = self.ambient_occlusion(hit, rng);
+        let colour = self.shade_hit(hit, ao);
+
+        // anything smoother than fully rough reflects, dielectrics a little
+        // and metals in their own colour
+        if hit.material.roughness < 1.0 {
+            let reflectivity = Vec3::splat(DIELECTRIC_REFLECTIVITY)
+                .lerp(hit.colour.truncate(), hit.material.metalness);
+            let reflection = self.reflection(hit, dir, rng);
+            return mix(colour, reflection, fresnel(reflectivity, dir, hit.normal));
+        }
+        colour
+    }
+
+    /// lit colour of a hit, `shade_hit` in instancing.wgsl. ao darkens the
+    /// indirect light, metals only get the light they reflect
+    fn shade_hit(&self, hit: &ReferenceHit, ao: f32) -> Vec3 {
+        let indirect = ao * sky_ambient(self.settings.sun.direction);
+        let shadow = self.sun_visibility(hit);
+
+        let light = hit.light;
+        let block_light = BLOCK_LIGHT_COLOUR * light.x * light.x;
+        let sky_light = (1.0 - light.y) * (1.0 - light.y);
+        let diffuse = (self.shade(hit.normal, shadow, indirect) * sky_light + block_light)
+            * (1.0 - hit.material.metalness);
+
+        hit.colour.truncate() * (diffuse + hit.emission() * EMISSION_STRENGTH)
+    }
+
+    /// what `hit` reflects of a ray going along `dir` in local space,
+    /// `trace_reflection` in instancing.wgsl
+    fn reflection(&self, hit: &ReferenceHit, dir: Vec3, rng: &mut Rng) -> Vec3 {
+        let normal = hit.normal;
+        let roughness = hit.material.roughness;
+        let mut reflected = reflect(dir, normal);
+        if roughness > 0.0 {
+            let noise = Vec3::new(rng.next_f32(), rng.next_f32(), rng.next_f32()) - 0.5;
+            reflected = (reflected + roughness * noise).normalize();
+            // keep it above the surface
+            if reflected.dot(normal) <= 0.0 {
+                reflected = reflect(reflected, normal);
+            }
+        }
+
+        let mut tint = Tint::default();
+        let pos = hit.pos + normal * SURFACE_OFFSET;
+        let colour = match self.trace_through(pos, reflected, f32::INFINITY, Some(&mut tint)) {
+            Some(hit) => self.shade_hit(&hit, 1.0),
+            None => {
+                let world_reflected = self.settings.transform.transform_vector3(reflected);
+                sky_colour(world_reflected.normalize(), self.settings.sun.direction)
+            }
+        };
+        tint.colour + tint.transmittance * colour
+    }
+
+    fn shade_path(&self, mut hit: ReferenceHit, bounces: u32, rng: &mut Rng) -> Vec3 {
+        let sun_direction = self.settings.sun.direction;
+        let mut colour = Vec3::ZERO;
+        let mut throughput = Vec3::ONE;
+        for bounce in 0..=bounces {
+            let albedo = hit.colour.truncate();
+            let sun_light = self.shade(hit.normal, self.sun_visibility(&hit), Vec3::ZERO);
+            colour += throughput * albedo * (sun_light + hit.emission() * EMISSION_STRENGTH);
+            if bounce == bounces {
+                break;
+            }
+
+            // cosine weighted, so the lambertian brdf and the pdf cancel out
+            // to just the albedo
+            throughput *= albedo;
+            let dir = cosine_hemisphere(self.world_normal(hit.normal), rng);
+            let local_dir = self.inverse_transform.transform_vector3(dir).normalize();
+            let origin = hit.pos + hit.normal * SURFACE_OFFSET;
+            let mut tint = Tint::default();
+            let next = self.trace_through(origin, local_dir, f32::INFINITY, Some(&mut tint));
+            colour += throughput * tint.colour;
+            throughput *= tint.transmittance;
+            match next {
+                Some(next) => hit = next,
+                None => {
+                    colour += throughput * sky_colour(dir, sun_direction);
+                    break;
+                }
+            }
+        }
+        colour
+    }
+
+    /// sun plus ambient light for a normal in local space, `shade` in
+    /// instancing.wgsl. like on the gpu the sun colour is used as is, without
+    /// the 1 / pi of a lambertian brdf
+    fn shade(&self, normal: Vec3, shadow: f32, ambient: Vec3) -> Vec3 {
+        let sun = &self.settings.sun;
+        let diffuse = self.world_normal(normal).dot(sun.direction).max(0.0);
+        sun.colour * diffuse * shadow + ambient
+    }
+
+    /// how much sun reaches the hit, transparent voxels let some of it
+    /// through
+    fn sun_visibility(&self, hit: &ReferenceHit) -> f32 {
+        let sun = &self.settings.sun;
+        if !self.settings.shadows || !sun.shadows {
+            return 1.0;
+        }
+
+        let local_sun_dir = self
+            .inverse_transform
+            .transform_vector3(sun.direction)
+            .normalize();
+        if local_sun_dir.dot(hit.normal) <= 0.0 {
+            return 0.0;
+        }
+
+        let mut tint = Tint::default();
+        let pos = hit.pos + hit.normal * SURFACE_OFFSET;
+        match self.trace_through(pos, local_sun_dir, f32::INFINITY, Some(&mut tint)) {
+            Some(_) => 0.0,
+            None => tint.transmittance,
+        }
+    }
+
+    /// fraction of cosine weighted rays that get further than `ao_distance`
+    fn ambient_occlusion(&self, hit: &ReferenceHit, rng: &mut Rng) -> f32 {
+        let samples = self.settings.ao_samples;
+        if samples == 0 || hit.normal == Vec3::ZERO {
+            return 1.0;
+        }
+
+        let pos = hit.pos + hit.normal * SURFACE_OFFSET;
+        let open = (0..samples)
+            .filter(|_| {
+                let dir = cosine_hemisphere(hit.normal, rng);
+                self.trace_local(pos, dir, self.settings.ao_distance)
+                    .is_none()
+            })
+            .count();
+        open as f32 / samples as f32
+    }
+
+    fn world_normal(&self, normal: Vec3) -> Vec3 {
+        (self.normal_transform * normal).normalize_or_zero()
+    }
+
+    /// first voxel along a world space ray, transparent or not
+    pub fn trace(&self, origin: Vec3, dir: Vec3, max_distance: f32) -> Option<ReferenceHit> {
+        let local_origin = self.inverse_transform.transform_point3(origin);
+        let local_dir = self.inverse_transform.transform_vector3(dir).normalize();
+        self.trace_local(local_origin, local_dir, max_distance)
+    }
+
+    /// first voxel along a ray in the volume's local space, transparent or
+    /// not, where the volume is centred on the origin and the finest bricks
+    /// are 1 unit across. `max_distance` is in local units
+    pub fn trace_local(&self, origin: Vec3, dir: Vec3, max_distance: f32) -> Option<ReferenceHit> {
+        self.trace_through(origin, dir, max_distance, None)
+    }
+
+    /// like `trace_local`, but with a tint transparent voxels are stepped
+    /// through and accumulated into it like `trace_brick` in instancing.wgsl
+    /// does, until too little light gets through them
+    fn trace_through(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_distance: f32,
+        mut tint: Option<&mut Tint>,
+    ) -> Option<ReferenceHit> {
+        let brickmap_depth = self.brickmap.brickmap_depth;
+        let volume_size = (1u32 << brickmap_depth) as f32;
+
+        // the node tree spans 0 to volume_size
+        let origin = origin + volume_size / 2.0;
+        let inv_dir = dir.recip();
+        let (t_min, t_max, mut normal) =
+            ray_box(origin, inv_dir, Vec3::ZERO, Vec3::splat(volume_size))?;
+        let t_max = t_max.min(max_distance);
+
+        // step from node to node through the tree, the same way the
+        // multilevel dda in shader.wgsl does
+        let mut t = t_min.max(0.0);
+        if t_min <= 0.0 {
+            normal = Vec3::ZERO;
+        }
+        while t < t_max {
+            let pos = (origin + dir * t - normal * SURFACE_OFFSET)
+                .clamp(Vec3::ZERO, Vec3::splat(volume_size - SURFACE_OFFSET));
+            let (index, node_pos, depth) = self
+                .brickmap
+                .get_node(pos.as_uvec3(), self.settings.max_depth);
+            let node_pos = node_pos.as_vec3();
+            let node_size = (1u32 << (brickmap_depth - depth)) as f32;
+
+            let brick = self.brickmap.brickmap[index].brick;
+            if brick != 0 {
+                match self.brickmap.brick_blocking(brick) {
+                    Ok(brick) => {
+                        let voxel = Voxels {
+                            brick: &brick,
+                            pos: node_pos,
+                            size: node_size,
+                        };
+                        let hit = |t: f32, normal: Vec3, cell: UVec3| ReferenceHit {
+                            distance: t,
+                            pos: origin + dir * t - volume_size / 2.0,
+                            normal,
+                            colour: Vec4::from_array(brick.get(cell).map(|c| c as f32)) / 255.0,
+                            light: Vec2::from_array(brick.get_light(cell).map(|c| c as f32))
+                                / 255.0,
+                            material: brick.get_material(cell),
+                            depth,
+                        };
+                        let opaque = |t: f32, normal: Vec3, cell: UVec3| {
+                            let Some(tint) = tint.as_deref_mut() else {
+                                return true;
+                            };
+                            let hit = hit(t, normal, cell);
+                            if !hit.material.transparent || tint.transmittance < MIN_TRANSMITTANCE {
+                                return true;
+                            }
+
+                            // transparent voxel, tint the ray and step through it
+                            let ambient = sky_ambient(self.settings.sun.direction);
+                            let alpha = hit.colour.w;
+                            tint.colour += tint.transmittance
+                                * alpha
+                                * hit.colour.truncate()
+                                * self.shade(normal, 1.0, ambient);
+                            tint.transmittance *= 1.0 - alpha;
+                            tint.surface.get_or_insert(hit);
+                            false
+                        };
+                        if let Some((t, normal, cell)) =
+                            voxel.march(origin, dir, inv_dir, t, t_max, normal, opaque)
+                        {
+                            return Some(hit(t, normal, cell));
+                        }
+                    }
+                    Err(e) => warn!("failed to read brick {}: {}", brick, e),
+                }
+            }
+
+            // on to the next node, through the face the ray leaves by
+            let far =
+                node_pos + Vec3::select(dir.cmpge(Vec3::ZERO), Vec3::splat(node_size), Vec3::ZERO);
+            let t_far = Vec3::select(
+                dir.cmpeq(Vec3::ZERO),
+                Vec3::INFINITY,
+                (far - origin) * inv_dir,
+            );
+            let axis = min_axis(t_far);
+            t = t_far[axis].max(t);
+            normal = Vec3::ZERO;
+            normal[axis] = -dir[axis].signum();
+        }
+
+        None
+    }
+}
+
+/// the voxels of a brick placed in the node tree
+struct Voxels<'a> {
+    brick: &'a Brick,
+    pos: Vec3,
+    size: f32,
+}
+
+impl Voxels<'_> {
+    /// first solid voxel along the ray from t that `opaque` accepts, returns
+    /// its distance, the normal of the face that was hit and the voxel's
+    /// position in the brick
+    #[allow(clippy::too_many_arguments)]
+    fn march(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        inv_dir: Vec3,
+        t: f32,
+        t_max: f32,
+        normal: Vec3,
+        mut opaque: impl FnMut(f32, Vec3, UVec3) -> bool,
+    ) -> Option<(f32, Vec3, UVec3)> {
+        let voxel_size = self.size / BRICK_SIZE as f32;
+        let pos = (origin + dir * t - normal * SURFACE_OFFSET - self.pos) / voxel_size;
+        let mut cell = pos
+            .floor()
+            .as_ivec3()
+            .clamp(IVec3::ZERO, IVec3::splat(BRICK_SIZE as i32 - 1));
+
+        // amanatides and woo
+        let step = IVec3::select(dir.cmpge(Vec3::ZERO), IVec3::ONE, IVec3::NEG_ONE);
+        let next = self.pos + (cell + step.max(IVec3::ZERO)).as_vec3() * voxel_size;
+        let mut t_next = Vec3::select(
+            dir.cmpeq(Vec3::ZERO),
+            Vec3::INFINITY,
+            (next - origin) * inv_dir,
+        );
+        let t_delta = (inv_dir * voxel_size).abs();
+
+        let mut t = t;
+        let mut normal = normal;
+        while t <= t_max {
+            if self.brick.get(cell.as_uvec3())[3] != 0 && opaque(t, normal, cell.as_uvec3()) {
+                return Some((t, normal, cell.as_uvec3()));
+            }
+
+            let axis = min_axis(t_next);
+            t = t_next[axis];
+            t_next[axis] += t_delta[axis];
+            cell[axis] += step[axis];
+            normal = Vec3::ZERO;
+            normal[axis] = -step[axis] as f32;
+
+            if cell.cmplt(IVec3::ZERO).any() || cell.cmpge(IVec3::splat(BRICK_SIZE as i32)).any() {
+                return None;
+            }
+        }
+
+        None
+    }
+}
+
+/// distances to where the ray enters and leaves the box, and the normal of
+/// the face it enters through
+fn ray_box(origin: Vec3, inv_dir: Vec3, min: Vec3, max: Vec3) -> Option<(f32, f32, Vec3)> {
+    let t1 = (min - origin) * inv_dir;
+    let t2 = (max - origin) * inv_dir;
+    // axes the ray is parallel to give nans, ignore them
+    let near = Vec3::select(t1.is_nan_mask(), Vec3::NEG_INFINITY, t1.min(t2));
+    let far = Vec3::select(t1.is_nan_mask(), Vec3::INFINITY, t1.max(t2));
+    let t_min = near.max_element();
+    let t_max = far.min_element();
+    if t_max < 0.0 || t_min > t_max {
+        return None;
+    }
+
+    let mut normal = Vec3::ZERO;
+    let axis = min_axis(-near);
+    normal[axis] = -inv_dir[axis].signum();
+    Some((t_min, t_max, normal))
+}
+
+/// schlick's approximation of how much a surface reflects seen from dir
+fn fresnel(reflectivity: Vec3, dir: Vec3, normal: Vec3) -> Vec3 {
+    let cos_theta = (-dir.dot(normal)).clamp(0.0, 1.0);
+    reflectivity + (1.0 - reflectivity) * (1.0 - cos_theta).powi(5)
+}
+
+fn reflect(dir: Vec3, normal: Vec3) -> Vec3 {
+    dir - 2.0 * dir.dot(normal) * normal
+}
+
+/// `mix` in wgsl, per channel
+fn mix(a: Vec3, b: Vec3, t: Vec3) -> Vec3 {
+    a + (b - a) * t
+}
+
+fn min_axis(v: Vec3) -> usize {
+    if v.x <= v.y && v.x <= v.z {
+        0
+    } else if v.y <= v.z {
+        1
+    } else {
+        2
+    }
+}
+
+fn cosine_hemisphere(normal: Vec3, rng: &mut Rng) -> Vec3 {
+    let (tangent, bitangent) = normal.any_orthonormal_pair();
+    let angle = rng.next_f32() * std::f32::consts::TAU;
+    let radius = rng.next_f32().sqrt();
+    let height = (1.0 - radius * radius).max(0.0).sqrt();
+    (tangent * angle.cos() * radius + bitangent * angle.sin() * radius + normal * height)
+        .normalize()
+}
+
+/// pcg, seeded per pixel so renders are reproducible
+struct Rng(u32);
+
+impl Rng {
+    fn new(seed: u32) -> Self {
+        let mut rng = Self(seed);
+        rng.next_f32();
+        rng
+    }
+
+    /// uniform in 0 to 1
+    fn next_f32(&mut self) -> f32 {
+        self.0 = self.0.wrapping_mul(747796405).wrapping_add(2891336453);
+        let x = self.0;
+        let x = ((x >> ((x >> 28) + 4)) ^ x).wrapping_mul(277803737);
+        let x = (x >> 22) ^ x;
+        (x >> 8) as f32 / (1 << 24) as f32
+    }
+}
@@ -0,0 +1,383 @@
+use super::main_pass::VOXEL_MAIN_PASS;
+use bevy::{
+    core_pipeline::{
+        core_3d::{self, CORE_3D, CORE_3D_DEPTH_FORMAT},
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+    },
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        render_graph::{RenderGraphApp, ViewNodeRunner},
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        texture::BevyDefault,
+        view::{ExtractedView, ViewTarget},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+use node::SkyNode;
+use std::f32::consts::TAU;
+
+mod node;
+
+pub const VOXEL_SKY_PASS: &str = "voxel_sky_pass";
+
+/// sun direction used when there is no directional light, matches the
+/// fallback in the shaders
+const FALLBACK_SUN_DIRECTION: Vec3 = Vec3::new(-0.8, 1.0, -0.8);
+
+/// bevy's hard coded camera exposure, lights are scaled by this before they
+/// reach the shaders
+const EXPOSURE: f32 = 1.0 / (4000.0 * 1.2);
+
+/// Draws an analytic sky behind everything for cameras with `SkyCamera`, and
+/// moves the first directional light with `TimeOfDay`.
+pub struct SkyPlugin;
+
+impl Plugin for SkyPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ExtractComponentPlugin::<SkyCamera>::default())
+            .init_resource::<TimeOfDay>()
+            .register_type::<TimeOfDay>()
+            .add_systems(Update, update_sun);
+
+        app.sub_app_mut(RenderApp)
+            .init_resource::<ExtractedSun>()
+            .add_systems(ExtractSchedule, extract_sun)
+            .add_render_graph_node::<ViewNodeRunner<SkyNode>>(CORE_3D, VOXEL_SKY_PASS)
+            .add_render_graph_edges(
+                CORE_3D,
+                &[
+                    VOXEL_MAIN_PASS,
+                    VOXEL_SKY_PASS,
+                    core_3d::graph::node::MAIN_TRANSPARENT_PASS,
+                ],
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .init_resource::<SkyPipeline>()
+            .init_resource::<SpecializedRenderPipelines<SkyPipeline>>()
+            .add_systems(
+                Render,
+                (
+                    prepare_uniforms.in_set(RenderSet::Prepare),
+                    prepare_pipelines.in_set(RenderSet::Prepare),
+                ),
+            );
+    }
+}
+
+/// Renders the sky where nothing else was drawn.
+#[derive(Component, ExtractComponent, Clone, Copy, Default, Reflect)]
+pub struct SkyCamera;
+
+/// Time of day driving the first directional light. The sun rises in +x at 6
+/// and sets in -x at 18, tilted towards +z.
+#[derive(Resource, Clone, Reflect)]
+pub struct TimeOfDay {
+    /// whether the time of day moves the light, it's left alone otherwise so
+    /// it can be set by hand
+    pub enabled: bool,
+    /// hour of the day, 0 to 24
+    pub hour: f32,
+    /// in game hours per second, 0 stops the clock
+    pub speed: f32,
+    /// illuminance of the sun at its highest
+    pub illuminance: f32,
+    /// how far the sun's path is tilted from overhead, in radians
+    pub tilt: f32,
+}
+
+impl Default for TimeOfDay {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            hour: 10.0,
+            speed: 0.0,
+            illuminance: 5000.0,
+            tilt: 0.6,
+        }
+    }
+}
+
+impl TimeOfDay {
+    /// world space direction towards the sun
+    pub fn sun_direction(&self) -> Vec3 {
+        let angle = (self.hour - 6.0) / 24.0 * TAU;
+        Quat::from_rotation_x(self.tilt) * Vec3::new(angle.cos(), angle.sin(), 0.0)
+    }
+
+    /// colour and illuminance of the sun, it reddens and fades out around
+    /// sunset
+    pub fn sun_light(&self) -> (Color, f32) {
+        let height = self.sun_direction().y;
+        let warmth = 1.0 - (height / 0.3).clamp(0.0, 1.0);
+        let colour = Color::rgb(1.0, 0.97 - 0.5 * warmth, 0.9 - 0.7 * warmth);
+        let illuminance = self.illuminance * ((height + 0.05) / 0.15).clamp(0.0, 1.0);
+        (colour, illuminance)
+    }
+}
+
+/// move the sun along its path
+fn update_sun(
+    time: Res<Time>,
+    mut time_of_day: ResMut<TimeOfDay>,
+    mut sun: Query<(&mut DirectionalLight, &mut Transform)>,
+) {
+    if !time_of_day.enabled {
+        return;
+    }
+    if time_of_day.speed != 0.0 {
+        time_of_day.hour = (time_of_day.hour + time_of_day.speed * time.delta_seconds()) % 24.0;
+    }
+
+    let Some((mut light, mut transform)) = sun.iter_mut().next() else {
+        return;
+    };
+    transform.look_to(-time_of_day.sun_direction(), Vec3::Y);
+    (light.color, light.illuminance) = time_of_day.sun_light();
+}
+
+/// The first directional light, as the shaders see it.
+#[derive(Resource, Clone, Copy)]
+pub struct ExtractedSun {
+    /// world space direction towards the sun
+    pub direction: Vec3,
+    /// linear colour premultiplied by illuminance and exposure
+    pub colour: Vec3,
+    pub shadows: bool,
+}
+
+impl Default for ExtractedSun {
+    fn default() -> Self {
+        Self {
+            direction: FALLBACK_SUN_DIRECTION.normalize(),
+            colour: Vec3::ONE,
+            shadows: false,
+        }
+    }
+}
+
+impl ExtractedSun {
+    /// the sun as the shaders see `light`, shining from `direction`
+    pub fn new(light: &DirectionalLight, direction: Vec3) -> Self {
+        Self {
+            direction,
+            colour: Vec4::from(light.color.as_linear_rgba_f32()).truncate()
+                * light.illuminance
+                * EXPOSURE,
+            shadows: light.shadows_enabled,
+        }
+    }
+
+    /// the sun `TimeOfDay` moves the first directional light to
+    pub fn from_time_of_day(time_of_day: &TimeOfDay, shadows: bool) -> Self {
+        let (color, illuminance) = time_of_day.sun_light();
+        let light = DirectionalLight {
+            color,
+            illuminance,
+            shadows_enabled: shadows,
+            ..default()
+        };
+        Self::new(&light, time_of_day.sun_direction())
+    }
+}
+
+// cpu versions of the functions in sky.wgsl, keep them in sync. tests/sky.rs
+// pins a few samples, and the golden reference scene compares them with
+// the gpu
+
+const DAY_ZENITH: Vec3 = Vec3::new(0.18, 0.35, 0.75);
+const DAY_HORIZON: Vec3 = Vec3::new(0.6, 0.75, 0.9);
+const NIGHT_ZENITH: Vec3 = Vec3::new(0.004, 0.006, 0.015);
+const NIGHT_HORIZON: Vec3 = Vec3::new(0.015, 0.02, 0.035);
+const SUNSET_COLOUR: Vec3 = Vec3::new(1.0, 0.4, 0.1);
+const SUN_COLOUR: Vec3 = Vec3::new(1.0, 0.9, 0.75);
+const GROUND_COLOUR: Vec3 = Vec3::new(0.12, 0.1, 0.08);
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn daylight(sun_dir: Vec3) -> f32 {
+    smoothstep(-0.15, 0.2, sun_dir.y)
+}
+
+/// colour of the sky looking along `dir`, both directions are normalized and
+/// in world space
+pub fn sky_colour(dir: Vec3, sun_dir: Vec3) -> Vec3 {
+    let day = daylight(sun_dir);
+    let zenith = NIGHT_ZENITH.lerp(DAY_ZENITH, day);
+    let horizon = NIGHT_HORIZON.lerp(DAY_HORIZON, day);
+
+    let height = dir.y.max(0.0);
+    let mut colour = horizon.lerp(zenith, height.powf(0.5));
+
+    let sun_amount = dir.dot(sun_dir).max(0.0);
+    let sunset = (1.0 - smoothstep(0.0, 0.4, sun_dir.y.abs())) * (1.0 - height).powi(4);
+    colour += SUNSET_COLOUR * sunset * (0.2 + 0.8 * sun_amount.powi(4));
+
+    let above_horizon = smoothstep(-0.02, 0.02, sun_dir.y);
+    colour += SUN_COLOUR
+        * above_horizon
+        * (smoothstep(0.9995, 0.9998, sun_amount) * 40.0 + sun_amount.powi(64) * 0.5);
+
+    if dir.y < 0.0 {
+        colour = colour.lerp(GROUND_COLOUR * (0.05 + day), smoothstep(0.0, 0.1, -dir.y));
+    }
+
+    colour
+}
+
+/// light arriving from the whole sky, for ambient lighting
+pub fn sky_ambient(sun_dir: Vec3) -> Vec3 {
+    let day = daylight(sun_dir);
+    let sunset = 1.0 - smoothstep(0.0, 0.4, sun_dir.y.abs());
+    (NIGHT_HORIZON * 3.0).lerp(0.3 * (DAY_ZENITH + DAY_HORIZON), day) + 0.1 * sunset * SUNSET_COLOUR
+}
+
+fn extract_sun(
+    mut commands: Commands,
+    lights: Extract<Query<(&DirectionalLight, &GlobalTransform)>>,
+) {
+    let sun = match lights.iter().next() {
+        Some((light, transform)) => ExtractedSun::new(light, transform.back()),
+        None => ExtractedSun::default(),
+    };
+    commands.insert_resource(sun);
+}
+
+#[derive(Clone, ShaderType)]
+pub struct SkyUniforms {
+    camera_inverse: Mat4,
+    sun_direction: Vec3,
+}
+
+#[derive(Component, Deref)]
+pub struct ViewSkyUniformBuffer(UniformBuffer<SkyUniforms>);
+
+#[derive(Component, Deref)]
+pub struct SkyPipelineId(CachedRenderPipelineId);
+
+fn prepare_uniforms(
+    mut commands: Commands,
+    query: Query<(Entity, &ExtractedView), With<SkyCamera>>,
+    sun: Res<ExtractedSun>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    for (entity, view) in query.iter() {
+        let camera_inverse = view.transform.compute_matrix() * view.projection.inverse();
+        let mut uniform_buffer = UniformBuffer::from(SkyUniforms {
+            camera_inverse,
+            sun_direction: sun.direction,
+        });
+        uniform_buffer.write_buffer(&render_device, &render_queue);
+
+        commands
+            .entity(entity)
+            .insert(ViewSkyUniformBuffer(uniform_buffer));
+    }
+}
+
+fn prepare_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    sky_pipeline: Res<SkyPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<SkyPipeline>>,
+    msaa: Res<Msaa>,
+    views: Query<(Entity, &ExtractedView), With<SkyCamera>>,
+) {
+    for (entity, view) in views.iter() {
+        let key = SkyPipelineKey {
+            hdr: view.hdr,
+            samples: msaa.samples(),
+        };
+        let pipeline_id = pipelines.specialize(&pipeline_cache, &sky_pipeline, key);
+        commands.entity(entity).insert(SkyPipelineId(pipeline_id));
+    }
+}
+
+#[derive(Resource)]
+struct SkyPipeline {
+    shader: Handle<Shader>,
+    bind_group_layout: BindGroupLayout,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct SkyPipelineKey {
+    hdr: bool,
+    samples: u32,
+}
+
+impl FromWorld for SkyPipeline {
+    fn from_world(render_world: &mut World) -> Self {
+        let shader = render_world.resource::<AssetServer>().load("sky_pass.wgsl");
+        let bind_group_layout = render_world
+            .resource::<RenderDevice>()
+            .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("sky bind group layout"),
+                entries: &[BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: BufferSize::new(SkyUniforms::SHADER_SIZE.into()),
+                    },
+                    count: None,
+                }],
+            });
+
+        SkyPipeline {
+            shader,
+            bind_group_layout,
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for SkyPipeline {
+    type Key = SkyPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let format = if key.hdr {
+            ViewTarget::TEXTURE_FORMAT_HDR
+        } else {
+            TextureFormat::bevy_default()
+        };
+
+        RenderPipelineDescriptor {
+            label: Some("sky pipeline".into()),
+            layout: vec![self.bind_group_layout.clone()],
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs: Vec::new(),
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState::default(),
+            // the fullscreen triangle is at the far plane, so only pixels
+            // nothing was drawn to pass
+            depth_stencil: Some(DepthStencilState {
+                format: CORE_3D_DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState {
+                count: key.samples,
+                ..default()
+            },
+            push_constant_ranges: Vec::new(),
+        }
+    }
+}
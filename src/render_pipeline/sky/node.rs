@@ -0,0 +1,73 @@
+use super::{SkyPipeline, SkyPipelineId, ViewSkyUniformBuffer};
+use bevy::{
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        render_graph::{self, RenderGraphContext, ViewNode},
+        render_resource::*,
+        renderer::RenderContext,
+        view::{ViewDepthTexture, ViewTarget},
+    },
+};
+
+#[derive(Default)]
+pub struct SkyNode;
+
+impl ViewNode for SkyNode {
+    type ViewQuery = (
+        &'static ViewTarget,
+        &'static ViewDepthTexture,
+        &'static ViewSkyUniformBuffer,
+        &'static SkyPipelineId,
+    );
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (target, depth, uniform_buffer, pipeline_id): QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipeline_data = world.resource::<SkyPipeline>();
+
+        let Some(pipeline) = pipeline_cache.get_render_pipeline(**pipeline_id) else {
+            return Ok(());
+        };
+
+        let bind_group = render_context.render_device().create_bind_group(
+            Some("sky bind group"),
+            &pipeline_data.bind_group_layout,
+            &[BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.binding().unwrap(),
+            }],
+        );
+
+        let render_pass_descriptor = RenderPassDescriptor {
+            label: Some("sky pass"),
+            color_attachments: &[Some(target.get_color_attachment(Operations {
+                load: LoadOp::Load,
+                store: true,
+            }))],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                view: &depth.view,
+                depth_ops: Some(Operations {
+                    load: LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        };
+
+        let mut render_pass = render_context
+            .command_encoder()
+            .begin_render_pass(&render_pass_descriptor);
+
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+use super::VoxelRenderer;
+use bevy::{
+    core::FrameCount,
+    core_pipeline::{
+        core_3d::{self, CORE_3D},
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+        prepass::ViewPrepassTextures,
+    },
+    prelude::*,
+    render::{
+        camera::{RenderTarget, TemporalJitter},
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        render_asset::RenderAssets,
+        render_graph::{RenderGraphApp, ViewNodeRunner},
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        view::{ExtractedView, ViewDepthTexture},
+        Render, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+use node::UpscaleNode;
+
+mod node;
+
+pub const VOXEL_UPSCALE_PASS: &str = "voxel_upscale_pass";
+
+/// frames in the jitter sequence, every output pixel gets samples from all
+/// over it before it repeats
+const JITTER_SEQUENCE_LENGTH: u32 = 16;
+
+/// Renders cameras with `TemporalUpscale` at a fraction of their output's
+/// resolution and accumulates the jittered frames into the output.
+pub struct UpscalePlugin;
+
+impl Plugin for UpscalePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ExtractComponentPlugin::<TemporalUpscale>::default())
+            .register_type::<TemporalUpscale>()
+            .add_systems(PostUpdate, (update_render_targets, update_jitter));
+
+        app.sub_app_mut(RenderApp)
+            .init_resource::<UpscaleHistories>()
+            .add_render_graph_node::<ViewNodeRunner<UpscaleNode>>(CORE_3D, VOXEL_UPSCALE_PASS)
+            .add_render_graph_edges(
+                CORE_3D,
+                &[
+                    core_3d::graph::node::FXAA,
+                    VOXEL_UPSCALE_PASS,
+                    core_3d::graph::node::END_MAIN_PASS_POST_PROCESSING,
+                ],
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .init_resource::<UpscalePipeline>()
+            .add_systems(
+                Render,
+                prepare_histories.in_set(RenderSet::PrepareBindGroups),
+            );
+    }
+}
+
+/// Renders the camera into its image target at `render_scale` of `output`'s
+/// size, jitters the projection every frame and reprojects the accumulated
+/// frames into `output`. `output` has to be an `Rgba16Float` image with
+/// `RENDER_ATTACHMENT` usage that the app keeps at the size it wants, and the
+/// camera's depth texture needs `TEXTURE_BINDING` usage and no msaa. With a
+/// `MotionVectorPrepass` the instanced voxels and meshes are moved along their
+/// motion vectors, everything else is reprojected with the depth.
+#[derive(Component, ExtractComponent, Clone, Reflect)]
+pub struct TemporalUpscale {
+    /// fraction of the output's resolution the camera renders at
+    pub render_scale: f32,
+    /// how many frames worth of samples the history holds at most. more
+    /// converges to a smoother image, but trails further behind
+    pub max_history: f32,
+    #[reflect(ignore)]
+    pub output: Handle<Image>,
+}
+
+impl Default for TemporalUpscale {
+    fn default() -> Self {
+        Self {
+            render_scale: 1.0,
+            max_history: 8.0,
+            output: Handle::default(),
+        }
+    }
+}
+
+/// keep the render targets of upscaled cameras at their scale of the output
+fn update_render_targets(
+    mut images: ResMut<Assets<Image>>,
+    cameras: Query<(&Camera, &TemporalUpscale)>,
+) {
+    for (camera, upscale) in cameras.iter() {
+        let RenderTarget::Image(target) = &camera.target else {
+            continue;
+        };
+        let Some(output) = images.get(&upscale.output) else {
+            continue;
+        };
+        let scale = upscale.render_scale.clamp(0.1, 1.0);
+        let size = (output.size().as_vec2() * scale)
+            .round()
+            .as_uvec2()
+            .max(UVec2::ONE);
+
+        let Some(target) = images.get_mut(target) else {
+            continue;
+        };
+        if size != target.size() {
+            info!("Resizing render target to ({}, {})", size.x, size.y);
+            target.resize(Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            });
+        }
+    }
+}
+
+/// move the jitter of upscaled cameras along a halton sequence
+fn update_jitter(
+    mut commands: Commands,
+    frame_count: Res<FrameCount>,
+    mut cameras: Query<(Entity, Option<&mut TemporalJitter>), With<TemporalUpscale>>,
+) {
+    let index = frame_count.0 % JITTER_SEQUENCE_LENGTH + 1;
+    let offset = Vec2::new(halton(index, 2), halton(index, 3)) - 0.5;
+    for (entity, jitter) in cameras.iter_mut() {
+        match jitter {
+            Some(mut jitter) => jitter.offset = offset,
+            None => {
+                commands.entity(entity).insert(TemporalJitter { offset });
+            }
+        }
+    }
+}
+
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut fraction = 1.0;
+    let mut result = 0.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+/// The accumulated frames of a view, read from one texture and written to the
+/// other every frame.
+pub struct UpscaleHistory {
+    textures: [TextureView; 2],
+    size: UVec2,
+    /// unjittered view projection of the last frame
+    view_proj: Option<Mat4>,
+    /// which texture this frame writes
+    current: usize,
+}
+
+impl UpscaleHistory {
+    fn new(render_device: &RenderDevice, size: UVec2) -> Self {
+        let texture = || {
+            render_device
+                .create_texture(&TextureDescriptor {
+                    label: Some("upscale history texture"),
+                    size: Extent3d {
+                        width: size.x,
+                        height: size.y,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: TextureFormat::Rgba16Float,
+                    usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                })
+                .create_view(&TextureViewDescriptor::default())
+        };
+
+        Self {
+            textures: [texture(), texture()],
+            size,
+            view_proj: None,
+            current: 0,
+        }
+    }
+
+    pub fn read(&self) -> &TextureView {
+        &self.textures[1 - self.current]
+    }
+
+    pub fn write(&self) -> &TextureView {
+        &self.textures[self.current]
+    }
+}
+
+/// Every upscaled view's history, kept between frames and keyed by the view
+/// entity.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct UpscaleHistories(HashMap<Entity, UpscaleHistory>);
+
+#[derive(Clone, ShaderType)]
+pub struct UpscaleUniforms {
+    /// from this frame's clip space to the last one's
+    reprojection: Mat4,
+    /// how far the projection was jittered, in pixels of the render target
+    jitter: Vec2,
+    max_history: f32,
+    /// the history is thrown away, when there is no last frame to reproject
+    reset: u32,
+    /// whether the prepass wrote the motion vectors of the voxels
+    motion_vectors: u32,
+}
+
+#[derive(Component, Deref)]
+pub struct ViewUpscaleUniformBuffer(UniformBuffer<UpscaleUniforms>);
+
+#[allow(clippy::type_complexity)]
+fn prepare_histories(
+    mut commands: Commands,
+    views: Query<(
+        Entity,
+        &ExtractedView,
+        &TemporalUpscale,
+        &ViewDepthTexture,
+        Option<&TemporalJitter>,
+        Option<&ViewPrepassTextures>,
+        Option<&VoxelRenderer>,
+    )>,
+    mut histories: ResMut<UpscaleHistories>,
+    images: Res<RenderAssets<Image>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    histories.retain(|entity, _| views.contains(*entity));
+
+    for (entity, view, upscale, depth, jitter, prepass_textures, renderer) in views.iter() {
+        let Some(output) = images.get(&upscale.output) else {
+            continue;
+        };
+        if !depth
+            .texture
+            .usage()
+            .contains(TextureUsages::TEXTURE_BINDING)
+            || depth.texture.sample_count() != 1
+        {
+            histories.remove(&entity);
+            continue;
+        }
+
+        let size = output.size.as_uvec2();
+        let history = histories
+            .entry(entity)
+            .or_insert_with(|| UpscaleHistory::new(&render_device, size));
+        if history.size != size {
+            *history = UpscaleHistory::new(&render_device, size);
+        }
+        history.current = 1 - history.current;
+
+        // the history is kept unjittered, the jitter only moves the samples
+        let view_proj = view.projection * view.transform.compute_matrix().inverse();
+        let previous_view_proj = history.view_proj.replace(view_proj);
+
+        // the fullscreen renderer doesn't draw into the prepass
+        let motion_vectors = prepass_textures
+            .is_some_and(|textures| textures.motion_vectors.is_some())
+            && renderer.copied().unwrap_or_default() == VoxelRenderer::Instanced;
+
+        let mut uniform_buffer = UniformBuffer::from(UpscaleUniforms {
+            reprojection: previous_view_proj.unwrap_or(view_proj) * view_proj.inverse(),
+            jitter: jitter.map_or(Vec2::ZERO, |jitter| jitter.offset),
+            max_history: upscale.max_history.max(1.0),
+            reset: previous_view_proj.is_none() as u32,
+            motion_vectors: motion_vectors as u32,
+        });
+        uniform_buffer.write_buffer(&render_device, &render_queue);
+
+        commands
+            .entity(entity)
+            .insert(ViewUpscaleUniformBuffer(uniform_buffer));
+    }
+}
+
+#[derive(Resource)]
+struct UpscalePipeline {
+    bind_group_layout: BindGroupLayout,
+    sampler: Sampler,
+    pipeline_id: CachedRenderPipelineId,
+    /// bound in place of the motion vectors of views without them
+    fallback_motion_vectors: TextureView,
+}
+
+impl FromWorld for UpscalePipeline {
+    fn from_world(render_world: &mut World) -> Self {
+        let render_device = render_world.resource::<RenderDevice>();
+        let shader = render_world.resource::<AssetServer>().load("upscale.wgsl");
+
+        let texture = |binding, sample_type| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type,
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let bind_group_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("upscale bind group layout"),
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(UpscaleUniforms::SHADER_SIZE.into()),
+                        },
+                        count: None,
+                    },
+                    texture(1, TextureSampleType::Float { filterable: false }),
+                    texture(2, TextureSampleType::Depth),
+                    texture(3, TextureSampleType::Float { filterable: true }),
+                    BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    texture(5, TextureSampleType::Float { filterable: false }),
+                ],
+            });
+
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("upscale history sampler"),
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        let fallback_motion_vectors = render_device
+            .create_texture(&TextureDescriptor {
+                label: Some("fallback motion vector texture"),
+                size: Extent3d::default(),
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Rg16Float,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            })
+            .create_view(&TextureViewDescriptor::default());
+
+        // the resolved frame goes to the output and into the history
+        let target = Some(ColorTargetState {
+            format: TextureFormat::Rgba16Float,
+            blend: None,
+            write_mask: ColorWrites::ALL,
+        });
+        let pipeline_id = render_world
+            .resource::<PipelineCache>()
+            .queue_render_pipeline(RenderPipelineDescriptor {
+                label: Some("upscale pipeline".into()),
+                layout: vec![bind_group_layout.clone()],
+                vertex: fullscreen_shader_vertex_state(),
+                fragment: Some(FragmentState {
+                    shader,
+                    shader_defs: Vec::new(),
+                    entry_point: "fragment".into(),
+                    targets: vec![target.clone(), target],
+                }),
+                primitive: PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: MultisampleState::default(),
+                push_constant_ranges: Vec::new(),
+            });
+
+        UpscalePipeline {
+            bind_group_layout,
+            sampler,
+            pipeline_id,
+            fallback_motion_vectors,
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use super::{TemporalUpscale, UpscaleHistories, UpscalePipeline, ViewUpscaleUniformBuffer};
+use bevy::{
+    core_pipeline::prepass::ViewPrepassTextures,
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_graph::{self, RenderGraphContext, ViewNode},
+        render_resource::*,
+        renderer::RenderContext,
+        view::{ViewDepthTexture, ViewTarget},
+    },
+};
+
+#[derive(Default)]
+pub struct UpscaleNode;
+
+impl ViewNode for UpscaleNode {
+    type ViewQuery = (
+        Entity,
+        &'static ViewTarget,
+        &'static ViewDepthTexture,
+        &'static TemporalUpscale,
+        &'static ViewUpscaleUniformBuffer,
+        Option<&'static ViewPrepassTextures>,
+    );
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (entity, target, depth, upscale, uniform_buffer, prepass_textures): QueryItem<
+            Self::ViewQuery,
+        >,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let upscale_pipeline = world.resource::<UpscalePipeline>();
+        let gpu_images = world.resource::<RenderAssets<Image>>();
+
+        let Some(history) = world.resource::<UpscaleHistories>().get(&entity) else {
+            return Ok(());
+        };
+        let Some(pipeline) = pipeline_cache.get_render_pipeline(upscale_pipeline.pipeline_id)
+        else {
+            return Ok(());
+        };
+        let Some(output) = gpu_images.get(&upscale.output) else {
+            return Ok(());
+        };
+
+        let motion_vectors = prepass_textures
+            .and_then(|textures| textures.motion_vectors.as_ref())
+            .map_or(&upscale_pipeline.fallback_motion_vectors, |texture| {
+                &texture.default_view
+            });
+
+        let bind_group = render_context.render_device().create_bind_group(
+            Some("upscale bind group"),
+            &upscale_pipeline.bind_group_layout,
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(target.main_texture_view()),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(&depth.view),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(history.read()),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::Sampler(&upscale_pipeline.sampler),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: BindingResource::TextureView(motion_vectors),
+                },
+            ],
+        );
+
+        let attachment = |view| {
+            Some(RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Clear(default()),
+                    store: true,
+                },
+            })
+        };
+        let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("upscale pass"),
+            color_attachments: &[
+                attachment(&output.texture_view),
+                attachment(history.write()),
+            ],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_render_pipeline(pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+
+        Ok(())
+    }
+}
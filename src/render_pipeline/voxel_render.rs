@@ -1,17 +1,33 @@
 use super::{
-    gpu_brickmap::GpuVoxelWorld,
-    voxel_world::{SetVoxelDataBindGroup, VoxelData},
-    VoxelVolume, BRICK_OFFSET,
+    brick_instances::BrickInstance,
+    fog::{FogUniforms, VoxelFog},
+    gpu_culling::{CullingBuffers, GpuCulling},
+    voxel_world::{
+        ExtractedVoxelWorld, GpuVoxelVolumes, SetVoxelDataBindGroup, VoxelBindGroupLayout,
+    },
+    DebugView, StreamingSettings, VoxelRenderer, VoxelVolume,
 };
 use bevy::{
-    core_pipeline::core_3d::Opaque3d,
-    ecs::system::{lifetimeless::*, SystemParamItem},
+    core_pipeline::{
+        core_3d::Opaque3d,
+        prepass::{
+            DepthPrepass, MotionVectorPrepass, NormalPrepass, Opaque3dPrepass,
+            MOTION_VECTOR_PREPASS_FORMAT, NORMAL_PREPASS_FORMAT,
+        },
+    },
+    ecs::{
+        query::Has,
+        system::{lifetimeless::*, SystemParamItem},
+    },
     pbr::{
-        MeshPipeline, MeshPipelineKey, RenderMeshInstances, SetMeshBindGroup, SetMeshViewBindGroup,
+        MeshPipeline, MeshPipelineKey, PrepassPipeline, RenderMeshInstances,
+        ScreenSpaceAmbientOcclusionSettings, SetMeshBindGroup, SetMeshViewBindGroup,
+        SetPrepassViewBindGroup, StandardMaterial,
     },
     prelude::*,
     render::RenderApp,
     render::{
+        batching::NoAutomaticBatching,
         mesh::{GpuBufferInfo, MeshVertexBufferLayout},
         render_asset::RenderAssets,
         render_phase::{
@@ -19,12 +35,12 @@ use bevy::{
             RenderPhase, SetItemPipeline, TrackedRenderPass,
         },
         render_resource::*,
-        renderer::RenderDevice,
+        renderer::{RenderDevice, RenderQueue},
         view::ExtractedView,
         Render, RenderSet,
     },
 };
-use bytemuck::{Pod, Zeroable};
+use std::collections::HashMap;
 
 pub struct VoxelRenderPlugin;
 
@@ -34,18 +50,25 @@ impl Plugin for VoxelRenderPlugin {
             .add_systems(PostUpdate, add_mesh_handles);
         app.sub_app_mut(RenderApp)
             .add_render_command::<Opaque3d, DrawVoxel>()
+            .add_render_command::<Opaque3dPrepass, DrawVoxelPrepass>()
             .init_resource::<SpecializedMeshPipelines<VoxelPipeline>>()
+            .init_resource::<SpecializedMeshPipelines<VoxelPrepassPipeline>>()
+            .init_resource::<InstanceBuffers>()
+            .init_resource::<ViewUniformBuffers>()
             .add_systems(
                 Render,
                 (
-                    queue_custom.in_set(RenderSet::QueueMeshes),
+                    (queue_custom, queue_prepass).in_set(RenderSet::QueueMeshes),
                     (prepare_instance_buffers.in_set(RenderSet::PrepareResources),).chain(),
+                    prepare_view_bind_groups.in_set(RenderSet::PrepareBindGroups),
                 ),
             );
     }
 
     fn finish(&self, app: &mut App) {
-        app.sub_app_mut(RenderApp).init_resource::<VoxelPipeline>();
+        app.sub_app_mut(RenderApp)
+            .init_resource::<VoxelPipeline>()
+            .init_resource::<VoxelPrepassPipeline>();
     }
 }
 
@@ -60,83 +83,219 @@ impl FromWorld for CubeHandle {
 }
 
 fn add_mesh_handles(
-    voxel_volumes: Query<Entity, With<VoxelVolume>>,
+    voxel_volumes: Query<Entity, (With<VoxelVolume>, Without<Handle<Mesh>>)>,
     mut commands: Commands,
     cube_handle: Res<CubeHandle>,
 ) {
+    // every volume has its own instance buffer and bind group, so they can't
+    // be batched together
     for entity in voxel_volumes.iter() {
-        commands.entity(entity).insert(cube_handle.clone());
+        commands
+            .entity(entity)
+            .insert((cube_handle.clone(), NoAutomaticBatching));
     }
 }
 
-#[derive(Clone, Copy, Pod, Zeroable)]
-#[repr(C)]
-struct BrickInstance {
-    position: Vec3,
-    scale: f32,
-    brick: u32,
-}
-
-#[derive(Component)]
+/// A volume's persistent instance buffer. Only the slots streaming changed
+/// are uploaded, and it's only recreated when the instances outgrow it.
 pub struct InstanceBuffer {
     buffer: Buffer,
+    capacity: usize,
     length: usize,
+    sorted_at: f32,
 }
 
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct InstanceBuffers(HashMap<Entity, InstanceBuffer>);
+
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
 fn prepare_instance_buffers(
-    mut commands: Commands,
-    query: Query<(Entity, &VoxelVolume)>,
+    query: Query<(Entity, &VoxelVolume, &ExtractedVoxelWorld)>,
     render_device: Res<RenderDevice>,
-    gpu_voxel_world: Res<GpuVoxelWorld>,
+    render_queue: Res<RenderQueue>,
+    mut gpu_voxel_volumes: ResMut<GpuVoxelVolumes>,
+    mut instance_buffers: ResMut<InstanceBuffers>,
+    streaming_settings: Res<StreamingSettings>,
+    time: Res<Time>,
+    views: Query<(Option<&VoxelRenderer>, Has<GpuCulling>), With<RenderPhase<Opaque3d>>>,
 ) {
-    let mut brick_istance_data = Vec::new();
+    instance_buffers.retain(|entity, _| gpu_voxel_volumes.contains_key(entity));
+
+    // cameras culling on the gpu build their own instances
+    if !views.iter().any(|(renderer, gpu_culling)| {
+        !gpu_culling && !renderer.is_some_and(|renderer| *renderer != VoxelRenderer::Instanced)
+    }) {
+        return;
+    }
 
-    // collect nodes
-    gpu_voxel_world.recursive_search(&mut |index, pos, depth| {
-        // skip non leaf nodes and empty leaf nodes
-        if gpu_voxel_world.brickmap[index] <= BRICK_OFFSET {
-            return;
+    let elapsed = time.elapsed_seconds();
+    for (entity, voxel_volume, extracted) in query.iter() {
+        let Some(gpu_voxel_volume) = gpu_voxel_volumes.get_mut(&entity) else {
+            continue;
+        };
+        let gpu_voxel_world = &mut gpu_voxel_volume.gpu_world;
+        gpu_voxel_world.update_fades(elapsed, streaming_settings.transition_time);
+
+        // sort nodes every once in a while, instances are in the volume's
+        // local space
+        let sorted_at = instance_buffers
+            .get(&entity)
+            .map_or(f32::NEG_INFINITY, |instance_buffer| {
+                instance_buffer.sorted_at
+            });
+        let sort = voxel_volume.sort && elapsed - sorted_at >= voxel_volume.sort_interval;
+        if sort {
+            let streaming_pos = extracted
+                .transform
+                .affine()
+                .inverse()
+                .transform_point3(voxel_volume.streaming_pos);
+            gpu_voxel_world
+                .instances
+                .sort_by_cached_key(|brick_instance| {
+                    let pos = brick_instance.position + brick_instance.scale / 2.0;
+                    let mut distance = streaming_pos.distance(pos);
+                    if voxel_volume.sort_reverse {
+                        distance = -distance;
+                    }
+                    distance
+                });
         }
 
-        let position = pos.as_vec3() - (1 << gpu_voxel_world.brickmap_depth - 1) as f32;
-        let scale = (1 << gpu_voxel_world.brickmap_depth - depth) as f32;
-        if depth > gpu_voxel_world.brickmap_depth {
-            error!("depth {} > {}. this is probably really bad", depth, gpu_voxel_world.brickmap_depth);
-            return;
+        let instances = &mut gpu_voxel_world.instances;
+        let length = instances.len();
+        let instance_size = std::mem::size_of::<BrickInstance>();
+        let instance_buffer = match instance_buffers.get_mut(&entity) {
+            Some(instance_buffer) if instance_buffer.capacity >= length => instance_buffer,
+            _ => {
+                // grow with some slack so streaming doesn't recreate it every frame
+                let capacity = length.next_power_of_two().max(64);
+                let buffer = render_device.create_buffer(&BufferDescriptor {
+                    label: Some("instance data buffer"),
+                    size: (capacity * instance_size) as u64,
+                    usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                instances.mark_all_dirty();
+                instance_buffers.insert(
+                    entity,
+                    InstanceBuffer {
+                        buffer,
+                        capacity,
+                        length,
+                        sorted_at,
+                    },
+                );
+                instance_buffers.get_mut(&entity).unwrap()
+            }
+        };
+
+        for range in instances.take_dirty() {
+            let data = bytemuck::cast_slice(&instances.as_slice()[range.clone()]);
+            render_queue.write_buffer(
+                &instance_buffer.buffer,
+                (range.start * instance_size) as u64,
+                data,
+            );
         }
-        let brick = gpu_voxel_world.brickmap[index] - BRICK_OFFSET;
-        brick_istance_data.push(BrickInstance {
-            position,
-            scale,
-            brick,
-        });
-    });
-
-    // sort nodes
-    let (entity, voxel_volume) = query.single();
-    if voxel_volume.sort {
-        radsort::sort_by_cached_key(&mut brick_istance_data, |brick_instance| {
-            let pos = brick_instance.position + brick_instance.scale / 2.0;
-            let mut distance = voxel_volume.streaming_pos.distance(pos);
-            if voxel_volume.sort_reverse {
-                distance = -distance;
+        instance_buffer.length = length;
+        if sort {
+            instance_buffer.sorted_at = elapsed;
+        }
+    }
+}
+
+#[derive(Clone, ShaderType)]
+struct VoxelViewUniforms {
+    fog: FogUniforms,
+    // members after a struct in a uniform have to start on 16 bytes
+    #[align(16)]
+    debug_view: u32,
+}
+
+/// per view data of the instanced renderer, bound at group 3
+#[derive(Component)]
+pub struct VoxelViewBindGroup(BindGroup);
+
+/// A view's persistent uniform buffer. Its bind group is only recreated when
+/// the buffer is.
+pub struct ViewUniformBuffer {
+    buffer: UniformBuffer<VoxelViewUniforms>,
+    bind_group: Option<(BufferId, BindGroup)>,
+}
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ViewUniformBuffers(HashMap<Entity, ViewUniformBuffer>);
+
+#[allow(clippy::type_complexity)]
+fn prepare_view_bind_groups(
+    mut commands: Commands,
+    views: Query<(Entity, Option<&VoxelFog>, Option<&DebugView>), With<ExtractedView>>,
+    voxel_pipeline: Res<VoxelPipeline>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut view_uniform_buffers: ResMut<ViewUniformBuffers>,
+) {
+    view_uniform_buffers.retain(|entity, _| views.contains(*entity));
+
+    for (entity, fog, debug_view) in views.iter() {
+        let uniforms = VoxelViewUniforms {
+            fog: fog.map(FogUniforms::from).unwrap_or_default(),
+            debug_view: debug_view.copied().unwrap_or_default() as u32,
+        };
+        let view_uniform_buffer =
+            view_uniform_buffers
+                .entry(entity)
+                .or_insert_with(|| ViewUniformBuffer {
+                    buffer: UniformBuffer::from(uniforms.clone()),
+                    bind_group: None,
+                });
+        view_uniform_buffer.buffer.set(uniforms);
+        view_uniform_buffer
+            .buffer
+            .write_buffer(&render_device, &render_queue);
+
+        let buffer_id = view_uniform_buffer.buffer.buffer().unwrap().id();
+        let bind_group = match &view_uniform_buffer.bind_group {
+            Some((id, bind_group)) if *id == buffer_id => bind_group.clone(),
+            _ => {
+                let bind_group = render_device.create_bind_group(
+                    Some("voxel view bind group"),
+                    &voxel_pipeline.view_bind_group_layout,
+                    &[BindGroupEntry {
+                        binding: 0,
+                        resource: view_uniform_buffer.buffer.binding().unwrap(),
+                    }],
+                );
+                view_uniform_buffer.bind_group = Some((buffer_id, bind_group.clone()));
+                bind_group
             }
-            distance
-        });
+        };
+        commands
+            .entity(entity)
+            .insert(VoxelViewBindGroup(bind_group));
     }
+}
 
-    let length = brick_istance_data.len();
-    let brick_instance_data = bytemuck::cast_slice(brick_istance_data.as_slice());
-    let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-        label: Some("instance data buffer"),
-        contents: brick_instance_data,
-        usage: BufferUsages::VERTEX,
-    });
-    commands
-        .entity(entity)
-        .insert(InstanceBuffer { buffer, length });
+/// the prepasses a view runs, the mesh view bind group has a layout for
+/// every combination
+fn prepass_key(depth: bool, normal: bool, motion_vector: bool) -> MeshPipelineKey {
+    let mut key = MeshPipelineKey::NONE;
+    if depth {
+        key |= MeshPipelineKey::DEPTH_PREPASS;
+    }
+    if normal {
+        key |= MeshPipelineKey::NORMAL_PREPASS;
+    }
+    if motion_vector {
+        key |= MeshPipelineKey::MOTION_VECTOR_PREPASS;
+    }
+    key
 }
 
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
 fn queue_custom(
     opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
     custom_pipeline: Res<VoxelPipeline>,
@@ -146,14 +305,40 @@ fn queue_custom(
     meshes: Res<RenderAssets<Mesh>>,
     render_mesh_instances: Res<RenderMeshInstances>,
     voxel_volumes: Query<Entity, With<VoxelVolume>>,
-    mut views: Query<(&ExtractedView, &mut RenderPhase<Opaque3d>)>,
+    mut views: Query<(
+        &ExtractedView,
+        &mut RenderPhase<Opaque3d>,
+        Option<&VoxelRenderer>,
+        Has<DepthPrepass>,
+        Has<NormalPrepass>,
+        Has<MotionVectorPrepass>,
+        Has<ScreenSpaceAmbientOcclusionSettings>,
+    )>,
 ) {
     let draw_custom = opaque_3d_draw_functions.read().id::<DrawVoxel>();
 
     let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
 
-    for (view, mut transparent_phase) in &mut views {
-        let view_key = msaa_key | MeshPipelineKey::from_hdr(view.hdr);
+    for (
+        view,
+        mut transparent_phase,
+        renderer,
+        depth_prepass,
+        normal_prepass,
+        motion_vector_prepass,
+        ssao,
+    ) in &mut views
+    {
+        if renderer.is_some_and(|renderer| *renderer != VoxelRenderer::Instanced) {
+            continue;
+        }
+
+        let mut view_key = msaa_key
+            | MeshPipelineKey::from_hdr(view.hdr)
+            | prepass_key(depth_prepass, normal_prepass, motion_vector_prepass);
+        if ssao {
+            view_key |= MeshPipelineKey::SCREEN_SPACE_AMBIENT_OCCLUSION;
+        }
         let rangefinder = view.rangefinder3d();
         for entity in &voxel_volumes {
             let Some(mesh_instance) = render_mesh_instances.get(&entity) else {
@@ -179,26 +364,105 @@ fn queue_custom(
     }
 }
 
-#[derive(Resource)]
+/// draws the traced depth, normals and motion vectors of the bricks into the
+/// prepass, so they work with ssao, taa and anything else reading them
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
+fn queue_prepass(
+    prepass_draw_functions: Res<DrawFunctions<Opaque3dPrepass>>,
+    prepass_pipeline: Res<VoxelPrepassPipeline>,
+    msaa: Res<Msaa>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<VoxelPrepassPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    voxel_volumes: Query<Entity, With<VoxelVolume>>,
+    mut views: Query<(
+        &ExtractedView,
+        &mut RenderPhase<Opaque3dPrepass>,
+        Option<&VoxelRenderer>,
+        Has<DepthPrepass>,
+        Has<NormalPrepass>,
+        Has<MotionVectorPrepass>,
+    )>,
+) {
+    let draw_prepass = prepass_draw_functions.read().id::<DrawVoxelPrepass>();
+
+    let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+
+    for (view, mut prepass_phase, renderer, depth_prepass, normal_prepass, motion_vector_prepass) in
+        &mut views
+    {
+        if renderer.is_some_and(|renderer| *renderer != VoxelRenderer::Instanced) {
+            continue;
+        }
+
+        let view_key = msaa_key | prepass_key(depth_prepass, normal_prepass, motion_vector_prepass);
+        let rangefinder = view.rangefinder3d();
+        for entity in &voxel_volumes {
+            let Some(mesh_instance) = render_mesh_instances.get(&entity) else {
+                continue;
+            };
+            let Some(mesh) = meshes.get(mesh_instance.mesh_asset_id) else {
+                continue;
+            };
+            let key = view_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+            let pipeline_id = pipelines
+                .specialize(&pipeline_cache, &prepass_pipeline, key, &mesh.layout)
+                .unwrap();
+            prepass_phase.add(Opaque3dPrepass {
+                entity,
+                pipeline_id,
+                draw_function: draw_prepass,
+                distance: rangefinder
+                    .distance_translation(&mesh_instance.transforms.transform.translation),
+                batch_range: 0..1,
+                dynamic_offset: None,
+            });
+        }
+    }
+}
+
+#[derive(Resource, Clone)]
 pub struct VoxelPipeline {
     shader: Handle<Shader>,
     mesh_pipeline: MeshPipeline,
     voxel_data_bind_group_layout: BindGroupLayout,
+    view_bind_group_layout: BindGroupLayout,
 }
 
 impl FromWorld for VoxelPipeline {
     fn from_world(world: &mut World) -> Self {
         let asset_server = world.resource::<AssetServer>();
         let mesh_pipeline = world.resource::<MeshPipeline>().clone();
-        let voxel_data = world.resource::<VoxelData>();
+        let voxel_bind_group_layout = world.resource::<VoxelBindGroupLayout>();
 
         let shader = asset_server.load("instancing.wgsl");
-        let voxel_data_bind_group_layout = voxel_data.bind_group_layout.clone();
+        let voxel_data_bind_group_layout = voxel_bind_group_layout.0.clone();
+        let view_bind_group_layout =
+            world
+                .resource::<RenderDevice>()
+                .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                    label: Some("voxel view bind group layout"),
+                    entries: &[BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(
+                                VoxelViewUniforms::SHADER_SIZE.into(),
+                            ),
+                        },
+                        count: None,
+                    }],
+                });
 
         VoxelPipeline {
             shader,
             mesh_pipeline,
             voxel_data_bind_group_layout,
+            view_bind_group_layout,
         }
     }
 }
@@ -217,6 +481,7 @@ impl SpecializedMeshPipeline for VoxelPipeline {
             self.mesh_pipeline.get_view_layout(key.into()).clone(),
             self.mesh_pipeline.mesh_layouts.model_only.clone(),
             self.voxel_data_bind_group_layout.clone(),
+            self.view_bind_group_layout.clone(),
         ];
 
         // meshes typically live in bind group 2. because we are using bindgroup 1
@@ -248,6 +513,11 @@ impl SpecializedMeshPipeline for VoxelPipeline {
                     offset: 16,
                     shader_location: 4,
                 },
+                VertexAttribute {
+                    format: VertexFormat::Float32,
+                    offset: 20,
+                    shader_location: 5,
+                },
             ],
         });
 
@@ -259,27 +529,147 @@ impl SpecializedMeshPipeline for VoxelPipeline {
     }
 }
 
+/// The instanced renderer's pipeline for the prepass. It traces the bricks
+/// like the main pass and writes the depth of the hit, so both passes agree
+/// on it.
+#[derive(Resource)]
+pub struct VoxelPrepassPipeline {
+    voxel_pipeline: VoxelPipeline,
+    view_layout_motion_vectors: BindGroupLayout,
+    view_layout_no_motion_vectors: BindGroupLayout,
+}
+
+impl FromWorld for VoxelPrepassPipeline {
+    fn from_world(world: &mut World) -> Self {
+        // the prepass view bind groups are shared with the pbr meshes
+        let prepass_pipeline = world.resource::<PrepassPipeline<StandardMaterial>>();
+
+        VoxelPrepassPipeline {
+            voxel_pipeline: world.resource::<VoxelPipeline>().clone(),
+            view_layout_motion_vectors: prepass_pipeline.view_layout_motion_vectors.clone(),
+            view_layout_no_motion_vectors: prepass_pipeline.view_layout_no_motion_vectors.clone(),
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for VoxelPrepassPipeline {
+    type Key = MeshPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.voxel_pipeline.specialize(key, layout)?;
+        descriptor.label = Some("voxel prepass pipeline".into());
+
+        let motion_vectors = key.contains(MeshPipelineKey::MOTION_VECTOR_PREPASS);
+        descriptor.layout = vec![
+            if motion_vectors {
+                self.view_layout_motion_vectors.clone()
+            } else {
+                self.view_layout_no_motion_vectors.clone()
+            },
+            self.voxel_pipeline
+                .mesh_pipeline
+                .mesh_layouts
+                .model_only
+                .clone(),
+            self.voxel_pipeline.voxel_data_bind_group_layout.clone(),
+        ];
+
+        // the mesh pipeline already added the defs of the prepasses the view
+        // runs
+        descriptor
+            .vertex
+            .shader_defs
+            .push("PREPASS_PIPELINE".into());
+        let fragment = descriptor.fragment.as_mut().unwrap();
+        fragment.shader_defs.push("PREPASS_PIPELINE".into());
+
+        // the same slots as the pbr prepass, deferred isn't supported
+        let target = |enabled: bool, format| {
+            enabled.then_some(ColorTargetState {
+                format,
+                blend: None,
+                write_mask: ColorWrites::ALL,
+            })
+        };
+        fragment.targets = vec![
+            target(
+                key.contains(MeshPipelineKey::NORMAL_PREPASS),
+                NORMAL_PREPASS_FORMAT,
+            ),
+            target(motion_vectors, MOTION_VECTOR_PREPASS_FORMAT),
+            None,
+            None,
+        ];
+        if fragment.targets.iter().all(Option::is_none) {
+            fragment.targets.clear();
+        }
+
+        Ok(descriptor)
+    }
+}
+
 type DrawVoxel = (
     SetItemPipeline,
     SetMeshViewBindGroup<0>,
     SetMeshBindGroup<1>,
     SetVoxelDataBindGroup<2>,
+    SetVoxelViewBindGroup<3>,
+    DrawVoxelPhase,
+);
+
+type DrawVoxelPrepass = (
+    SetItemPipeline,
+    SetPrepassViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    SetVoxelDataBindGroup<2>,
     DrawVoxelPhase,
 );
 
+pub struct SetVoxelViewBindGroup<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetVoxelViewBindGroup<I> {
+    type Param = ();
+    type ViewWorldQuery = Read<VoxelViewBindGroup>;
+    type ItemWorldQuery = ();
+
+    fn render<'w>(
+        _item: &P,
+        bind_group: &'w VoxelViewBindGroup,
+        _entity: (),
+        _param: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        pass.set_bind_group(I, &bind_group.0, &[]);
+        RenderCommandResult::Success
+    }
+}
+
 pub struct DrawVoxelPhase;
 
 impl<P: PhaseItem> RenderCommand<P> for DrawVoxelPhase {
-    type Param = (SRes<RenderAssets<Mesh>>, SRes<RenderMeshInstances>);
-    type ViewWorldQuery = ();
-    type ItemWorldQuery = Read<InstanceBuffer>;
+    type Param = (
+        SRes<RenderAssets<Mesh>>,
+        SRes<RenderMeshInstances>,
+        SRes<CullingBuffers>,
+        SRes<InstanceBuffers>,
+    );
+    type ViewWorldQuery = Has<GpuCulling>;
+    type ItemWorldQuery = ();
 
     #[inline]
     fn render<'w>(
         item: &P,
-        _view: (),
-        instance_buffer: &'w InstanceBuffer,
-        (meshes, render_mesh_instances): SystemParamItem<'w, '_, Self::Param>,
+        gpu_culling: bool,
+        _entity: (),
+        (meshes, render_mesh_instances, culling_buffers, instance_buffers): SystemParamItem<
+            'w,
+            '_,
+            Self::Param,
+        >,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
         let Some(mesh_instance) = render_mesh_instances.get(&item.entity()) else {
@@ -291,6 +681,32 @@ impl<P: PhaseItem> RenderCommand<P> for DrawVoxelPhase {
         };
 
         pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
+
+        // the cull pass wrote the instances and their count
+        if gpu_culling {
+            let (
+                Some(culling_buffers),
+                GpuBufferInfo::Indexed {
+                    buffer,
+                    index_format,
+                    ..
+                },
+            ) = (
+                culling_buffers.into_inner().get(&item.entity()),
+                &gpu_mesh.buffer_info,
+            )
+            else {
+                return RenderCommandResult::Failure;
+            };
+            pass.set_vertex_buffer(1, culling_buffers.instances.slice(..));
+            pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+            pass.draw_indexed_indirect(&culling_buffers.draw_args, 0);
+            return RenderCommandResult::Success;
+        }
+
+        let Some(instance_buffer) = instance_buffers.into_inner().get(&item.entity()) else {
+            return RenderCommandResult::Failure;
+        };
         pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
 
         match &gpu_mesh.buffer_info {
@@ -1,12 +1,14 @@
 use super::{
     gpu_brickmap::GpuVoxelWorld,
-    voxel_world::{CpuVoxelWorld, VoxelData},
+    voxel_world::{CpuVoxelWorld, ExtractedVoxelWorld, GpuVoxelVolume, GpuVoxelVolumes, VoxelData},
     VoxelVolume, VoxelWorldStatsResource, BRICK_OFFSET, BRICK_SIZE,
 };
 use bevy::{
+    math::Vec3A,
     prelude::*,
     render::{
         extract_resource::{ExtractResource, ExtractResourcePlugin},
+        primitives::Aabb,
         renderer::RenderQueue,
         Render, RenderApp, RenderSet,
     },
@@ -15,24 +17,76 @@ use bevy::{
 #[derive(Resource, ExtractResource, Clone, Reflect)]
 pub struct StreamingSettings {
     pub pause_streaming: bool,
-    pub streaming_ratio: f32,
+    /// nodes are divided when their size to distance ratio is above this
+    pub divide_ratio: f32,
+    /// and culled when it drops below this. keep it below `divide_ratio` so
+    /// nodes near the boundary don't flip between the two
+    pub cull_ratio: f32,
+    /// multiplies the ratio of nodes at each depth, starting at depth 1
+    pub depth_bias: Vec<f32>,
+    /// nodes deeper than this are never resident
+    pub max_depth: u32,
+    /// seconds a node has to stay divided before it can be culled again
+    pub min_divided_time: f32,
+    /// how many seconds ahead along the streaming velocity to stream in
+    pub prediction_time: f32,
+    /// multiplies the ratio of nodes outside the streaming frustum
+    pub outside_frustum_bias: f32,
+    /// seconds the instanced renderer cross-fades nodes to their new level
+    /// for, 0 switches instantly
+    pub transition_time: f32,
 }
 
 impl Default for StreamingSettings {
     fn default() -> Self {
         Self {
             pause_streaming: false,
-            streaming_ratio: 0.4,
+            divide_ratio: 0.4,
+            cull_ratio: 0.3,
+            depth_bias: vec![1.0; 8],
+            max_depth: 16,
+            min_divided_time: 0.5,
+            prediction_time: 1.0,
+            outside_frustum_bias: 0.5,
+            transition_time: 0.3,
         }
     }
 }
 
+/// What streaming last wanted from each node, shown by `DebugView::Streaming`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum StreamingState {
+    /// the node is at the level streaming wants
+    Resident = 0,
+    /// the node should be divided, but its children aren't resident yet
+    Finer = 1,
+    /// the node's parent should be culled back to a coarser level
+    Coarser = 2,
+}
+
+impl StreamingSettings {
+    fn bias(&self, depth: u32) -> f32 {
+        self.depth_bias
+            .get(depth as usize - 1)
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// distance in the volume's local space below which nodes of `node_size`
+    /// bricks at `depth` get divided, ignoring the frustum bias
+    pub fn divide_distance(&self, node_size: f32, depth: u32) -> f32 {
+        100.0 * node_size * self.bias(depth) / (self.divide_ratio * BRICK_SIZE as f32)
+    }
+}
+
 pub struct VoxelStreamingPlugin;
 
 impl Plugin for VoxelStreamingPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(ExtractResourcePlugin::<StreamingSettings>::default())
-            .insert_resource(StreamingSettings::default());
+            .insert_resource(StreamingSettings::default())
+            .register_type::<StreamingSettings>();
 
         app.sub_app_mut(RenderApp)
             .add_systems(Render, voxel_streaming_system.in_set(RenderSet::Queue));
@@ -40,18 +94,77 @@ impl Plugin for VoxelStreamingPlugin {
 }
 
 fn voxel_streaming_system(
-    voxel_data: Res<VoxelData>,
     render_queue: Res<RenderQueue>,
-    cpu_voxel_world: Res<CpuVoxelWorld>,
-    mut gpu_voxel_world: ResMut<GpuVoxelWorld>,
+    mut gpu_voxel_volumes: ResMut<GpuVoxelVolumes>,
     streaming_settings: Res<StreamingSettings>,
     voxel_stats: Res<VoxelWorldStatsResource>,
-    voxel_volume: Query<&VoxelVolume>,
+    voxel_volumes: Query<(Entity, &VoxelVolume, &ExtractedVoxelWorld)>,
+    time: Res<Time>,
 ) {
     if streaming_settings.pause_streaming {
         return;
     }
 
+    let mut voxel_stats = voxel_stats.lock().unwrap();
+    voxel_stats.nodes = 0;
+    voxel_stats.bricks = 0;
+    voxel_stats.max_nodes = 0;
+    voxel_stats.max_bricks = 0;
+    voxel_stats.paged_bricks = 0;
+    voxel_stats.max_paged_bricks = 0;
+    voxel_stats.streaming_pending = 0;
+
+    for (entity, voxel_volume, extracted) in voxel_volumes.iter() {
+        let Some(gpu_voxel_volume) = gpu_voxel_volumes.get_mut(&entity) else {
+            continue;
+        };
+        let GpuVoxelVolume {
+            cpu_world: cpu_voxel_world,
+            gpu_world: gpu_voxel_world,
+            data: voxel_data,
+            ..
+        } = gpu_voxel_volume;
+
+        voxel_stats.streaming_pending += stream_volume(
+            voxel_volume,
+            &extracted.transform,
+            voxel_data,
+            cpu_voxel_world,
+            gpu_voxel_world,
+            &streaming_settings,
+            &render_queue,
+            time.elapsed_seconds(),
+        );
+
+        let dim = gpu_voxel_world.color_texture_size / BRICK_SIZE;
+        voxel_stats.nodes +=
+            gpu_voxel_world.brickmap.len() - gpu_voxel_world.brickmap_holes.len() * 8;
+        voxel_stats.bricks += (dim.x * dim.y * dim.z) as usize - gpu_voxel_world.brick_holes.len();
+        voxel_stats.max_nodes += gpu_voxel_world.brickmap.len();
+        voxel_stats.max_bricks += (dim.x * dim.y * dim.z) as usize;
+        if let Some(pager) = &cpu_voxel_world.pager {
+            let (paged_bricks, max_paged_bricks) = pager.cached();
+            voxel_stats.paged_bricks += paged_bricks;
+            voxel_stats.max_paged_bricks += max_paged_bricks;
+        }
+    }
+}
+
+/// returns how many nodes streaming wanted to divide or cull
+#[allow(clippy::too_many_arguments)]
+fn stream_volume(
+    voxel_volume: &VoxelVolume,
+    transform: &GlobalTransform,
+    voxel_data: &VoxelData,
+    cpu_voxel_world: &CpuVoxelWorld,
+    gpu_voxel_world: &mut GpuVoxelWorld,
+    streaming_settings: &StreamingSettings,
+    render_queue: &RenderQueue,
+    elapsed_seconds: f32,
+) -> usize {
+    // free the old bricks of nodes that have finished fading
+    gpu_voxel_world.end_transitions(elapsed_seconds - streaming_settings.transition_time);
+
     // collect the nodes that need to be updated
     let mut nodes_to_divide = Vec::new();
     let mut nodes_to_cull = Vec::new();
@@ -87,62 +200,149 @@ fn voxel_streaming_system(
     // voxel_data.counters.unmap();
 
     // --- distance guided streaming ---
-    let mut streaming_pos = voxel_volume.single().streaming_pos;
-    streaming_pos += (1 << cpu_voxel_world.brickmap_depth - 1) as f32;
+    // distances are measured in the volume's local space to the path the
+    // camera is predicted to take
+    let local_to_world = transform.affine();
+    let world_to_local = local_to_world.inverse();
+    let half_size = (1 << (cpu_voxel_world.brickmap_depth - 1)) as f32;
+    let streaming_pos = world_to_local.transform_point3(voxel_volume.streaming_pos) + half_size;
+    let streaming_velocity = world_to_local.transform_vector3(voxel_volume.streaming_velocity);
+    let predicted_pos = streaming_pos + streaming_velocity * streaming_settings.prediction_time;
 
+    let mut streaming_state = std::mem::take(&mut gpu_voxel_world.streaming_state);
+    let mut nodes_to_coarsen = Vec::new();
     gpu_voxel_world.recursive_search(&mut |index, pos, depth| {
+        streaming_state[index] = StreamingState::Resident as u32;
         let node_size = (1 << cpu_voxel_world.brickmap_depth - depth) as f32;
+        let node_center = pos.as_vec3() + node_size / 2.0;
         let distance =
-            (pos.as_vec3() + node_size / 2.0 - streaming_pos).length() * BRICK_SIZE as f32;
-        let ratio = 100.0 * node_size / distance;
+            distance_to_segment(node_center, streaming_pos, predicted_pos) * BRICK_SIZE as f32;
+        let mut ratio = 100.0 * node_size / distance * streaming_settings.bias(depth);
+
+        // nodes behind the camera are less important
+        if let Some(frustum) = &voxel_volume.streaming_frustum {
+            let aabb = Aabb {
+                center: (node_center - half_size).into(),
+                half_extents: Vec3A::splat(node_size / 2.0),
+            };
+            if !frustum.intersects_obb(&aabb, &local_to_world, true, false) {
+                ratio *= streaming_settings.outside_frustum_bias;
+            }
+        }
 
         let children_index = gpu_voxel_world.brickmap[index];
-        if ratio > streaming_settings.streaming_ratio {
-            if children_index >= BRICK_OFFSET {
+        if children_index >= BRICK_OFFSET {
+            if ratio > streaming_settings.divide_ratio && depth < streaming_settings.max_depth {
                 let cpu_node_index = gpu_voxel_world.gpu_to_cpu[index] as usize;
                 let cpu_node = cpu_voxel_world.brickmap[cpu_node_index];
                 if cpu_node.children != 0 {
-                    nodes_to_divide.push((index, pos, depth));
+                    nodes_to_divide.push((index, pos, depth, ratio));
+                    streaming_state[index] = StreamingState::Finer as u32;
                 }
             }
         } else {
-            if children_index < BRICK_OFFSET {
+            let divided_for = elapsed_seconds - gpu_voxel_world.divided_at[index];
+            let coarser =
+                depth >= streaming_settings.max_depth || ratio < streaming_settings.cull_ratio;
+            if coarser {
+                nodes_to_coarsen.push(children_index as usize);
+            }
+            if coarser
+                && (depth >= streaming_settings.max_depth
+                    || divided_for >= streaming_settings.min_divided_time)
+            {
                 nodes_to_cull.push((index, pos, depth));
             }
         }
     });
+
+    // children are visited after their parent, so mark the ones that are
+    // only resident until their parent is culled afterwards
+    for children_index in nodes_to_coarsen {
+        for i in 0..8 {
+            if gpu_voxel_world.brickmap[8 * children_index + i] >= BRICK_OFFSET {
+                streaming_state[8 * children_index + i] = StreamingState::Coarser as u32;
+            }
+        }
+    }
+    gpu_voxel_world.streaming_state = streaming_state;
     drop(my_span);
 
+    // divide the most important nodes first in case we run out of space
+    let pending = nodes_to_divide.len() + nodes_to_cull.len();
+
     let my_span = info_span!("streaming division").entered();
-    for (index, _, _) in nodes_to_divide {
-        if let Err(e) =
-            gpu_voxel_world.divide_node(index, &voxel_data, &cpu_voxel_world, &render_queue)
-        {
-            warn!("failed to divide node: {}", e);
-            break;
+    nodes_to_divide.sort_by(|(_, _, _, a), (_, _, _, b)| b.total_cmp(a));
+    for (index, pos, depth, _) in nodes_to_divide {
+        match gpu_voxel_world.divide_node(
+            index,
+            pos,
+            depth,
+            voxel_data,
+            cpu_voxel_world,
+            render_queue,
+            elapsed_seconds,
+        ) {
+            Ok(true) => {
+                gpu_voxel_world.divided_at[index] = elapsed_seconds;
+                let children_index = 8 * gpu_voxel_world.brickmap[index] as usize;
+                gpu_voxel_world.streaming_state[children_index..children_index + 8]
+                    .fill(StreamingState::Resident as u32);
+            }
+            // still paging in, try again next frame
+            Ok(false) => {}
+            Err(e) => {
+                warn!("failed to divide node: {}", e);
+                break;
+            }
         }
     }
     drop(my_span);
 
+    // cull the deepest nodes first so parents only ever cull leaf children
     let my_span = info_span!("streaming culling").entered();
-    for (index, _, _) in nodes_to_cull {
-        if let Err(e) =
-            gpu_voxel_world.cull_node(index, &voxel_data, &cpu_voxel_world, &render_queue)
-        {
+    nodes_to_cull.sort_by_key(|(_, _, depth)| std::cmp::Reverse(*depth));
+    for (index, pos, depth) in nodes_to_cull {
+        let children_index = 8 * gpu_voxel_world.brickmap[index] as usize;
+        if (0..8).any(|i| gpu_voxel_world.brickmap[children_index + i] < BRICK_OFFSET) {
+            continue;
+        }
+        if let Err(e) = gpu_voxel_world.cull_node(
+            index,
+            pos,
+            depth,
+            voxel_data,
+            cpu_voxel_world,
+            render_queue,
+            elapsed_seconds,
+        ) {
             warn!("failed to cull node: {}", e);
             break;
         }
     }
     drop(my_span);
 
-    let mut voxel_stats = voxel_stats.lock().unwrap();
-    let dim = gpu_voxel_world.color_texture_size / BRICK_SIZE;
-    voxel_stats.nodes = gpu_voxel_world.brickmap.len() - gpu_voxel_world.brickmap_holes.len() * 8;
-    voxel_stats.bricks = (dim.x * dim.y * dim.z) as usize - gpu_voxel_world.brick_holes.len();
+    if let Some(pager) = &cpu_voxel_world.pager {
+        pager.evict();
+    }
 
     let (_, data, _) = unsafe { gpu_voxel_world.brickmap.align_to::<u8>() };
     render_queue.write_buffer(&voxel_data.brickmap, 0, data);
+    let (_, data, _) = unsafe { gpu_voxel_world.streaming_state.align_to::<u8>() };
+    render_queue.write_buffer(&voxel_data.streaming_state, 0, data);
 
     // let counters = vec![0; gpu_voxel_world.brickmap.len() * COUNTER_BITS / 8];
     // render_queue.write_buffer(&voxel_data.counters, 0, &counters);
+
+    pending
+}
+
+fn distance_to_segment(p: Vec3, a: Vec3, b: Vec3) -> f32 {
+    let ab = b - a;
+    let t = if ab == Vec3::ZERO {
+        0.0
+    } else {
+        ((p - a).dot(ab) / ab.length_squared()).clamp(0.0, 1.0)
+    };
+    p.distance(a + ab * t)
 }
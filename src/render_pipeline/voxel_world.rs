@@ -1,127 +1,154 @@
 use super::{
-    cpu_brickmap::{Brick, CpuBrickmap},
+    cpu_brickmap::{Brick, CpuBrickmap, Material},
     gpu_brickmap::GpuVoxelWorld,
-    load_anvil::load_anvil,
-    BRICK_OFFSET, BRICK_SIZE, COUNTER_BITS,
+    load_anvil::{convert_anvil, load_anvil, region_stamps},
+    DebugView, VoxelVolume, BRICK_OFFSET, BRICK_SIZE, COUNTER_BITS,
 };
+use anyhow::Result;
 use bevy::{
     ecs::system::{lifetimeless::SRes, SystemParamItem},
     prelude::*,
     render::{
-        extract_resource::ExtractResource,
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
         render_phase::{PhaseItem, RenderCommand, RenderCommandResult, TrackedRenderPass},
         render_resource::*,
         renderer::{RenderDevice, RenderQueue},
-        Render, RenderApp, RenderSet,
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
     },
+    utils::HashMap,
 };
 use std::{
-    collections::VecDeque,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
-#[derive(Resource, Deref, DerefMut)]
-pub struct CpuVoxelWorld(CpuBrickmap);
+/// The cpu side of a voxel world. Each voxel volume references one of these
+/// through a `Handle<CpuVoxelWorld>`, several volumes can share one world.
+#[derive(Asset, TypePath, Clone, Deref)]
+pub struct CpuVoxelWorld(Arc<CpuBrickmap>);
+
+impl CpuVoxelWorld {
+    pub fn new(brickmap: CpuBrickmap) -> Self {
+        Self(Arc::new(brickmap))
+    }
+
+    /// load an anvil world and build its mipmaps (slooowwww)
+    pub fn load_anvil(region_path: impl Into<PathBuf>, world_depth: u32) -> Self {
+        let mut cpu_brickmap = load_anvil(region_path.into(), world_depth);
+        cpu_brickmap.spread_light();
+        cpu_brickmap.recreate_mipmaps();
+        Self::new(cpu_brickmap)
+    }
+
+    /// like `load_anvil`, but converts the world to a paged brickmap at
+    /// `paged_path` a region at a time and opens that. it's converted again
+    /// when it was written by an older version or the region files changed
+    pub fn load_anvil_paged(
+        region_path: impl Into<PathBuf>,
+        world_depth: u32,
+        paged_path: impl AsRef<Path>,
+        resident_depth: u32,
+        cache_size: usize,
+    ) -> Result<Self> {
+        let region_path = region_path.into();
+        let paged_path = paged_path.as_ref();
+        let sources = region_stamps(&region_path, world_depth);
+        if !CpuBrickmap::is_current_paged(paged_path, &sources) {
+            info!("converting world to {}", paged_path.display());
+            convert_anvil(
+                &region_path,
+                world_depth,
+                paged_path,
+                resident_depth,
+                &sources,
+            )?;
+        }
+        Self::load_paged(paged_path, cache_size)
+    }
+
+    /// the material of the voxel at `pos`, in voxels from the brickmap's
+    /// corner. `None` if the voxel is empty or outside the finest bricks.
+    /// paged bricks are read on the calling thread
+    pub fn material_at(&self, pos: UVec3) -> Option<Material> {
+        if pos
+            .cmpge(UVec3::splat(BRICK_SIZE << self.brickmap_depth))
+            .any()
+        {
+            return None;
+        }
+        let (index, _, depth) = self.get_node(pos / BRICK_SIZE, None);
+        let brick_index = self.brickmap[index].brick;
+        if depth != self.brickmap_depth || brick_index == 0 {
+            return None;
+        }
+        let brick = match self.brick_blocking(brick_index) {
+            Ok(brick) => brick,
+            Err(e) => {
+                warn!("failed to read brick {}: {}", brick_index, e);
+                return None;
+            }
+        };
+        let pos = pos % BRICK_SIZE;
+        (brick.get(pos)[3] > 0).then(|| brick.get_material(pos))
+    }
+
+    /// open a world written by `PagedWriter`. only the node tree and coarse
+    /// bricks are kept in memory, at most `cache_size` fine bricks are paged
+    /// in at a time
+    pub fn load_paged(path: impl AsRef<Path>, cache_size: usize) -> Result<Self> {
+        let cpu_brickmap = CpuBrickmap::load_paged(path.as_ref(), cache_size)?;
+        Ok(Self::new(cpu_brickmap))
+    }
+}
 
 pub struct VoxelWorldPlugin;
 
 impl Plugin for VoxelWorldPlugin {
     fn build(&self, app: &mut App) {
         let stats = VoxelWorldStatsResource::default();
-        app.insert_resource(stats.clone());
-        app.sub_app_mut(RenderApp).insert_resource(stats.clone());
+        app.insert_resource(stats.clone())
+            .init_asset::<CpuVoxelWorld>()
+            .init_resource::<VoxelPoolSettings>()
+            .register_type::<VoxelPoolSettings>()
+            .add_plugins(ExtractResourcePlugin::<VoxelPoolSettings>::default());
+        app.sub_app_mut(RenderApp)
+            .insert_resource(stats.clone())
+            .init_resource::<GpuVoxelVolumes>()
+            .add_systems(ExtractSchedule, extract_voxel_worlds)
+            .add_systems(
+                Render,
+                (
+                    prepare_voxel_volumes.in_set(RenderSet::Prepare),
+                    (resize_pools, prepare_uniforms)
+                        .in_set(RenderSet::Prepare)
+                        .after(prepare_voxel_volumes),
+                    prepare_bind_group.in_set(RenderSet::PrepareBindGroups),
+                ),
+            );
     }
 
     fn finish(&self, app: &mut App) {
-        let render_device = app.world.resource::<RenderDevice>();
-        let render_queue = app.world.resource::<RenderQueue>();
-
-        // brickmap settings
-        let world_depth = 9;
-        let color_texture_size = UVec3::splat(640);
-        let brickmap_max_nodes = 1 << 16;
-
-        // load world (slooowwww)
-        let path = PathBuf::from("assets/worlds/imperial_city");
-        let mut cpu_brickmap = load_anvil(path, world_depth);
-        cpu_brickmap.recreate_mipmaps();
-
-        // setup gpu brickmap
-        let brickmap_depth = world_depth - BRICK_SIZE.trailing_zeros();
-        let dim = color_texture_size / BRICK_SIZE;
-        let brick_count = (dim.x * dim.y * dim.z) as usize;
-        let mut gpu_voxel_world = GpuVoxelWorld {
-            brickmap: vec![BRICK_OFFSET; 8 * brickmap_max_nodes],
-            gpu_to_cpu: vec![0; 8 * brickmap_max_nodes],
-            brickmap_holes: (1..brickmap_max_nodes).collect::<VecDeque<usize>>(),
-            brick_holes: (1..brick_count).collect::<VecDeque<usize>>(),
-            color_texture_size,
-            brickmap_depth,
-        };
+        app.sub_app_mut(RenderApp)
+            .init_resource::<VoxelBindGroupLayout>();
+    }
+}
 
-        // uniforms
-        let voxel_uniforms = VoxelUniforms {
-            brickmap_depth,
-            brick_size: BRICK_SIZE.trailing_zeros(),
-            brick_ints: Brick::brick_ints() as u32,
-        };
-        let mut uniform_buffer = UniformBuffer::from(voxel_uniforms.clone());
-        uniform_buffer.write_buffer(render_device, render_queue);
-
-        // brickmap
-        let (_, brickmap, _) = unsafe { gpu_voxel_world.brickmap.align_to::<u8>() };
-        let brickmap = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            contents: brickmap,
-            label: None,
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
-
-        // counters
-        let counters = vec![0; brickmap_max_nodes * COUNTER_BITS]; // * 8 / 8
-        let counters = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            contents: &counters,
-            label: None,
-            usage: BufferUsages::STORAGE, // | BufferUsages::COPY_DST | BufferUsages::MAP_READ,
-        });
-
-        // bricks
-        let bricks = vec![0; 4 * Brick::brick_ints() * brick_count];
-        let bricks = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            contents: &bricks,
-            label: None,
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
-
-        // color
-        let texture_length = color_texture_size.x * color_texture_size.y * color_texture_size.z;
-        let color = vec![0; 4 * texture_length as usize];
-        let color = render_device.create_texture_with_data(
-            render_queue,
-            &TextureDescriptor {
-                label: None,
-                view_formats: &[TextureFormat::Rgba8Unorm],
-                size: Extent3d {
-                    width: color_texture_size.x,
-                    height: color_texture_size.y,
-                    depth_or_array_layers: color_texture_size.z,
-                },
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: TextureDimension::D3,
-                format: TextureFormat::Rgba8Unorm,
-                usage: TextureUsages::STORAGE_BINDING,
-            },
-            &color,
-        );
+/// The bind group layout shared by the voxel data of every volume.
+#[derive(Resource, Deref)]
+pub struct VoxelBindGroupLayout(pub BindGroupLayout);
 
+impl FromWorld for VoxelBindGroupLayout {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
         let bind_group_layout =
             render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
                 label: Some("voxelization bind group layout"),
                 entries: &[
                     BindGroupLayoutEntry {
                         binding: 0,
-                        visibility: ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                        visibility: ShaderStages::VERTEX
+                            | ShaderStages::FRAGMENT
+                            | ShaderStages::COMPUTE,
                         ty: BindingType::Buffer {
                             ty: BufferBindingType::Uniform,
                             has_dynamic_offset: false,
@@ -169,28 +196,146 @@ impl Plugin for VoxelWorldPlugin {
                         },
                         count: None,
                     },
+                    BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::ReadOnly,
+                            format: TextureFormat::Rgba8Unorm,
+                            view_dimension: TextureViewDimension::D3,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(4),
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 7,
+                        visibility: ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                        ty: BindingType::Texture {
+                            sample_type: TextureSampleType::Uint,
+                            view_dimension: TextureViewDimension::D3,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
                 ],
             });
 
-        let voxel_data = VoxelData {
+        Self(bind_group_layout)
+    }
+}
+
+/// The world and transform of a voxel volume, extracted every frame.
+#[derive(Component)]
+pub struct ExtractedVoxelWorld {
+    pub id: AssetId<CpuVoxelWorld>,
+    pub world: CpuVoxelWorld,
+    pub transform: GlobalTransform,
+}
+
+#[allow(clippy::type_complexity)]
+fn extract_voxel_worlds(
+    mut commands: Commands,
+    voxel_worlds: Extract<Res<Assets<CpuVoxelWorld>>>,
+    voxel_volumes: Extract<
+        Query<(Entity, &Handle<CpuVoxelWorld>, &GlobalTransform), With<VoxelVolume>>,
+    >,
+) {
+    let mut values = Vec::new();
+    for (entity, handle, transform) in voxel_volumes.iter() {
+        // the world might not be loaded yet
+        if let Some(world) = voxel_worlds.get(handle) {
+            values.push((
+                entity,
+                ExtractedVoxelWorld {
+                    id: handle.id(),
+                    world: world.clone(),
+                    transform: *transform,
+                },
+            ));
+        }
+    }
+    commands.insert_or_spawn_batch(values);
+}
+
+/// The gpu residency of a single voxel volume.
+pub struct GpuVoxelVolume {
+    pub world_id: AssetId<CpuVoxelWorld>,
+    pub cpu_world: CpuVoxelWorld,
+    pub gpu_world: GpuVoxelWorld,
+    pub data: VoxelData,
+    /// transform the uniforms were last written with
+    transform: Option<Mat4>,
+}
+
+/// Every voxel volume's gpu residency, keyed by the volume entity.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct GpuVoxelVolumes(HashMap<Entity, GpuVoxelVolume>);
+
+impl GpuVoxelVolume {
+    pub fn new(
+        world_id: AssetId<CpuVoxelWorld>,
+        cpu_world: CpuVoxelWorld,
+        pool_settings: &VoxelPoolSettings,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) -> Self {
+        let (color_texture_size, brickmap_max_nodes) =
+            pool_settings.resolve(&render_device.limits());
+
+        // setup gpu brickmap
+        let brickmap_depth = cpu_world.brickmap_depth;
+        let mut gpu_world =
+            GpuVoxelWorld::new(color_texture_size, brickmap_max_nodes, brickmap_depth);
+
+        // uniforms
+        let voxel_uniforms = VoxelUniforms {
+            brickmap_depth,
+            brick_size: BRICK_SIZE.trailing_zeros(),
+            brick_ints: Brick::brick_ints() as u32,
+            transform: Mat4::IDENTITY,
+            inverse_transform: Mat4::IDENTITY,
+            debug_view: 0,
+            previous_transform: Mat4::IDENTITY,
+        };
+        let mut uniform_buffer = UniformBuffer::from(voxel_uniforms);
+        uniform_buffer.write_buffer(render_device, render_queue);
+
+        // brickmap, counters, streaming state, bricks, color, light and material
+        let (brickmap, counters, streaming_state, bricks, color, light, material) =
+            create_pools(render_device, color_texture_size, brickmap_max_nodes);
+
+        let data = VoxelData {
             uniform_buffer,
             brickmap,
             counters,
+            streaming_state,
             bricks,
             color,
-            bind_group_layout,
+            light,
+            material,
             bind_group: None,
         };
 
         // initialize brickmap with lowest mip level
         for i in 0..8 {
-            let brick_index = cpu_brickmap.brickmap[i].brick;
+            // empty nodes too, streaming looks up their children through this
+            gpu_world.gpu_to_cpu[i] = i as u32;
+            let brick_index = cpu_world.brickmap[i].brick;
             if brick_index > 0 {
-                let brick = &cpu_brickmap.bricks[brick_index as usize];
-                match gpu_voxel_world.allocate_brick(brick, &voxel_data, render_queue) {
+                let brick = cpu_world.brick_blocking(brick_index);
+                match brick.and_then(|brick| gpu_world.allocate_brick(&brick, &data, render_queue))
+                {
                     Ok(gpu_brick_index) => {
-                        gpu_voxel_world.brickmap[i] = BRICK_OFFSET + gpu_brick_index as u32;
-                        gpu_voxel_world.gpu_to_cpu[i] = i as u32;
+                        gpu_world.brickmap[i] = BRICK_OFFSET + gpu_brick_index as u32;
                     }
                     Err(e) => {
                         error!("failed to allocate brick: {}", e);
@@ -199,101 +344,376 @@ impl Plugin for VoxelWorldPlugin {
             }
         }
 
-        app.sub_app_mut(RenderApp)
-            .insert_resource(voxel_uniforms)
-            .insert_resource(voxel_data)
-            .insert_resource(CpuVoxelWorld(cpu_brickmap))
-            .insert_resource(gpu_voxel_world)
-            .add_systems(
-                Render,
-                (
-                    prepare_uniforms.in_set(RenderSet::Prepare),
-                    prepare_bind_group.in_set(RenderSet::PrepareBindGroups),
-                ),
+        gpu_world.rebuild_instances();
+
+        Self {
+            world_id,
+            cpu_world,
+            gpu_world,
+            data,
+            transform: None,
+        }
+    }
+}
+
+/// create the gpu residency of new volumes and drop the one of removed volumes
+fn prepare_voxel_volumes(
+    voxel_volumes: Query<(Entity, &ExtractedVoxelWorld)>,
+    mut gpu_voxel_volumes: ResMut<GpuVoxelVolumes>,
+    pool_settings: Res<VoxelPoolSettings>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    gpu_voxel_volumes.retain(|entity, gpu_voxel_volume| {
+        voxel_volumes
+            .get(*entity)
+            .is_ok_and(|(_, extracted)| extracted.id == gpu_voxel_volume.world_id)
+    });
+
+    for (entity, extracted) in voxel_volumes.iter() {
+        if !gpu_voxel_volumes.contains_key(&entity) {
+            info!("uploading voxel world for {:?}", entity);
+            let gpu_voxel_volume = GpuVoxelVolume::new(
+                extracted.id,
+                extracted.world.clone(),
+                &pool_settings,
+                &render_device,
+                &render_queue,
             );
+            gpu_voxel_volumes.insert(entity, gpu_voxel_volume);
+        }
+    }
+}
+
+/// Size of the gpu brick and node pools. Changing this at runtime reallocates
+/// the pools and migrates the resident bricks into the new allocation.
+#[derive(Resource, ExtractResource, Clone, PartialEq, Reflect)]
+pub struct VoxelPoolSettings {
+    /// Pick the pool sizes from the adapter limits and `memory_budget` instead
+    /// of `color_texture_size` and `max_nodes`.
+    pub auto: bool,
+    /// Memory budget in megabytes used when `auto` is set.
+    pub memory_budget: u32,
+    /// Side length of the color texture in voxels. Rounded down to a multiple
+    /// of `BRICK_SIZE`.
+    pub color_texture_size: u32,
+    /// Number of node groups (8 nodes each) in the gpu brickmap.
+    pub max_nodes: u32,
+}
+
+impl Default for VoxelPoolSettings {
+    fn default() -> Self {
+        Self {
+            auto: false,
+            memory_budget: 1024,
+            color_texture_size: 640,
+            max_nodes: 1 << 16,
+        }
+    }
+}
+
+impl VoxelPoolSettings {
+    /// returns the color texture size and node count to allocate
+    pub fn resolve(&self, limits: &WgpuLimits) -> (UVec3, usize) {
+        let max_storage = limits
+            .max_storage_buffer_binding_size
+            .min(limits.max_buffer_size.min(u32::MAX as u64) as u32)
+            as u64;
+        let brick_bytes = 4 * Brick::brick_ints() as u64;
+
+        let (side, max_nodes) = if self.auto {
+            // every brick costs its colors, light and material plus its bitmask
+            let budget = self.memory_budget as u64 * 1024 * 1024;
+            let bytes_per_brick = Brick::BYTES as u64 + brick_bytes;
+            let side = ((budget / bytes_per_brick) as f64).cbrt() as u32 * BRICK_SIZE;
+            let side = side.min(limits.max_texture_dimension_3d);
+
+            // one node group per brick is plenty, nodes are cheap
+            let dim = (side / BRICK_SIZE) as u64;
+            (side, dim.pow(3) as u32)
+        } else {
+            (self.color_texture_size, self.max_nodes)
+        };
+
+        // respect the adapter limits
+        let mut side = side.min(limits.max_texture_dimension_3d) / BRICK_SIZE;
+        while side > 3 && (side as u64).pow(3) * brick_bytes > max_storage {
+            side -= 1;
+        }
+        let side = side.max(3) * BRICK_SIZE;
+        let max_nodes = (max_nodes as u64)
+            .min(max_storage / (8 * 4))
+            .min(max_storage / COUNTER_BITS as u64)
+            .max(2);
+
+        (UVec3::splat(side), max_nodes as usize)
+    }
+}
+
+/// creates the brickmap, counters, streaming state, bricks, color, light and
+/// material pools
+pub fn create_pools(
+    render_device: &RenderDevice,
+    color_texture_size: UVec3,
+    max_nodes: usize,
+) -> (Buffer, Buffer, Buffer, Buffer, Texture, Texture, Texture) {
+    let dim = color_texture_size / BRICK_SIZE;
+    let brick_count = (dim.x * dim.y * dim.z) as usize;
+
+    // brickmap
+    let brickmap = vec![BRICK_OFFSET; 8 * max_nodes];
+    let (_, brickmap, _) = unsafe { brickmap.align_to::<u8>() };
+    let brickmap = render_device.create_buffer_with_data(&BufferInitDescriptor {
+        contents: brickmap,
+        label: None,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+    });
+
+    // counters
+    let counters = vec![0; max_nodes * COUNTER_BITS]; // * 8 / 8
+    let counters = render_device.create_buffer_with_data(&BufferInitDescriptor {
+        contents: &counters,
+        label: None,
+        usage: BufferUsages::STORAGE, // | BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+    });
+
+    // streaming state, one u32 per node
+    let streaming_state = render_device.create_buffer(&BufferDescriptor {
+        label: None,
+        size: (4 * 8 * max_nodes) as u64,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    // bricks
+    let bricks = render_device.create_buffer(&BufferDescriptor {
+        label: None,
+        size: (4 * Brick::brick_ints() * brick_count) as u64,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+
+    // color, and light laid out the same way
+    let texture_descriptor = TextureDescriptor {
+        label: None,
+        view_formats: &[TextureFormat::Rgba8Unorm],
+        size: Extent3d {
+            width: color_texture_size.x,
+            height: color_texture_size.y,
+            depth_or_array_layers: color_texture_size.z,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D3,
+        format: TextureFormat::Rgba8Unorm,
+        usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_DST | TextureUsages::COPY_SRC,
+    };
+    let color = render_device.create_texture(&texture_descriptor);
+    let light = render_device.create_texture(&texture_descriptor);
+
+    // material, a byte per voxel. r8uint can't be a storage texture everywhere
+    // so it's read with textureLoad instead
+    let material = render_device.create_texture(&TextureDescriptor {
+        view_formats: &[TextureFormat::R8Uint],
+        format: TextureFormat::R8Uint,
+        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST | TextureUsages::COPY_SRC,
+        ..texture_descriptor
+    });
+
+    (
+        brickmap,
+        counters,
+        streaming_state,
+        bricks,
+        color,
+        light,
+        material,
+    )
+}
+
+fn resize_pools(
+    pool_settings: Res<VoxelPoolSettings>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut gpu_voxel_volumes: ResMut<GpuVoxelVolumes>,
+) {
+    if !pool_settings.is_changed() {
+        return;
+    }
+
+    let (color_texture_size, max_nodes) = pool_settings.resolve(&render_device.limits());
+    for gpu_voxel_volume in gpu_voxel_volumes.values_mut() {
+        let GpuVoxelVolume {
+            cpu_world,
+            gpu_world,
+            data,
+            ..
+        } = gpu_voxel_volume;
+        if color_texture_size == gpu_world.color_texture_size
+            && max_nodes == gpu_world.brickmap.len() / 8
+        {
+            continue;
+        }
+
+        info!(
+            "resizing brick pool to {:?} and node pool to {} nodes",
+            color_texture_size, max_nodes
+        );
+        gpu_world.resize(
+            color_texture_size,
+            max_nodes,
+            data,
+            cpu_world,
+            &render_device,
+            &render_queue,
+        );
     }
 }
 
-#[derive(Resource)]
 pub struct VoxelData {
     pub uniform_buffer: UniformBuffer<VoxelUniforms>,
     pub brickmap: Buffer,
     pub counters: Buffer,
+    /// what streaming wants from each node, for `DebugView::Streaming`
+    pub streaming_state: Buffer,
     pub bricks: Buffer,
     pub color: Texture,
-    pub bind_group_layout: BindGroupLayout,
+    /// block light, sky occlusion, emission and surface of the bricks, same
+    /// layout as `color`
+    pub light: Texture,
+    /// the material id and transparent bit of each voxel, same layout as
+    /// `color`
+    pub material: Texture,
     pub bind_group: Option<BindGroup>,
 }
 
-#[derive(Resource, ExtractResource, Clone, ShaderType)]
+#[derive(Clone, ShaderType)]
 pub struct VoxelUniforms {
     brickmap_depth: u32,
     brick_size: u32,
     brick_ints: u32,
+    transform: Mat4,
+    inverse_transform: Mat4,
+    /// `DebugView` of the volume, 0 leaves it to the camera
+    debug_view: u32,
+    /// `transform` of the last frame, for motion vectors
+    previous_transform: Mat4,
 }
 
 fn prepare_uniforms(
-    voxel_uniforms: Res<VoxelUniforms>,
-    mut voxel_data: ResMut<VoxelData>,
+    voxel_volumes: Query<(Entity, &ExtractedVoxelWorld, Option<&DebugView>)>,
+    mut gpu_voxel_volumes: ResMut<GpuVoxelVolumes>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
 ) {
-    voxel_data.uniform_buffer.set(voxel_uniforms.clone());
-    voxel_data
-        .uniform_buffer
-        .write_buffer(&render_device, &render_queue);
+    for (entity, extracted, debug_view) in voxel_volumes.iter() {
+        let Some(gpu_voxel_volume) = gpu_voxel_volumes.get_mut(&entity) else {
+            continue;
+        };
+
+        let transform = extracted.transform.compute_matrix();
+        // there is no last frame to move from on the first one
+        let previous_transform = gpu_voxel_volume
+            .transform
+            .replace(transform)
+            .unwrap_or(transform);
+        let uniform_buffer = &mut gpu_voxel_volume.data.uniform_buffer;
+        let voxel_uniforms = uniform_buffer.get_mut();
+        voxel_uniforms.previous_transform = previous_transform;
+        voxel_uniforms.transform = transform;
+        voxel_uniforms.inverse_transform = transform.inverse();
+        voxel_uniforms.debug_view = debug_view.copied().unwrap_or_default() as u32;
+        uniform_buffer.write_buffer(&render_device, &render_queue);
+    }
 }
 
-fn prepare_bind_group(render_device: Res<RenderDevice>, mut voxel_data: ResMut<VoxelData>) {
-    let bind_group = render_device.create_bind_group(
-        Some("voxel bind group"),
-        &voxel_data.bind_group_layout,
-        &[
-            BindGroupEntry {
-                binding: 0,
-                resource: voxel_data.uniform_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 1,
-                resource: voxel_data.brickmap.as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 2,
-                resource: voxel_data.counters.as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 3,
-                resource: voxel_data.bricks.as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 4,
-                resource: BindingResource::TextureView(
-                    &voxel_data
-                        .color
-                        .create_view(&TextureViewDescriptor::default()),
-                ),
-            },
-        ],
-    );
-    voxel_data.bind_group = Some(bind_group);
+fn prepare_bind_group(
+    render_device: Res<RenderDevice>,
+    bind_group_layout: Res<VoxelBindGroupLayout>,
+    mut gpu_voxel_volumes: ResMut<GpuVoxelVolumes>,
+) {
+    for gpu_voxel_volume in gpu_voxel_volumes.values_mut() {
+        gpu_voxel_volume
+            .data
+            .prepare_bind_group(&render_device, &bind_group_layout);
+    }
+}
+
+impl VoxelData {
+    fn prepare_bind_group(&mut self, render_device: &RenderDevice, layout: &BindGroupLayout) {
+        let voxel_data = self;
+        let bind_group = render_device.create_bind_group(
+            Some("voxel bind group"),
+            layout,
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: voxel_data.uniform_buffer.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: voxel_data.brickmap.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: voxel_data.counters.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: voxel_data.bricks.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::TextureView(
+                        &voxel_data
+                            .color
+                            .create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: BindingResource::TextureView(
+                        &voxel_data
+                            .light
+                            .create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: voxel_data.streaming_state.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: BindingResource::TextureView(
+                        &voxel_data
+                            .material
+                            .create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+            ],
+        );
+        voxel_data.bind_group = Some(bind_group);
+    }
 }
 
 pub struct SetVoxelDataBindGroup<const I: usize>;
 
 impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetVoxelDataBindGroup<I> {
-    type Param = SRes<VoxelData>;
+    type Param = SRes<GpuVoxelVolumes>;
     type ViewWorldQuery = ();
     type ItemWorldQuery = ();
 
     fn render<'w>(
-        _item: &P,
+        item: &P,
         _view: (),
         _entity: (),
         query: SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        let voxel_world_data = query.into_inner();
-        let bind_group = voxel_world_data.bind_group.as_ref();
+        let gpu_voxel_volumes = query.into_inner();
+        let bind_group = gpu_voxel_volumes
+            .get(&item.entity())
+            .and_then(|gpu_voxel_volume| gpu_voxel_volume.data.bind_group.as_ref());
         match bind_group {
             Some(bind_group) => {
                 pass.set_bind_group(I, bind_group, &[]);
@@ -315,6 +735,11 @@ impl Default for VoxelWorldStatsResource {
         Self(Arc::new(Mutex::new(VoxelWorldStats {
             nodes: 0,
             bricks: 0,
+            max_nodes: 0,
+            max_bricks: 0,
+            paged_bricks: 0,
+            max_paged_bricks: 0,
+            streaming_pending: 0,
         })))
     }
 }
@@ -322,4 +747,12 @@ impl Default for VoxelWorldStatsResource {
 pub struct VoxelWorldStats {
     pub nodes: usize,
     pub bricks: usize,
+    pub max_nodes: usize,
+    pub max_bricks: usize,
+    /// fine bricks paged in from disk, over every paged world
+    pub paged_bricks: usize,
+    pub max_paged_bricks: usize,
+    /// nodes streaming wanted to divide or cull last frame, 0 once it has
+    /// converged for the current streaming positions
+    pub streaming_pending: usize,
 }
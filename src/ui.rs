@@ -1,9 +1,11 @@
-use crate::{
-    character::CharacterEntity,
-    render_pipeline::{StreamingSettings, VoxelVolume, VoxelWorldStatsResource},
+use crate::character::CharacterEntity;
+use alex::render_pipeline::{
+    DebugView, GpuCulling, MainPassSettings, StreamingSettings, TemporalUpscale, TimeOfDay,
+    VoxelFog, VoxelPoolSettings, VoxelRenderer, VoxelVolume, VoxelWorldStatsResource,
 };
 use bevy::{
     diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin},
+    pbr::ScreenSpaceAmbientOcclusionSettings,
     prelude::*,
     window::PrimaryWindow,
 };
@@ -34,16 +36,33 @@ impl Plugin for UiPlugin {
 #[derive(Resource, Deref, DerefMut)]
 struct FpsData(VecDeque<f64>);
 
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
 fn ui_system(
+    mut commands: Commands,
     mut contexts: EguiContexts,
     window: Query<Entity, With<PrimaryWindow>>,
     diagnostics: Res<DiagnosticsStore>,
     mut character: Query<&mut CharacterEntity>,
-    mut voxel_volume: Query<&mut VoxelVolume>,
+    mut voxel_volume: Query<(Entity, &mut VoxelVolume)>,
+    mut cameras: Query<(
+        Entity,
+        &mut VoxelRenderer,
+        Option<&mut MainPassSettings>,
+        Option<&mut VoxelFog>,
+        Option<&mut GpuCulling>,
+        Option<&mut DebugView>,
+        Option<&mut TemporalUpscale>,
+        Has<ScreenSpaceAmbientOcclusionSettings>,
+    )>,
+    mut sun: Query<(&mut DirectionalLight, &mut Transform)>,
+    mut time_of_day: ResMut<TimeOfDay>,
     mut fps_data: ResMut<FpsData>,
     streaming_settings: ResMut<StreamingSettings>,
     type_registry: ResMut<AppTypeRegistry>,
     voxel_stats: Res<VoxelWorldStatsResource>,
+    mut pool_settings: ResMut<VoxelPoolSettings>,
+    mut pending_pool_settings: Local<Option<VoxelPoolSettings>>,
 ) {
     let mut character_entity = character.single_mut();
 
@@ -74,16 +93,166 @@ fn ui_system(
         }
 
         let voxel_stats = voxel_stats.lock().unwrap();
-        ui.label(format!("Nodes: {}", voxel_stats.nodes));
-        ui.label(format!("Bricks: {}", voxel_stats.bricks));
+        ui.label(format!(
+            "Nodes: {} / {}",
+            voxel_stats.nodes, voxel_stats.max_nodes
+        ));
+        ui.label(format!(
+            "Bricks: {} / {}",
+            voxel_stats.bricks, voxel_stats.max_bricks
+        ));
+        if voxel_stats.max_paged_bricks > 0 {
+            ui.label(format!(
+                "Paged bricks: {} / {}",
+                voxel_stats.paged_bricks, voxel_stats.max_paged_bricks
+            ));
+        }
+
+        for (entity, voxel_volume) in voxel_volume.iter_mut() {
+            ui.push_id(entity, |ui| {
+                ui_for_value(voxel_volume.into_inner(), ui, &type_registry.read());
+            });
+        }
+
+        // pick the renderer per camera
+        for (entity, renderer, main_pass_settings, fog, gpu_culling, debug_view, upscale, ssao) in
+            cameras.iter_mut()
+        {
+            ui.push_id(entity, |ui| {
+                ui_for_value(renderer.into_inner(), ui, &type_registry.read());
+                if let Some(debug_view) = debug_view {
+                    ui.horizontal(|ui| {
+                        ui.label("Debug view: ");
+                        ui_for_value(debug_view.into_inner(), ui, &type_registry.read());
+                    });
+                }
+                if let Some(main_pass_settings) = main_pass_settings {
+                    ui.collapsing("Fullscreen tracer", |ui| {
+                        ui_for_value(main_pass_settings.into_inner(), ui, &type_registry.read());
+                    });
+                }
+                if let Some(fog) = fog {
+                    ui.collapsing("Fog", |ui| {
+                        ui_for_value(fog.into_inner(), ui, &type_registry.read());
+                    });
+                }
+                if let Some(mut upscale) = upscale {
+                    ui.collapsing("Upscaling", |ui| {
+                        ui.add(
+                            egui::Slider::new(&mut upscale.render_scale, 0.25..=1.0)
+                                .text("Render scale"),
+                        );
+                        ui.horizontal(|ui| {
+                            ui.label("Max history: ");
+                            ui.add(DragValue::new(&mut upscale.max_history).speed(0.1));
+                        });
+                    });
+                }
 
-        let voxel_volume = voxel_volume.single_mut();
-        ui_for_value(voxel_volume.into_inner(), ui, &type_registry.read());
+                // only the instanced renderer draws into the prepass ssao reads
+                let mut enabled = ssao;
+                if ui.checkbox(&mut enabled, "SSAO").changed() {
+                    match enabled {
+                        true => commands
+                            .entity(entity)
+                            .insert(ScreenSpaceAmbientOcclusionSettings::default()),
+                        false => commands
+                            .entity(entity)
+                            .remove::<ScreenSpaceAmbientOcclusionSettings>(),
+                    };
+                }
+
+                let mut enabled = gpu_culling.is_some();
+                if ui.checkbox(&mut enabled, "GPU culling").changed() {
+                    match enabled {
+                        true => commands.entity(entity).insert(GpuCulling::default()),
+                        false => commands.entity(entity).remove::<GpuCulling>(),
+                    };
+                }
+                if let Some(gpu_culling) = gpu_culling {
+                    ui_for_value(gpu_culling.into_inner(), ui, &type_registry.read());
+                }
+            });
+        }
 
         ui.push_id(5, |ui| {
             ui_for_value(streaming_settings.into_inner(), ui, &type_registry.read());
         });
 
+        // only reallocate the pools once the new settings are applied
+        ui.push_id(6, |ui| {
+            ui.collapsing("Brick pool", |ui| {
+                let pending = pending_pool_settings.get_or_insert_with(|| pool_settings.clone());
+                ui_for_value(pending, ui, &type_registry.read());
+                if ui.button("Apply").clicked() && *pending != *pool_settings {
+                    *pool_settings = pending.clone();
+                }
+            });
+        });
+
+        // the first directional light is the sun, it follows the time of day
+        // or is set by hand
+        ui.collapsing("Sun", |ui| {
+            ui.checkbox(&mut time_of_day.enabled, "Time of day");
+            if time_of_day.enabled {
+                ui.add(egui::Slider::new(&mut time_of_day.hour, 0.0..=24.0).text("Hour"));
+                ui.horizontal(|ui| {
+                    ui.label("Hours per second: ");
+                    ui.add(DragValue::new(&mut time_of_day.speed).speed(0.01));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Intensity: ");
+                    ui.add(DragValue::new(&mut time_of_day.illuminance).speed(10.0));
+                });
+            }
+
+            let Some((mut light, mut transform)) = sun.iter_mut().next() else {
+                return;
+            };
+            if !time_of_day.enabled {
+                let direction = transform.back();
+                let mut elevation = direction.y.asin().to_degrees();
+                let mut azimuth = direction.z.atan2(direction.x).to_degrees();
+                let elevation_changed = ui
+                    .horizontal(|ui| {
+                        ui.label("Elevation: ");
+                        ui.add(DragValue::new(&mut elevation).clamp_range(-90.0..=90.0))
+                            .changed()
+                    })
+                    .inner;
+                let azimuth_changed = ui
+                    .horizontal(|ui| {
+                        ui.label("Azimuth: ");
+                        ui.add(DragValue::new(&mut azimuth)).changed()
+                    })
+                    .inner;
+                if elevation_changed || azimuth_changed {
+                    let (elevation, azimuth) = (elevation.to_radians(), azimuth.to_radians());
+                    let direction = Vec3::new(
+                        elevation.cos() * azimuth.cos(),
+                        elevation.sin(),
+                        elevation.cos() * azimuth.sin(),
+                    );
+                    transform.look_to(-direction, Vec3::Y);
+                }
+
+                ui.horizontal(|ui| {
+                    ui.label("Colour: ");
+                    let mut colour = light.color.as_rgba_f32();
+                    let mut rgb = [colour[0], colour[1], colour[2]];
+                    if ui.color_edit_button_rgb(&mut rgb).changed() {
+                        colour[..3].copy_from_slice(&rgb);
+                        light.color = Color::rgba(colour[0], colour[1], colour[2], colour[3]);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Intensity: ");
+                    ui.add(DragValue::new(&mut light.illuminance).speed(10.0));
+                });
+            }
+            ui.checkbox(&mut light.shadows_enabled, "Shadows");
+        });
+
         ui.horizontal(|ui| {
             ui.label("Speed: ");
             ui.add(DragValue::new(&mut character_entity.speed));
@@ -0,0 +1,430 @@
+use alex::{
+    readback::{ReadbackChannel, ReadbackPlugin, ReadbackRequest},
+    render_pipeline::{
+        CpuBrickmap, CpuVoxelWorld, ExtractedSun, MainPassSettings, ReferenceCamera,
+        ReferenceRenderer, ReferenceSettings, SkyCamera, StreamingSettings, TimeOfDay, VoxelPlugin,
+        VoxelRenderer, VoxelVolume, VoxelVolumeBundle, VoxelWorldStatsResource,
+    },
+};
+use bevy::{
+    app::PluginsState,
+    core_pipeline::{
+        prepass::{DepthPrepass, NormalPrepass},
+        tonemapping::{DebandDither, Tonemapping},
+    },
+    log::LogPlugin,
+    pbr::ScreenSpaceAmbientOcclusionSettings,
+    prelude::*,
+    render::{
+        camera::RenderTarget,
+        primitives::Frustum,
+        render_resource::*,
+        renderer::{initialize_renderer, RenderInstance},
+        settings::{RenderCreation, WgpuSettings},
+        texture::{CompressedImageFormats, ImageSampler, ImageType},
+        RenderPlugin,
+    },
+    tasks::block_on,
+    window::ExitCondition,
+    winit::WinitPlugin,
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+const SIZE: UVec2 = UVec2::new(320, 180);
+/// frames streaming has to stay idle before the image is taken
+const SETTLE_FRAMES: u32 = 5;
+const MAX_FRAMES: u32 = 1000;
+/// largest difference in a channel for a pixel to still count as matching.
+/// rasterization rules leave some room on the edges of bricks
+const TOLERANCE: u8 = 8;
+/// fraction of the pixels that may differ by more than `TOLERANCE`
+const MAX_MISMATCHED: f32 = 0.002;
+/// the same against the reference renderer. it traces ambient occlusion
+/// where the gpu estimates it from the neighbouring voxels, so corners are
+/// allowed to be off by more and in more places
+const REFERENCE_TOLERANCE: u8 = 24;
+const REFERENCE_MAX_MISMATCHED: f32 = 0.03;
+/// field of view of the cameras
+const FOV: f32 = 1.2;
+
+/// the tests share the software adapter, and a slow one at that
+static RENDERER: Mutex<()> = Mutex::new(());
+
+pub struct Scene {
+    pub name: &'static str,
+    pub brickmap: CpuBrickmap,
+    pub camera: Transform,
+    pub renderer: VoxelRenderer,
+    /// pbr meshes drawn along with the voxels
+    pub meshes: Vec<(Mesh, Transform)>,
+    /// run the depth and normal prepass and ssao on them
+    pub ssao: bool,
+}
+
+/// renders the scene and compares it against its golden image
+pub fn check(scene: Scene) {
+    let _lock = RENDERER.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(render_creation) = software_renderer() else {
+        panic!(
+            "can't render {}, there is no software adapter on a primary backend",
+            scene.name
+        );
+    };
+
+    let name = scene.name;
+    let image = render(scene, render_creation, false);
+    compare(name, image);
+}
+
+/// renders the scene and compares it against what the reference renderer
+/// makes of it, without tonemapping so both are the clamped linear colour
+pub fn check_reference(scene: Scene) {
+    let _lock = RENDERER.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(render_creation) = software_renderer() else {
+        panic!(
+            "can't render {}, there is no software adapter on a primary backend",
+            scene.name
+        );
+    };
+
+    // the same sun `TimeOfDay` moves the scene's light to
+    let renderer = ReferenceRenderer::new(
+        &scene.brickmap,
+        ReferenceSettings {
+            sun: ExtractedSun::from_time_of_day(&TimeOfDay::default(), true),
+            ..default()
+        },
+    );
+    let reference = to_srgb(&renderer.render(&ReferenceCamera {
+        transform: scene.camera,
+        projection: PerspectiveProjection {
+            fov: FOV,
+            ..default()
+        },
+        size: SIZE,
+    }));
+
+    let name = scene.name;
+    let image = render(scene, render_creation, true);
+    let reference_path = save_output(&format!("{}.reference.png", name), reference.clone());
+    compare_images(
+        name,
+        &reference_path,
+        &reference,
+        image,
+        REFERENCE_TOLERANCE,
+        REFERENCE_MAX_MISMATCHED,
+    );
+}
+
+/// clamps the linear hdr output of the reference renderer into an srgb image,
+/// like the gpu writes into the target without tonemapping
+fn to_srgb(image: &Image) -> Image {
+    let pixels = bytemuck::pod_collect_to_vec::<u8, [f32; 4]>(&image.data);
+    let data = pixels
+        .iter()
+        .flat_map(|&[r, g, b, _]| Color::rgb_linear(r, g, b).as_rgba_u8())
+        .collect();
+    Image::new(
+        image.texture_descriptor.size,
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+/// the software adapter, so images don't depend on the gpu they ran on
+fn software_renderer() -> Option<RenderCreation> {
+    // lavapipe, warp or the like. llvmpipe behind the gl backend can't run
+    // the culling compute shaders and doesn't draw the voxels right
+    let settings = WgpuSettings {
+        backends: Some(wgpu::Backends::PRIMARY),
+        ..default()
+    };
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: settings.backends.unwrap_or(wgpu::Backends::all()),
+        dx12_shader_compiler: settings.dx12_shader_compiler.clone(),
+    });
+    let options = wgpu::RequestAdapterOptions {
+        power_preference: settings.power_preference,
+        force_fallback_adapter: true,
+        compatible_surface: None,
+    };
+    block_on(instance.request_adapter(&options))?;
+
+    let (device, queue, adapter_info, adapter) =
+        block_on(initialize_renderer(&instance, &settings, &options));
+    Some(RenderCreation::Manual(
+        device,
+        queue,
+        adapter_info,
+        adapter,
+        RenderInstance(Arc::new(instance)),
+    ))
+}
+
+/// `linear` leaves out tonemapping and dithering
+fn render(scene: Scene, render_creation: RenderCreation, linear: bool) -> Image {
+    let mut app = App::new();
+    app.add_plugins((
+        DefaultPlugins
+            .set(WindowPlugin {
+                primary_window: None,
+                exit_condition: ExitCondition::DontExit,
+                close_when_requested: false,
+            })
+            .set(RenderPlugin { render_creation })
+            .disable::<WinitPlugin>()
+            // the global logger can only be set once per process
+            .disable::<LogPlugin>(),
+        VoxelPlugin,
+        ReadbackPlugin,
+    ))
+    .insert_resource(Msaa::Off)
+    .insert_resource(StreamingSettings {
+        transition_time: 0.0,
+        ..default()
+    })
+    .insert_resource(TimeOfDay::default())
+    .add_systems(Update, follow_camera);
+
+    while app.plugins_state() == PluginsState::Adding {
+        bevy::tasks::tick_global_task_pools_on_main_thread();
+    }
+    app.finish();
+    app.cleanup();
+
+    let target = setup(&mut app.world, scene, linear);
+
+    let mut settled = 0;
+    for _ in 0..MAX_FRAMES {
+        app.update();
+
+        if let Some((_, image)) = app
+            .world
+            .resource::<ReadbackChannel>()
+            .receiver
+            .try_iter()
+            .last()
+        {
+            return image;
+        }
+
+        let voxel_stats = app.world.resource::<VoxelWorldStatsResource>().clone();
+        let voxel_stats = voxel_stats.lock().unwrap();
+        if voxel_stats.nodes > 0 && voxel_stats.streaming_pending == 0 {
+            settled += 1;
+        } else {
+            settled = 0;
+        }
+        if settled >= SETTLE_FRAMES {
+            app.world.resource_mut::<ReadbackRequest>().0 = Some((0, target.clone()));
+        }
+    }
+
+    panic!("nothing was rendered after {} frames", MAX_FRAMES);
+}
+
+/// spawns the scene, returns the image the camera renders to
+fn setup(world: &mut World, scene: Scene, linear: bool) -> Handle<Image> {
+    let voxel_world = world
+        .resource_mut::<Assets<CpuVoxelWorld>>()
+        .add(CpuVoxelWorld::new(scene.brickmap));
+    world.spawn(VoxelVolumeBundle {
+        world: voxel_world,
+        ..default()
+    });
+
+    for (mesh, transform) in scene.meshes {
+        let mesh = world.resource_mut::<Assets<Mesh>>().add(mesh);
+        let material = world
+            .resource_mut::<Assets<StandardMaterial>>()
+            .add(Color::rgb(0.8, 0.2, 0.2).into());
+        world.spawn(PbrBundle {
+            mesh,
+            material,
+            transform,
+            ..default()
+        });
+    }
+
+    world.spawn(DirectionalLightBundle {
+        directional_light: DirectionalLight {
+            shadows_enabled: true,
+            ..default()
+        },
+        ..default()
+    });
+
+    let mut target = Image::new_fill(
+        Extent3d {
+            width: SIZE.x,
+            height: SIZE.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0; 4],
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    target.texture_descriptor.usage =
+        TextureUsages::TEXTURE_BINDING | TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC;
+    let target = world.resource_mut::<Assets<Image>>().add(target);
+
+    let mut camera = world.spawn((
+        Camera3dBundle {
+            transform: scene.camera,
+            camera: Camera {
+                hdr: true,
+                target: RenderTarget::Image(target.clone()),
+                ..default()
+            },
+            projection: Projection::Perspective(PerspectiveProjection {
+                fov: FOV,
+                near: 0.001,
+                far: 1000.0,
+                ..default()
+            }),
+            camera_3d: Camera3d {
+                depth_texture_usages: (TextureUsages::RENDER_ATTACHMENT
+                    | TextureUsages::TEXTURE_BINDING)
+                    .into(),
+                ..default()
+            },
+            tonemapping: match linear {
+                true => Tonemapping::None,
+                false => Tonemapping::default(),
+            },
+            dither: match linear {
+                true => DebandDither::Disabled,
+                false => DebandDither::Enabled,
+            },
+            ..default()
+        },
+        scene.renderer,
+        MainPassSettings::default(),
+        SkyCamera,
+    ));
+    if scene.ssao {
+        camera.insert((
+            DepthPrepass,
+            NormalPrepass,
+            ScreenSpaceAmbientOcclusionSettings::default(),
+        ));
+    }
+
+    target
+}
+
+fn follow_camera(
+    mut voxel_volumes: Query<&mut VoxelVolume>,
+    cameras: Query<(&Transform, &Frustum), With<Camera3d>>,
+) {
+    let (transform, frustum) = cameras.single();
+    for mut voxel_volume in voxel_volumes.iter_mut() {
+        voxel_volume.streaming_pos = transform.translation;
+        voxel_volume.streaming_frustum = Some(*frustum);
+    }
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden/images")
+        .join(format!("{}.png", name))
+}
+
+/// saves an image of a failed test next to the test binary
+fn save_output(file_name: &str, image: Image) -> PathBuf {
+    let out = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    std::fs::create_dir_all(&out).unwrap();
+    let path = out.join(file_name);
+    image.try_into_dynamic().unwrap().save(&path).unwrap();
+    path
+}
+
+fn compare(name: &str, actual: Image) {
+    let path = golden_path(name);
+    if std::env::var_os("GOLDEN_BLESS").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        actual.try_into_dynamic().unwrap().save(&path).unwrap();
+        eprintln!("wrote {}, check it in if it looks right", path.display());
+        return;
+    }
+    if !path.exists() {
+        let actual_path = save_output(&format!("{}.png", name), actual);
+        panic!(
+            "{} has no golden image, the render is in {}. rerun with GOLDEN_BLESS=1 to write it",
+            name,
+            actual_path.display()
+        );
+    }
+
+    let expected = Image::from_buffer(
+        &std::fs::read(&path).unwrap(),
+        ImageType::Extension("png"),
+        CompressedImageFormats::NONE,
+        true,
+        ImageSampler::Default,
+    )
+    .unwrap();
+    assert_eq!(
+        expected.size(),
+        actual.size(),
+        "{} changed size, rerun with GOLDEN_BLESS=1 if that was intended",
+        name
+    );
+    compare_images(name, &path, &expected, actual, TOLERANCE, MAX_MISMATCHED);
+}
+
+/// fails if more than `max_mismatched` of the pixels differ from `expected`,
+/// which was read from `path`, by more than `tolerance` in a channel
+fn compare_images(
+    name: &str,
+    path: &Path,
+    expected: &Image,
+    actual: Image,
+    tolerance: u8,
+    max_mismatched: f32,
+) {
+    // mismatched pixels are marked in red on a dimmed copy of the image
+    let mut mismatched = 0;
+    let mut diff = actual.data.clone();
+    for (pixel, (expected, actual)) in diff.chunks_exact_mut(4).zip(
+        expected
+            .data
+            .chunks_exact(4)
+            .zip(actual.data.chunks_exact(4)),
+    ) {
+        let matches = expected
+            .iter()
+            .zip(actual)
+            .all(|(expected, actual)| expected.abs_diff(*actual) <= tolerance);
+        if matches {
+            pixel[..3].iter_mut().for_each(|channel| *channel /= 3);
+        } else {
+            mismatched += 1;
+            pixel.copy_from_slice(&[255, 0, 0, 255]);
+        }
+    }
+
+    let pixels = (SIZE.x * SIZE.y) as f32;
+    if mismatched as f32 / pixels > max_mismatched {
+        let diff = Image {
+            data: diff,
+            ..actual.clone()
+        };
+        let actual_path = save_output(&format!("{}.png", name), actual);
+        let diff_path = save_output(&format!("{}.diff.png", name), diff);
+        panic!(
+            "{} differs from {} in {} of {} pixels, see {} and {}",
+            name,
+            path.display(),
+            mismatched,
+            pixels,
+            actual_path.display(),
+            diff_path.display(),
+        );
+    }
+}
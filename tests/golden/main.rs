@@ -0,0 +1,83 @@
+//! Golden image tests for the voxel renderer. Each test renders a small
+//! synthetic brickmap through `VoxelPlugin` on the software adapter and
+//! compares it against the image stored in tests/golden/images.
+//!
+//! They need a software adapter on a primary backend (vulkan, metal or dx12),
+//! lavapipe on linux, so they're ignored by default. Run them with
+//! `cargo test --test golden -- --ignored`, they fail when there is no such
+//! adapter. A missing image fails too, run with `GOLDEN_BLESS=1` to write the
+//! missing images, or rewrite all of them after an intended change to the
+//! output, and check them in after looking at them.
+//!
+//! A render matches its image when at most 0.2% of its pixels are off by more
+//! than 8 in a channel of the 8 bit srgb output, which leaves room for the
+//! rasterization of brick edges to differ between adapters. Failed renders
+//! and a diff marking the mismatched pixels in red are written to
+//! `CARGO_TARGET_TMPDIR/golden`.
+//!
+//! The `reference_` tests compare against the cpu reference renderer instead
+//! of an image, so a change to the shading has to be made to both. They
+//! allow 3% of the pixels to be off by more than 24, the reference traces
+//! ambient occlusion where the gpu estimates it.
+
+use alex::render_pipeline::VoxelRenderer;
+use bevy::prelude::*;
+use harness::{check, check_reference, Scene};
+
+mod harness;
+mod scenes;
+
+// where the cameras are and what they look at
+const CHECKERBOARD: (Vec3, Vec3) = (Vec3::new(3.0, 1.5, 3.0), Vec3::new(0.0, -3.0, 0.0));
+const SINGLE_BRICK: (Vec3, Vec3) = (Vec3::new(1.8, 1.2, 2.2), Vec3::splat(0.5));
+const DEEP_TREE: (Vec3, Vec3) = (Vec3::new(2.5, 1.5, 3.0), Vec3::new(0.0, 0.0, -40.0));
+const TRANSPARENT_VOXELS: (Vec3, Vec3) = (Vec3::new(0.9, 0.8, 2.5), Vec3::new(0.5, 0.5, -0.5));
+const REFLECTIVE_POOL: (Vec3, Vec3) = (Vec3::new(-1.0, -1.5, 2.0), Vec3::new(0.25, -3.5, 0.25));
+const SUNKEN_MESHES: (Vec3, Vec3) = (Vec3::new(2.0, -2.0, 2.5), Vec3::new(0.0, -3.75, 0.0));
+
+/// an ignored test per line, `name: check(scene, view, renderer, options)`
+/// renders the function of that name in `scenes` from the view with the
+/// `VoxelRenderer` and checks it with `check` or `check_reference`. the
+/// options are `sunken_meshes` to draw them along with the voxels and `ssao`
+macro_rules! golden {
+    (@sunken_meshes $scene:ident) => {
+        $scene.meshes = scenes::sunken_meshes();
+    };
+    (@ssao $scene:ident) => {
+        $scene.ssao = true;
+    };
+    ($($name:ident: $check:ident($brickmap:ident, $view:expr, $renderer:ident $(, $option:ident)*);)*) => {
+        $(
+            #[test]
+            #[ignore = "needs a software adapter, run with --ignored"]
+            fn $name() {
+                let (position, target) = $view;
+                #[allow(unused_mut)]
+                let mut scene = Scene {
+                    name: stringify!($name),
+                    brickmap: scenes::$brickmap(),
+                    camera: Transform::from_translation(position).looking_at(target, Vec3::Y),
+                    renderer: VoxelRenderer::$renderer,
+                    meshes: Vec::new(),
+                    ssao: false,
+                };
+                $(golden!(@$option scene);)*
+                $check(scene);
+            }
+        )*
+    };
+}
+
+golden! {
+    checkerboard: check(checkerboard, CHECKERBOARD, Instanced);
+    checkerboard_fullscreen: check(checkerboard, CHECKERBOARD, Fullscreen);
+    single_brick: check(single_brick, SINGLE_BRICK, Instanced);
+    deep_tree: check(deep_tree, DEEP_TREE, Instanced);
+    transparent_voxels: check(transparent_voxels, TRANSPARENT_VOXELS, Instanced);
+    reflective_pool: check(reflective_pool, REFLECTIVE_POOL, Instanced);
+    meshes_in_voxels: check(checkerboard, SUNKEN_MESHES, Instanced, sunken_meshes);
+    meshes_in_voxels_fullscreen: check(checkerboard, SUNKEN_MESHES, Fullscreen, sunken_meshes);
+    meshes_in_voxels_ssao: check(checkerboard, SUNKEN_MESHES, Instanced, sunken_meshes, ssao);
+    reference_single_brick: check_reference(single_brick, SINGLE_BRICK, Instanced);
+    reference_transparent_voxels: check_reference(transparent_voxels, TRANSPARENT_VOXELS, Instanced);
+}
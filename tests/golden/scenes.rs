@@ -0,0 +1,168 @@
+//! Small synthetic worlds. Positions passed to `place_brick` are in bricks,
+//! the volume is centred on the origin with the finest bricks 1 unit across.
+
+use alex::render_pipeline::{Brick, CpuBrickmap, Material, BRICK_SIZE};
+use bevy::prelude::*;
+
+/// a brick with the colour `voxel` gives each voxel, `None` leaves it empty.
+/// voxels that aren't opaque get a transparent material
+fn brick(voxel: impl Fn(UVec3) -> Option<[u8; 4]>) -> Brick {
+    let mut brick = Brick::empty();
+    for x in 0..BRICK_SIZE {
+        for y in 0..BRICK_SIZE {
+            for z in 0..BRICK_SIZE {
+                let pos = UVec3::new(x, y, z);
+                if let Some(colour) = voxel(pos) {
+                    brick.write(pos, colour);
+                    if colour[3] < 255 {
+                        brick.write_material(
+                            pos,
+                            Material {
+                                transparent: true,
+                                ..default()
+                            },
+                        );
+                    }
+                }
+            }
+        }
+    }
+    brick
+}
+
+/// lights and mipmaps the brickmap like a loaded world
+fn finish(mut brickmap: CpuBrickmap) -> CpuBrickmap {
+    brickmap.spread_light();
+    brickmap.recreate_mipmaps();
+    brickmap
+}
+
+/// a floor of 8 by 8 bricks, with a checkerboard of 4 voxel squares
+pub fn checkerboard() -> CpuBrickmap {
+    let mut brickmap = CpuBrickmap::new(3);
+    for x in 0..8 {
+        for z in 0..8 {
+            let floor = brick(|pos| {
+                let square = (pos.x / 4 + pos.z / 4) % 2;
+                (pos.y < 4).then_some(match square {
+                    0 => [230, 230, 230, 255],
+                    _ => [40, 40, 40, 255],
+                })
+            });
+            brickmap.place_brick(floor, UVec3::new(x, 0, z)).unwrap();
+        }
+    }
+    finish(brickmap)
+}
+
+/// one brick holding a sphere, coloured by position so the orientation is
+/// easy to see
+pub fn single_brick() -> CpuBrickmap {
+    let mut brickmap = CpuBrickmap::new(2);
+    let sphere = brick(|pos| {
+        let offset = pos.as_vec3() + 0.5 - BRICK_SIZE as f32 / 2.0;
+        (offset.length() < 7.0).then(|| {
+            let colour = (pos * 255 / (BRICK_SIZE - 1)).as_ivec3();
+            [colour.x as u8, colour.y as u8, colour.z as u8, 255]
+        })
+    });
+    brickmap.place_brick(sphere, UVec3::splat(2)).unwrap();
+    finish(brickmap)
+}
+
+/// a depth 8 tree with a few pillars far apart, so streaming has to divide
+/// all the way down near the camera and leaves the distant ones coarse
+pub fn deep_tree() -> CpuBrickmap {
+    let mut brickmap = CpuBrickmap::new(8);
+    let centre = UVec3::splat(128);
+    let pillar =
+        |colour: [u8; 4]| brick(move |pos| (pos.x % 8 < 6 && pos.z % 8 < 6).then_some(colour));
+    let pillars = [
+        (IVec3::new(0, 0, 0), [200, 60, 60, 255]),
+        (IVec3::new(2, 0, -1), [60, 200, 60, 255]),
+        (IVec3::new(-3, 0, -12), [60, 60, 200, 255]),
+        (IVec3::new(10, 0, -60), [200, 200, 60, 255]),
+        (IVec3::new(-40, 0, -120), [200, 60, 200, 255]),
+    ];
+    for (offset, colour) in pillars {
+        for y in 0..3 {
+            let pos = (centre.as_ivec3() + offset + IVec3::Y * (y - 1)).as_uvec3();
+            brickmap.place_brick(pillar(colour), pos).unwrap();
+        }
+    }
+    finish(brickmap)
+}
+
+/// a wall of half transparent voxels in front of an opaque one
+pub fn transparent_voxels() -> CpuBrickmap {
+    let mut brickmap = CpuBrickmap::new(2);
+    let glass =
+        brick(|pos| (pos.z >= 12 && (pos.x + pos.y) % 3 != 0).then_some([120, 200, 255, 100]));
+    let wall = brick(|pos| (pos.z < 4).then_some([220, 160, 90, 255]));
+    brickmap.place_brick(glass, UVec3::new(2, 2, 2)).unwrap();
+    brickmap.place_brick(wall, UVec3::new(2, 2, 1)).unwrap();
+    finish(brickmap)
+}
+
+/// a pool of water on a polished floor, with a block hanging over it to see
+/// reflected in both
+pub fn reflective_pool() -> CpuBrickmap {
+    let mut brickmap = CpuBrickmap::new(3);
+    let water = Material {
+        transparent: true,
+        roughness: 0.05,
+        ..default()
+    };
+    let polished = Material {
+        roughness: 0.2,
+        ..default()
+    };
+    for x in 0..4 {
+        for z in 0..4 {
+            let mut floor = brick(|pos| match pos.y {
+                0..=3 => Some([150, 150, 160, 255]),
+                4..=6 if (1..3).contains(&x) && (1..3).contains(&z) => Some([40, 90, 160, 120]),
+                _ => None,
+            });
+            for pos in (0..BRICK_SIZE * BRICK_SIZE * BRICK_SIZE).map(|i| {
+                UVec3::new(
+                    i % BRICK_SIZE,
+                    i / BRICK_SIZE % BRICK_SIZE,
+                    i / BRICK_SIZE / BRICK_SIZE,
+                )
+            }) {
+                let material = match floor.get(pos)[3] {
+                    255 => polished,
+                    0 => continue,
+                    _ => water,
+                };
+                floor.write_material(pos, material);
+            }
+            brickmap
+                .place_brick(floor, UVec3::new(x + 2, 0, z + 2))
+                .unwrap();
+        }
+    }
+    let block = brick(|pos| {
+        (pos.x >= 4 && pos.x < 12 && pos.z >= 4 && pos.z < 12).then_some([200, 60, 60, 255])
+    });
+    brickmap.place_brick(block, UVec3::new(3, 1, 3)).unwrap();
+    finish(brickmap)
+}
+
+/// a sphere and a box sunk halfway into the top of `checkerboard`, the voxels
+/// have to cut them off where they enter the floor
+pub fn sunken_meshes() -> Vec<(Mesh, Transform)> {
+    let sphere = shape::UVSphere {
+        radius: 0.5,
+        ..default()
+    };
+    let cube = shape::Cube { size: 0.6 };
+    vec![
+        (sphere.into(), Transform::from_xyz(0.0, -3.75, 0.0)),
+        (
+            cube.into(),
+            Transform::from_xyz(1.0, -3.75, -1.0).with_rotation(Quat::from_rotation_y(0.6)),
+        ),
+    ]
+}
@@ -0,0 +1,130 @@
+//! Round trip of the paged brickmap file: pieces written by `PagedWriter` are
+//! read back through `load_paged` and its `BrickPager`, and have to match the
+//! same bricks placed in memory.
+
+use alex::render_pipeline::{Brick, CpuBrickmap, Material, PagedWriter, BRICK_SIZE};
+use bevy::prelude::*;
+
+const DEPTH: u32 = 3;
+
+/// a brick only `seed` makes, with colour, light and material in every voxel
+/// so a mixed up byte shows
+fn brick(seed: u32) -> Brick {
+    let mut brick = Brick::empty();
+    for i in 0..BRICK_SIZE * BRICK_SIZE * BRICK_SIZE {
+        let pos = UVec3::new(
+            i % BRICK_SIZE,
+            i / BRICK_SIZE % BRICK_SIZE,
+            i / BRICK_SIZE / BRICK_SIZE,
+        );
+        let value = (i * 7 + seed * 31) as u8;
+        if value < 40 {
+            continue;
+        }
+        let alpha = if value & 4 != 0 { 120 } else { 255 };
+        brick.write(pos, [value, value ^ 0x55, seed as u8, alpha]);
+        brick.write_light(pos, [value / 2, 255 - value]);
+        brick.write_material(
+            pos,
+            Material {
+                id: value % 13,
+                transparent: alpha < 255,
+                emission: value % 4 * 60,
+                roughness: (value % 16) as f32 / 15.0,
+                metalness: (value % 2) as f32,
+            },
+        );
+    }
+    brick
+}
+
+/// a brickmap holding `positions` with mipmaps built, the way pieces reach
+/// `PagedWriter::add`
+fn brickmap(positions: &[(UVec3, u32)]) -> CpuBrickmap {
+    let mut brickmap = CpuBrickmap::new(DEPTH);
+    for &(pos, seed) in positions {
+        brickmap.place_brick(brick(seed), pos).unwrap();
+    }
+    brickmap.recreate_mipmaps();
+    brickmap
+}
+
+fn bytes(brick: &Brick) -> Vec<u8> {
+    [brick.to_gpu(), brick.light_to_gpu(), brick.material_bytes()].concat()
+}
+
+/// walks both trees side by side, checking they have the same nodes and
+/// bricks. returns how many bricks were compared
+fn compare(expected: &CpuBrickmap, paged: &CpuBrickmap, indices: (usize, usize)) -> usize {
+    let mut compared = 0;
+    for i in 0..8 {
+        let expected_node = expected.brickmap[indices.0 + i];
+        let paged_node = paged.brickmap[indices.1 + i];
+        assert_eq!(expected_node.brick == 0, paged_node.brick == 0);
+        assert_eq!(expected_node.children == 0, paged_node.children == 0);
+        if expected_node.brick != 0 {
+            let expected_brick = expected.brick_blocking(expected_node.brick).unwrap();
+            let paged_brick = paged.brick_blocking(paged_node.brick).unwrap();
+            assert!(bytes(&expected_brick) == bytes(&paged_brick));
+            compared += 1;
+        }
+        if expected_node.children != 0 {
+            compared += compare(
+                expected,
+                paged,
+                (
+                    8 * expected_node.children as usize,
+                    8 * paged_node.children as usize,
+                ),
+            );
+        }
+    }
+    compared
+}
+
+#[test]
+fn paged_round_trip() {
+    // the first two share a node at the split depth, the third shares one
+    // above it with them, so `finish` has to mip across pieces
+    let first = [(UVec3::new(0, 0, 0), 1), (UVec3::new(1, 1, 0), 2)];
+    let second = [(UVec3::new(2, 0, 0), 3), (UVec3::new(7, 7, 7), 4)];
+
+    let path = std::env::temp_dir().join(format!("alex_paged_{}.bin", std::process::id()));
+    let sources = [1, 2, 3];
+    let mut writer = PagedWriter::new(&path, DEPTH, 1, 2).unwrap();
+    writer.add(brickmap(&first)).unwrap();
+    writer.add(brickmap(&second)).unwrap();
+    writer.finish(&sources).unwrap();
+
+    assert!(CpuBrickmap::is_current_paged(&path, &sources));
+    assert!(!CpuBrickmap::is_current_paged(&path, &[1, 2]));
+
+    // a cache smaller than the paged bricks, so some are evicted
+    let paged = CpuBrickmap::load_paged(&path, 2).unwrap();
+    assert_eq!(paged.brickmap_depth, DEPTH);
+    let expected = brickmap(&[first, second].concat());
+
+    // a paged brick comes from the pager's thread eventually
+    let finest = |brickmap: &CpuBrickmap| {
+        let (index, _, _) = brickmap.get_node(UVec3::splat(7), None);
+        brickmap.brickmap[index].brick
+    };
+    let index = finest(&paged);
+    assert!(index >= paged.bricks.len() as u32);
+    let streamed = loop {
+        if let Some(brick) = paged.brick(index) {
+            break brick;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    };
+    assert!(bytes(&streamed) == bytes(&expected.bricks[finest(&expected) as usize]));
+
+    // the rest are read on this thread. 4 finest bricks, 3 at depth 2 and 2
+    // at depth 1
+    assert_eq!(compare(&expected, &paged, (0, 0)), 9);
+    let pager = paged.pager.as_ref().unwrap();
+    pager.evict();
+    assert_eq!(pager.cached(), (2, 2));
+
+    std::fs::remove_file(&path).unwrap();
+}
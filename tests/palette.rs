@@ -0,0 +1,53 @@
+//! Blocks of a loaded world find their palette entries by state.
+
+use alex::render_pipeline::{Palette, PaletteEntry};
+use fastanvil::Block;
+
+/// a block the way a chunk's nbt describes it
+fn block(name: &str, properties: &[(&str, &str)]) -> Block {
+    let properties = properties
+        .iter()
+        .map(|(key, value)| (key.to_string(), (*value).into()))
+        .collect::<serde_json::Map<String, serde_json::Value>>();
+    serde_json::from_value(serde_json::json!({ "Name": name, "Properties": properties })).unwrap()
+}
+
+fn emission(palette: &Palette, name: &str, properties: &[(&str, &str)]) -> u8 {
+    let PaletteEntry { material, .. } = palette.get(&block(name, properties));
+    material.emission
+}
+
+#[test]
+fn light_sources_by_state() {
+    let palette = Palette::load();
+
+    // keyed by their full state, waterlogged isn't part of the key
+    let lantern = [("hanging", "false"), ("waterlogged", "false")];
+    assert!(emission(&palette, "minecraft:lantern", &lantern) > 0);
+    let wall_torch = [("facing", "north")];
+    assert!(emission(&palette, "minecraft:wall_torch", &wall_torch) > 0);
+    let lamp = [("lit", "true")];
+    assert!(emission(&palette, "minecraft:redstone_lamp", &lamp) > 0);
+    let unlit_lamp = [("lit", "false")];
+    assert_eq!(
+        emission(&palette, "minecraft:redstone_lamp", &unlit_lamp),
+        0
+    );
+
+    // keyed by a part of their state
+    let campfire = [
+        ("facing", "east"),
+        ("lit", "true"),
+        ("signal_fire", "false"),
+    ];
+    assert!(emission(&palette, "minecraft:campfire", &campfire) > 0);
+    let unlit_campfire = [
+        ("facing", "east"),
+        ("lit", "false"),
+        ("signal_fire", "false"),
+    ];
+    assert_eq!(emission(&palette, "minecraft:campfire", &unlit_campfire), 0);
+
+    // keyed by name alone
+    assert!(emission(&palette, "minecraft:torch", &[]) > 0);
+}
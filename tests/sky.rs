@@ -0,0 +1,55 @@
+//! Pins samples of the cpu copy of sky.wgsl the reference renderer lights
+//! with. The values were worked out from sky.wgsl, so a change to either copy
+//! has to be made to both and here. The golden `reference_*` scenes compare
+//! the two copies on the gpu.
+
+use alex::render_pipeline::{sky_ambient, sky_colour};
+use bevy::prelude::*;
+
+const NOON: Vec3 = Vec3::Y;
+const MIDNIGHT: Vec3 = Vec3::NEG_Y;
+
+fn sunset() -> Vec3 {
+    Vec3::new(1.0, 0.05, 0.2).normalize()
+}
+
+#[track_caller]
+fn assert_close(actual: Vec3, expected: [f32; 3]) {
+    let expected = Vec3::from_array(expected);
+    assert!(
+        actual.abs_diff_eq(expected, 1e-4),
+        "expected {}, got {}",
+        expected,
+        actual
+    );
+}
+
+#[test]
+fn sky_colour_samples() {
+    // the horizon and the zenith of the day sky, with the sun disc overhead
+    assert_close(sky_colour(Vec3::X, NOON), [0.6, 0.75, 0.9]);
+    assert_close(sky_colour(Vec3::Y, NOON), [40.68, 36.8, 31.125]);
+    // the ground lit by the day
+    assert_close(sky_colour(Vec3::NEG_Y, NOON), [0.126, 0.105, 0.084]);
+
+    // the sunset glow is brightest towards the sun
+    let towards_sun = Vec3::new(1.0, 0.1, 0.2).normalize();
+    assert_close(
+        sky_colour(towards_sun, sunset()),
+        [1.38358, 1.05292, 0.93596],
+    );
+    assert_close(
+        sky_colour(Vec3::NEG_X, sunset()),
+        [0.55896, 0.53622, 0.57498],
+    );
+
+    let up = Vec3::new(0.3, 0.5, 0.2).normalize();
+    assert_close(sky_colour(up, MIDNIGHT), [0.00509, 0.00739, 0.01699]);
+}
+
+#[test]
+fn sky_ambient_samples() {
+    assert_close(sky_ambient(NOON), [0.234, 0.33, 0.495]);
+    assert_close(sky_ambient(sunset()), [0.25467, 0.26091, 0.3494]);
+    assert_close(sky_ambient(MIDNIGHT), [0.045, 0.06, 0.105]);
+}